@@ -1,4 +1,11 @@
-use std::{env::var, fs::File, io::Write, net::Ipv4Addr, path::Path, process::exit};
+use std::{
+  env::var,
+  fs::File,
+  io::Write,
+  net::{IpAddr, Ipv4Addr},
+  path::Path,
+  process::exit,
+};
 
 use lazy_static::lazy_static;
 use log::error;
@@ -11,9 +18,27 @@ lazy_static! {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ServerConfig {
   pub port: u16,
+  /// The externally reachable base URL (ex: `https://photos.example.com`), used
+  /// to build OAuth redirect URLs. Defaults to `http://localhost:{port}`
+  #[serde(default)]
+  pub public_url: Option<String>,
+  /// Where the browser is sent after the google OAuth callback, an `oauth`
+  /// query parameter is added with the result. Defaults to `{public_url}/`
+  #[serde(default)]
+  pub frontend_url: Option<String>,
   pub content_dir: String,
   pub client_secret_path: String,
   pub compression: Compression,
+  /// How long running syncs get to finish the item they are on when the
+  /// server is stopped, before their downloads are cut off and checkpointed
+  #[serde(default = "default_shutdown_grace")]
+  pub shutdown_grace_secs: u64,
+  /// Addresses of the reverse proxies in front of the server. The client
+  /// address in the audit log is read from their `X-Forwarded-For` or
+  /// `X-Real-IP`, so the server must only be reachable through them. Without
+  /// any the address is not recorded
+  #[serde(default)]
+  pub trusted_proxies: Vec<IpAddr>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -36,11 +61,271 @@ pub struct DatabaseConfig {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AuthConfig {
   pub jwt_secret: String,
+  /// Who can create accounts with `/api/users/new`
+  #[serde(default)]
+  pub registration: RegistrationMode,
+  /// How long new invite codes stay valid when the admin does not say
+  #[serde(default = "default_invite_expiry")]
+  pub invite_expiry_secs: u64,
+  #[serde(default)]
+  pub password_policy: PasswordPolicyConfig,
+}
+
+/// Rules new passwords have to follow (registration, password changes and
+/// admin resets)
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PasswordPolicyConfig {
+  pub min_length: usize,
+  pub require_lowercase: bool,
+  pub require_uppercase: bool,
+  pub require_digit: bool,
+  pub require_symbol: bool,
+  /// File with one common/breached password per line, matched case
+  /// insensitively
+  pub blocklist_path: Option<String>,
+}
+
+impl Default for PasswordPolicyConfig {
+  fn default() -> Self {
+    Self {
+      min_length: 8,
+      require_lowercase: false,
+      require_uppercase: false,
+      require_digit: false,
+      require_symbol: false,
+      blocklist_path: None,
+    }
+  }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationMode {
+  /// Anyone can register
+  #[default]
+  Open,
+  /// Registering needs a valid invite code created by an admin
+  Invite,
+  /// Nobody can register (except the first user)
+  Closed,
+}
+
+fn default_shutdown_grace() -> u64 {
+  30
+}
+
+fn default_invite_expiry() -> u64 {
+  7 * 24 * 60 * 60
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuditConfig {
+  /// Audit events older than this are deleted, 0 keeps them forever
+  pub retention_days: u64,
+}
+
+impl Default for AuditConfig {
+  fn default() -> Self {
+    Self { retention_days: 90 }
+  }
+}
+
+/// Prometheus metrics at `/api/metrics`
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+  /// Off by default, the metrics tell a lot about the users and the API usage
+  pub enabled: bool,
+  /// Scrapers have to send this as a bearer token, anyone can read the
+  /// metrics when it is not set
+  pub token: Option<String>,
+}
+
+/// Where archived media is stored
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+  /// A directory on the local disk
+  Local { path: String },
+  /// Any S3 compatible object store (AWS, MinIO, Garage, ...)
+  S3 {
+    /// Ex: `https://s3.eu-west-1.amazonaws.com` or `http://localhost:9000`
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    /// Use `{endpoint}/{bucket}/{key}` urls instead of `{bucket}.{endpoint}`,
+    /// most self hosted stores need this
+    #[serde(default)]
+    path_style: bool,
+    /// Prefix added to every key
+    #[serde(default)]
+    prefix: String,
+  },
+}
+
+impl Default for StorageConfig {
+  fn default() -> Self {
+    Self::Local { path: "archive".to_string() }
+  }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DownloaderConfig {
   pub pool_size: usize,
+  /// Local directory downloads are written to before they are stored
+  #[serde(default = "default_staging_path")]
+  pub staging_path: String,
+  /// How often a failed download is retried (resuming where it stopped)
+  /// before the item is skipped until the next sync
+  #[serde(default = "default_max_retries")]
+  pub max_retries: u32,
+  #[serde(default)]
+  pub bandwidth: BandwidthConfig,
+  #[serde(default)]
+  pub quota: QuotaConfig,
+}
+
+/// Budget of Photos Library API requests, the project quota is shared by
+/// every user
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+  /// Requests all users can make per day together, 0 for no budget (google
+  /// still enforces its own)
+  pub daily_requests: u64,
+  /// Hour (UTC) the quota resets at, google resets it at midnight Pacific time
+  pub reset_hour_utc: u32,
+}
+
+impl Default for QuotaConfig {
+  fn default() -> Self {
+    Self { daily_requests: 10000, reset_hour_utc: 8 }
+  }
+}
+
+/// Download speed caps, in bytes per second. 0 means unlimited
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct BandwidthConfig {
+  /// Cap of all downloads together
+  pub limit: u64,
+  /// Cap of the downloads of a single user
+  pub user_limit: u64,
+  /// Users with a different cap than `user_limit`
+  pub users: Vec<UserBandwidthConfig>,
+  /// Parts of the day (local time) with a different global cap than `limit`,
+  /// ex: `{ start = "08:00", end = "19:00", limit = 2000000 }` to only allow
+  /// 2 MB/s during office hours
+  pub schedule: Vec<BandwidthWindowConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UserBandwidthConfig {
+  pub user_id: i32,
+  pub limit: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BandwidthWindowConfig {
+  /// `HH:MM`, the window goes past midnight if `end` is before `start`
+  pub start: String,
+  pub end: String,
+  pub limit: u64,
+}
+
+/// Automatic syncs on the cron schedules users set up
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SchedulerConfig {
+  pub enabled: bool,
+  /// Part of the day (local time) scheduled syncs do not start in, runs that
+  /// fall in it move to the first occurrence after it
+  pub quiet_hours: Option<QuietHoursConfig>,
+  /// Up to this many seconds are added to every run, so schedules on the same
+  /// minute do not all hit google at once
+  pub jitter_secs: u64,
+  /// How often to look for due schedules
+  pub tick_secs: u64,
+}
+
+impl Default for SchedulerConfig {
+  fn default() -> Self {
+    Self { enabled: true, quiet_hours: None, jitter_secs: 300, tick_secs: 60 }
+  }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct QuietHoursConfig {
+  /// `HH:MM`, the quiet hours go past midnight if `end` is before `start`
+  pub start: String,
+  pub end: String,
+}
+
+fn default_staging_path() -> String {
+  "staging".to_string()
+}
+
+fn default_max_retries() -> u32 {
+  5
+}
+
+/// HTTP client used for every request to google
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct HttpConfig {
+  pub connect_timeout_secs: u64,
+  /// How long an API call can take. Downloads are only limited by
+  /// `read_timeout_secs`
+  pub request_timeout_secs: u64,
+  /// How long a response can go without sending anything
+  pub read_timeout_secs: u64,
+  /// How long an unused connection is kept open for the next request
+  pub idle_timeout_secs: u64,
+  /// Interval of TCP keep-alive probes, 0 to turn them off
+  pub tcp_keepalive_secs: u64,
+  /// Use HTTP/2 when the server supports it
+  pub http2: bool,
+  pub user_agent: String,
+  /// Send every request through this proxy, ex: `http://proxy.lan:3128`
+  pub proxy: Option<String>,
+}
+
+impl Default for HttpConfig {
+  fn default() -> Self {
+    Self {
+      connect_timeout_secs: 10,
+      request_timeout_secs: 60,
+      read_timeout_secs: 60,
+      idle_timeout_secs: 90,
+      tcp_keepalive_secs: 60,
+      http2: true,
+      user_agent: "photo_archiver".to_string(),
+      proxy: None,
+    }
+  }
+}
+
+/// Browsable copies of the archive, laid out by a path template
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LayoutConfig {
+  /// Path of every item under `root`, built from `{user}`, `{user_id}`,
+  /// `{account}`, `{album}`, `{year}`, `{month}`, `{day}`, `{date}`, `{id}`,
+  /// `{filename}`, `{name}`, `{ext}` and `{hash}`. Empty to only keep the
+  /// content addressed blobs
+  pub template: String,
+  /// Storage prefix the layout is written to
+  pub root: String,
+}
+
+impl Default for LayoutConfig {
+  fn default() -> Self {
+    Self { template: "{user}/{year}/{month}/{filename}".to_string(), root: "library".to_string() }
+  }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -49,6 +334,18 @@ pub struct Config {
   pub database: DatabaseConfig,
   pub auth: AuthConfig,
   pub downloader: DownloaderConfig,
+  #[serde(default)]
+  pub http: HttpConfig,
+  #[serde(default)]
+  pub audit: AuditConfig,
+  #[serde(default)]
+  pub metrics: MetricsConfig,
+  #[serde(default)]
+  pub storage: StorageConfig,
+  #[serde(default)]
+  pub layout: LayoutConfig,
+  #[serde(default)]
+  pub scheduler: SchedulerConfig,
 }
 
 impl Default for Config {
@@ -56,9 +353,13 @@ impl Default for Config {
     Self {
       server: ServerConfig {
         port: 8080,
+        public_url: None,
+        frontend_url: None,
         content_dir: "html".to_string(),
         client_secret_path: "secret.json".to_string(),
         compression: Compression { zstd: true, br: true, gzip: true },
+        shutdown_grace_secs: default_shutdown_grace(),
+        trusted_proxies: Vec::new(),
       },
       database: DatabaseConfig {
         ip: Ipv4Addr::new(127, 0, 0, 1),
@@ -68,12 +369,44 @@ impl Default for Config {
         password: "password".to_string(),
         dbname: "photoarchiver".to_string(),
       },
-      auth: AuthConfig { jwt_secret: "changeme".to_string() },
-      downloader: DownloaderConfig { pool_size: 5 },
+      auth: AuthConfig {
+        jwt_secret: "changeme".to_string(),
+        registration: RegistrationMode::Open,
+        invite_expiry_secs: default_invite_expiry(),
+        password_policy: PasswordPolicyConfig::default(),
+      },
+      downloader: DownloaderConfig {
+        pool_size: 5,
+        staging_path: default_staging_path(),
+        max_retries: default_max_retries(),
+        bandwidth: BandwidthConfig::default(),
+        quota: QuotaConfig::default(),
+      },
+      http: HttpConfig::default(),
+      audit: AuditConfig::default(),
+      metrics: MetricsConfig::default(),
+      storage: StorageConfig::default(),
+      layout: LayoutConfig::default(),
+      scheduler: SchedulerConfig::default(),
     }
   }
 }
 
+impl ServerConfig {
+  /// Get the public base URL without a trailing slash
+  pub fn get_public_url(&self) -> String {
+    match &self.public_url {
+      Some(u) => u.trim_end_matches('/').to_string(),
+      None => format!("http://localhost:{}", self.port),
+    }
+  }
+
+  /// Get the frontend URL the OAuth callback redirects to
+  pub fn get_frontend_url(&self) -> String {
+    self.frontend_url.clone().unwrap_or_else(|| format!("{}/", self.get_public_url()))
+  }
+}
+
 impl Config {
   pub fn parse<P>(path: P) -> Self
   where
@@ -87,7 +420,7 @@ impl Config {
       }
     };
 
-    let mut config_file = match File::options().create(true).write(true).read(true).append(true).open(&path) {
+    let mut config_file = match File::options().create(true).read(true).append(true).open(&path) {
       Ok(f) => f,
       Err(e) => {
         error!("Failed to open config file: {}", e);
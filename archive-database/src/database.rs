@@ -1,16 +1,32 @@
-use std::{process::exit, sync::Arc, time::Duration};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use archive_config::{DatabaseConfig, CONFIG};
 use log::{debug, error, info};
+use migration::{Migrator, MigratorTrait};
 use sea_orm::{
-  ActiveModelTrait, ColumnTrait, ConnectOptions, DatabaseConnection, EntityTrait, IntoActiveModel, QueryFilter, Set,
+  sea_query::{Expr, OnConflict},
+  ActiveModelTrait, ColumnTrait, ConnectOptions, ConnectionTrait, DatabaseConnection, EntityTrait, FromQueryResult,
+  IntoActiveModel, JoinType, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, RelationTrait, Set, SqlErr,
+  TransactionTrait, TryIntoModel,
 };
-use serde::de::value::Error;
 use tokio::sync::Mutex;
 
 use crate::{
-  entities::users,
-  structs::{DatabaseError, User},
+  entities::{
+    album_items, albums, audit_events, blobs, google_accounts, invites, media_items, pending_media_items, sync_jobs,
+    sync_schedules, users, verify_runs,
+  },
+  structs::{
+    AlbumInfo, AuditFilter, DatabaseError, DedupStats, GUser, MediaItemInfo, Registration, Role, SyncJobStatus, User,
+    VerifyRunStatus,
+  },
 };
 
 pub type SharedDatabase = Arc<Mutex<PhotoArchiverDatabase>>;
@@ -18,36 +34,104 @@ pub type SharedDatabase = Arc<Mutex<PhotoArchiverDatabase>>;
 pub struct PhotoArchiverDatabase {
   config: DatabaseConfig,
   client: Option<DatabaseConnection>,
+  /// Pings done before queries and how long they took in total, in
+  /// microseconds
+  pings: AtomicU64,
+  ping_micros: AtomicU64,
 }
 
 impl PhotoArchiverDatabase {
   pub fn new(config: DatabaseConfig) -> SharedDatabase {
-    Arc::new(Mutex::new(Self { config, client: None }))
+    Arc::new(Mutex::new(Self { config, client: None, pings: AtomicU64::new(0), ping_micros: AtomicU64::new(0) }))
+  }
+
+  /// Connect to the database and bring the schema up to date
+  ///
+  /// Returns Ok(()) if the database is ready to use or a DatabaseError if it
+  /// could not be reached or migrated
+  pub async fn init(&mut self) -> Result<(), DatabaseError> {
+    self.client = Some(Self::connect(&self.config).await?);
+    Ok(())
   }
 
-  pub async fn init(&mut self) -> Result<(), Error> {
+  /// Same as [`PhotoArchiverDatabase::init`] without a database to put the
+  /// connection in, so it can be tried without holding the database lock.
+  /// Pass the connection to [`PhotoArchiverDatabase::set_connection`]
+  pub async fn connect(config: &DatabaseConfig) -> Result<DatabaseConnection, DatabaseError> {
     debug!("Initializing database connection");
-    let connection_string = format!(
-      "postgres://{}:{}@{}:{}/{}",
-      self.config.username, self.config.password, self.config.ip, self.config.port, self.config.dbname
-    );
+    let connection_string =
+      format!("postgres://{}:{}@{}:{}/{}", config.username, config.password, config.ip, config.port, config.dbname);
 
     let mut options = ConnectOptions::new(connection_string);
     options.connect_timeout(Duration::from_secs(CONFIG.database.timeout));
 
-    let client = match sea_orm::Database::connect(options).await {
-      Ok(r) => r,
-      Err(e) => {
-        error!("Failed to connect to database at {}:{}: {}", CONFIG.database.ip, CONFIG.database.port, e);
-        exit(1)
-      }
-    };
+    let client = sea_orm::Database::connect(options).await.map_err(|e| {
+      error!("Failed to connect to database at {}:{}: {}", config.ip, config.port, e);
+      DatabaseError::new(format!("Failed to connect to database at {}:{}", config.ip, config.port))
+    })?;
 
-    info!("Connected to database at {}:{}", self.config.ip, self.config.port);
+    info!("Connected to database at {}:{}", config.ip, config.port);
 
+    Migrator::up(&client, None).await.map_err(|e| {
+      error!("Failed to run database migrations: {}", e);
+      DatabaseError::new("Failed to run database migrations")
+    })?;
+
+    Ok(client)
+  }
+
+  #[inline]
+  pub fn set_connection(&mut self, client: DatabaseConnection) {
     self.client = Some(client);
+  }
 
-    Ok(())
+  #[inline]
+  pub fn is_connected(&self) -> bool {
+    self.client.is_some()
+  }
+
+  /// Close the connection, later queries fail until the next `init`
+  pub async fn close(&mut self) {
+    if let Some(client) = self.client.take() {
+      match client.close().await {
+        Ok(_) => info!("Closed the database connection"),
+        Err(e) => error!("Failed to close the database connection: {}", e),
+      }
+    }
+  }
+
+  /// Check that the database answers
+  ///
+  /// Returns how long it took to answer or a DatabaseError if it did not
+  pub async fn ping(&self) -> Result<Duration, DatabaseError> {
+    let started = Instant::now();
+    self.connection().await?;
+    Ok(started.elapsed())
+  }
+
+  /// Get the database connection, making sure it is initialized and alive
+  async fn connection(&self) -> Result<&DatabaseConnection, DatabaseError> {
+    match &self.client {
+      Some(c) if self.timed_ping(c).await => Ok(c),
+      _ => {
+        error!("Database is not initialized or the connection is invalid");
+        Err(DatabaseError::new("Database is not initialized or the connection is invalid"))
+      }
+    }
+  }
+
+  async fn timed_ping(&self, client: &DatabaseConnection) -> bool {
+    let started = Instant::now();
+    let ok = client.ping().await.is_ok();
+    self.pings.fetch_add(1, Ordering::Relaxed);
+    self.ping_micros.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    ok
+  }
+
+  /// How many round trips to the database were timed and how long they took
+  /// together
+  pub fn get_latency(&self) -> (u64, Duration) {
+    (self.pings.load(Ordering::Relaxed), Duration::from_micros(self.ping_micros.load(Ordering::Relaxed)))
   }
 
   /// Get a  Vec of all the users in the database
@@ -55,12 +139,7 @@ impl PhotoArchiverDatabase {
   /// Returns Vec<User> if getting users was successful or a DatabaseError if it
   /// was not
   pub async fn get_all_users(&self) -> Result<Vec<User>, DatabaseError> {
-    if self.client.is_none() || !self.client.as_ref().unwrap().ping().await.is_ok() {
-      error!("Database is not initialized or the connection is invalid");
-      return Err(DatabaseError::new("Database is not initialized or the connection is invalid"));
-    }
-
-    let db = self.client.as_ref().unwrap();
+    let db = self.connection().await?;
     let models = users::Entity::find().all(db).await.unwrap();
 
     let res = models.iter().map(|m| m.clone().into()).collect();
@@ -71,12 +150,7 @@ impl PhotoArchiverDatabase {
   where
     V: Into<sea_orm::Value>,
   {
-    if self.client.is_none() || !self.client.as_ref().unwrap().ping().await.is_ok() {
-      error!("Database is not initialized or the connection is invalid");
-      return Err(DatabaseError::new("Database is not initialized or the connection is invalid"));
-    }
-
-    let db = self.client.as_ref().unwrap();
+    let db = self.connection().await?;
     let user = users::Entity::find().filter(field.eq(value.into())).one(db).await.map_err(|e| {
       error!("Error querying that database: {}", e);
       DatabaseError::new("Failed to query the database")
@@ -93,12 +167,7 @@ impl PhotoArchiverDatabase {
   /// Returns Ok(()) if the user was successfully modified or a DatabaseError if
   /// the operation failed
   pub async fn update_user(&self, id: i32, username: String, password_hash: String) -> Result<(), DatabaseError> {
-    if self.client.is_none() || !self.client.as_ref().unwrap().ping().await.is_ok() {
-      error!("Database is not initialized or the connection is invalid");
-      return Err(DatabaseError::new("Database is not initialized or the connection is invalid"));
-    }
-
-    let db = self.client.as_ref().unwrap();
+    let db = self.connection().await?;
 
     let user = users::Entity::find_by_id(id).one(db).await.map_err(|e| {
       error!("Failed to fetch user: {}", e);
@@ -123,27 +192,94 @@ impl PhotoArchiverDatabase {
 
   /// Creates an new user
   ///
-  /// Returns Ok(()) if the user was successfully created or a DatabaseError if
-  /// the operation failed
-  pub async fn new_user(&self, user: User) -> Result<(), DatabaseError> {
-    if self.client.is_none() || !self.client.as_ref().unwrap().ping().await.is_ok() {
-      error!("Database is not initialized or the connection is invalid");
-      return Err(DatabaseError::new("Database is not initialized or the connection is invalid"));
+  /// Returns whether the user was created or the username is taken, or a
+  /// DatabaseError if the operation failed
+  pub async fn new_user(&self, user: User) -> Result<Registration, DatabaseError> {
+    let db = self.connection().await?;
+
+    let new_user = users::ActiveModel {
+      username: Set(user.get_username()),
+      password_hash: Set(user.get_password_hash()),
+      role: Set(user.get_role().to_string()),
+      ..Default::default()
+    };
+
+    match new_user.insert(db).await {
+      Ok(_) => Ok(Registration::Created),
+      Err(e) if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => Ok(Registration::UsernameTaken),
+      Err(e) => {
+        error!("Error inserting new user: {}", e);
+        Err(DatabaseError::new("Failed to insert new user"))
+      }
     }
-    let db = self.client.as_ref().unwrap();
+  }
+
+  /// Creates a new user with an invite code, the user gets the role of the
+  /// invite and the invite is used up
+  ///
+  /// Returns whether the user was created, the username is taken or the
+  /// invite is not valid, or a DatabaseError if the operation failed
+  pub async fn new_user_with_invite(&self, user: User, code: &str) -> Result<Registration, DatabaseError> {
+    let db = self.connection().await?;
+    let now = unix_timestamp();
+
+    let txn = db.begin().await.map_err(|e| {
+      error!("Failed to start transaction: {}", e);
+      DatabaseError::new("Failed to start transaction")
+    })?;
+
+    let invite = invites::Entity::find_by_id(code).lock_exclusive().one(&txn).await.map_err(|e| {
+      error!("Failed to fetch invite: {}", e);
+      DatabaseError::new("Failed to fetch invite")
+    })?;
+
+    let invite = match invite {
+      Some(i) if i.used_by.is_none() && i.expires_at > now => i,
+      _ => return Ok(Registration::InvalidInvite),
+    };
 
     let new_user = users::ActiveModel {
       username: Set(user.get_username()),
       password_hash: Set(user.get_password_hash()),
+      role: Set(invite.role.clone()),
       ..Default::default()
     };
 
-    let _ = new_user.insert(db).await.map_err(|e| {
-      error!("Error inserting new user: {}", e);
-      return DatabaseError::new("Failed to insert new user");
+    // The invite stays unused when the transaction is dropped
+    let new_user = match new_user.insert(&txn).await {
+      Ok(u) => u,
+      Err(e) if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) =>
+        return Ok(Registration::UsernameTaken),
+      Err(e) => {
+        error!("Error inserting new user: {}", e);
+        return Err(DatabaseError::new("Failed to insert new user"));
+      }
+    };
+
+    let mut invite = invite.into_active_model();
+    invite.used_by = Set(Some(new_user.id));
+    invite.used_at = Set(Some(now));
+    invite.update(&txn).await.map_err(|e| {
+      error!("Failed to use invite: {}", e);
+      DatabaseError::new("Failed to use invite")
     })?;
 
-    Ok(())
+    txn.commit().await.map_err(|e| {
+      error!("Failed to commit transaction: {}", e);
+      DatabaseError::new("Failed to commit transaction")
+    })?;
+
+    Ok(Registration::Created)
+  }
+
+  /// Count the users in the database
+  pub async fn count_users(&self) -> Result<u64, DatabaseError> {
+    let db = self.connection().await?;
+
+    users::Entity::find().count(db).await.map_err(|e| {
+      error!("Failed to count users: {}", e);
+      DatabaseError::new("Failed to count users")
+    })
   }
 
   /// Delate an existing user
@@ -151,18 +287,1202 @@ impl PhotoArchiverDatabase {
   /// Returns Ok(()) if the user was deleted successfully or a DatabaseError if
   /// the operation failed
   pub async fn delate_user(&self, user_id: i32) -> Result<(), DatabaseError> {
-    if self.client.is_none() || !self.client.as_ref().unwrap().ping().await.is_ok() {
-      error!("Database is not initialized or the connection is invalid");
-      return Err(DatabaseError::new("Database is not initialized or the connection is invalid"));
-    }
+    let db = self.connection().await?;
+
+    let txn = db.begin().await.map_err(|e| {
+      error!("Failed to start transaction: {}", e);
+      DatabaseError::new("Failed to start transaction")
+    })?;
 
-    let db = self.client.as_ref().unwrap();
+    // Media items are deleted with the user, their blobs need to be released
+    Self::release_user_media(&txn, user_id).await?;
 
-    let _ = users::Entity::delete_by_id(user_id).exec(db).await.map_err(|e| {
+    let _ = users::Entity::delete_by_id(user_id).exec(&txn).await.map_err(|e| {
       error!("Failed to delete user: {}", e);
       DatabaseError::new("Failed to delete user")
     })?;
 
+    txn.commit().await.map_err(|e| {
+      error!("Failed to commit transaction: {}", e);
+      DatabaseError::new("Failed to commit transaction")
+    })?;
+
+    Ok(())
+  }
+
+  /// Get the google account linked to a user
+  ///
+  /// Returns Ok(None) if the user has not linked a google account or a
+  /// DatabaseError if the query failed
+  pub async fn get_google_account(&self, user_id: i32) -> Result<Option<GUser>, DatabaseError> {
+    let db = self.connection().await?;
+
+    let account =
+      google_accounts::Entity::find().filter(google_accounts::Column::UserId.eq(user_id)).one(db).await.map_err(
+        |e| {
+          error!("Failed to fetch google account: {}", e);
+          DatabaseError::new("Failed to fetch google account")
+        },
+      )?;
+
+    Ok(account.map(|m| m.into()))
+  }
+
+  /// Link a google account to a user, replacing any previously linked account
+  ///
+  /// Returns Ok(()) if the account was saved or a DatabaseError if the
+  /// operation failed
+  pub async fn set_google_account(&self, user_id: i32, guser: &GUser) -> Result<(), DatabaseError> {
+    let db = self.connection().await?;
+
+    let existing =
+      google_accounts::Entity::find().filter(google_accounts::Column::UserId.eq(user_id)).one(db).await.map_err(
+        |e| {
+          error!("Failed to fetch google account: {}", e);
+          DatabaseError::new("Failed to fetch google account")
+        },
+      )?;
+
+    let mut account = match existing {
+      Some(m) => m.into_active_model(),
+      None => google_accounts::ActiveModel { user_id: Set(user_id), ..Default::default() },
+    };
+
+    account.name = Set(guser.get_name().to_string());
+    account.pfp_url = Set(guser.get_pfp_url().to_string());
+    account.access_token = Set(guser.get_auth_token().to_string());
+    account.linked_at = Set(unix_timestamp());
+    account.scopes = Set(guser.get_scopes().join(" "));
+    account.refresh_token = Set(guser.get_refresh_token().map(|t| t.to_string()));
+    account.token_expires_at = Set(guser.get_expires_at());
+
+    account.save(db).await.map_err(|e| {
+      error!("Failed to save google account: {}", e);
+      DatabaseError::new("Failed to save google account")
+    })?;
+
+    Ok(())
+  }
+
+  /// Store a refreshed auth token of the google account linked to a user
+  ///
+  /// Returns Ok(()) if the token was saved or a DatabaseError if the
+  /// operation failed
+  pub async fn update_google_token(&self, user_id: i32, guser: &GUser) -> Result<(), DatabaseError> {
+    let db = self.connection().await?;
+
+    google_accounts::Entity::update_many()
+      .col_expr(google_accounts::Column::AccessToken, Expr::value(guser.get_auth_token()))
+      .col_expr(google_accounts::Column::RefreshToken, Expr::value(guser.get_refresh_token()))
+      .col_expr(google_accounts::Column::TokenExpiresAt, Expr::value(guser.get_expires_at()))
+      .filter(google_accounts::Column::UserId.eq(user_id))
+      .exec(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to update google token: {}", e);
+        DatabaseError::new("Failed to update google token")
+      })?;
+
+    Ok(())
+  }
+
+  /// Creates a single use invite code
+  ///
+  /// Returns the new invite or a DatabaseError if the operation failed
+  pub async fn new_invite(
+    &self,
+    code: String,
+    role: Role,
+    created_by: i32,
+    expires_at: i64,
+  ) -> Result<invites::Model, DatabaseError> {
+    let db = self.connection().await?;
+
+    let invite = invites::ActiveModel {
+      code: Set(code),
+      role: Set(role.to_string()),
+      created_by: Set(Some(created_by)),
+      created_at: Set(unix_timestamp()),
+      expires_at: Set(expires_at),
+      used_by: Set(None),
+      used_at: Set(None),
+    };
+
+    invite.insert(db).await.map_err(|e| {
+      error!("Error inserting invite: {}", e);
+      DatabaseError::new("Failed to insert invite")
+    })
+  }
+
+  /// Get all invites, newest first
+  pub async fn get_invites(&self) -> Result<Vec<invites::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    invites::Entity::find().order_by_desc(invites::Column::CreatedAt).all(db).await.map_err(|e| {
+      error!("Failed to fetch invites: {}", e);
+      DatabaseError::new("Failed to fetch invites")
+    })
+  }
+
+  /// Unlink the google account of a user
+  ///
+  /// Returns Ok(()) if the account was removed (or there was none) or a
+  /// DatabaseError if the operation failed
+  pub async fn delete_google_account(&self, user_id: i32) -> Result<(), DatabaseError> {
+    let db = self.connection().await?;
+
+    google_accounts::Entity::delete_many().filter(google_accounts::Column::UserId.eq(user_id)).exec(db).await.map_err(
+      |e| {
+        error!("Failed to delete google account: {}", e);
+        DatabaseError::new("Failed to delete google account")
+      },
+    )?;
+
+    Ok(())
+  }
+
+  /// Add an event to the audit log
+  pub async fn new_audit_event(
+    &self,
+    action: &str,
+    actor_id: Option<i32>,
+    target_id: Option<i32>,
+    ip: Option<String>,
+    details: Option<String>,
+  ) -> Result<(), DatabaseError> {
+    let db = self.connection().await?;
+
+    let event = audit_events::ActiveModel {
+      created_at: Set(unix_timestamp()),
+      action: Set(action.to_string()),
+      actor_id: Set(actor_id),
+      target_id: Set(target_id),
+      ip: Set(ip),
+      details: Set(details),
+      ..Default::default()
+    };
+
+    event.insert(db).await.map_err(|e| {
+      error!("Error inserting audit event: {}", e);
+      DatabaseError::new("Failed to insert audit event")
+    })?;
+
+    Ok(())
+  }
+
+  /// Get a page of audit events matching `filter`, newest first
+  ///
+  /// Returns the events and the total number of matching events
+  pub async fn get_audit_events(
+    &self,
+    filter: &AuditFilter,
+    page: u64,
+    per_page: u64,
+  ) -> Result<(Vec<audit_events::Model>, u64), DatabaseError> {
+    let db = self.connection().await?;
+
+    let mut query = audit_events::Entity::find();
+    if let Some(action) = &filter.action {
+      query = query.filter(audit_events::Column::Action.eq(action.clone()));
+    }
+    if let Some(actor_id) = filter.actor_id {
+      query = query.filter(audit_events::Column::ActorId.eq(actor_id));
+    }
+    if let Some(target_id) = filter.target_id {
+      query = query.filter(audit_events::Column::TargetId.eq(target_id));
+    }
+    if let Some(since) = filter.since {
+      query = query.filter(audit_events::Column::CreatedAt.gte(since));
+    }
+    if let Some(until) = filter.until {
+      query = query.filter(audit_events::Column::CreatedAt.lt(until));
+    }
+
+    let paginator = query
+      .order_by_desc(audit_events::Column::CreatedAt)
+      .order_by_desc(audit_events::Column::Id)
+      .paginate(db, per_page.max(1));
+
+    let total = paginator.num_items().await.map_err(|e| {
+      error!("Failed to count audit events: {}", e);
+      DatabaseError::new("Failed to count audit events")
+    })?;
+    let events = paginator.fetch_page(page).await.map_err(|e| {
+      error!("Failed to fetch audit events: {}", e);
+      DatabaseError::new("Failed to fetch audit events")
+    })?;
+
+    Ok((events, total))
+  }
+
+  /// Delete audit events created before `before`
+  ///
+  /// Returns the number of deleted events
+  pub async fn purge_audit_events(&self, before: i64) -> Result<u64, DatabaseError> {
+    let db = self.connection().await?;
+
+    let res =
+      audit_events::Entity::delete_many().filter(audit_events::Column::CreatedAt.lt(before)).exec(db).await.map_err(
+        |e| {
+          error!("Failed to purge audit events: {}", e);
+          DatabaseError::new("Failed to purge audit events")
+        },
+      )?;
+
+    Ok(res.rows_affected)
+  }
+
+  /// Get a blob by its content hash
+  pub async fn get_blob(&self, hash: &str) -> Result<Option<blobs::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    blobs::Entity::find_by_id(hash).one(db).await.map_err(|e| {
+      error!("Failed to fetch blob: {}", e);
+      DatabaseError::new("Failed to fetch blob")
+    })
+  }
+
+  /// Get the google ids of every media item a user has archived
+  pub async fn get_media_item_ids(&self, user_id: i32) -> Result<HashSet<String>, DatabaseError> {
+    let db = self.connection().await?;
+
+    let ids: Vec<String> = media_items::Entity::find()
+      .select_only()
+      .column(media_items::Column::GoogleId)
+      .filter(media_items::Column::UserId.eq(user_id))
+      .into_tuple()
+      .all(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch media item ids: {}", e);
+        DatabaseError::new("Failed to fetch media item ids")
+      })?;
+
+    Ok(ids.into_iter().collect())
+  }
+
+  /// Point a media item of a user at the blob with `hash`, creating the item
+  /// and the blob if needed. The blob gains a reference and the blob the item
+  /// pointed at before loses one
+  ///
+  /// Returns the media item or a DatabaseError if the operation failed
+  pub async fn link_media_item(
+    &self,
+    user_id: i32,
+    item: &MediaItemInfo,
+    hash: &str,
+    size: i64,
+  ) -> Result<media_items::Model, DatabaseError> {
+    let db = self.connection().await?;
+    let now = unix_timestamp();
+
+    let txn = db.begin().await.map_err(|e| {
+      error!("Failed to start transaction: {}", e);
+      DatabaseError::new("Failed to start transaction")
+    })?;
+
+    let existing = media_items::Entity::find()
+      .filter(media_items::Column::UserId.eq(user_id))
+      .filter(media_items::Column::GoogleId.eq(item.google_id.clone()))
+      .lock_exclusive()
+      .one(&txn)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch media item: {}", e);
+        DatabaseError::new("Failed to fetch media item")
+      })?;
+
+    if let Some(m) = &existing {
+      if m.blob_hash == hash {
+        return Ok(m.clone());
+      }
+    }
+
+    // Another item may be adding the same blob at the same time
+    let blob =
+      blobs::ActiveModel { hash: Set(hash.to_string()), size: Set(size), ref_count: Set(0), created_at: Set(now) };
+    blobs::Entity::insert(blob)
+      .on_conflict(OnConflict::column(blobs::Column::Hash).do_nothing().to_owned())
+      .exec_without_returning(&txn)
+      .await
+      .map_err(|e| {
+        error!("Failed to insert blob: {}", e);
+        DatabaseError::new("Failed to insert blob")
+      })?;
+
+    Self::add_blob_refs(&txn, hash, 1).await?;
+
+    let mut model = match existing {
+      Some(m) => {
+        Self::add_blob_refs(&txn, &m.blob_hash, -1).await?;
+        m.into_active_model()
+      }
+      None =>
+        media_items::ActiveModel { user_id: Set(user_id), google_id: Set(item.google_id.clone()), ..Default::default() },
+    };
+
+    model.filename = Set(item.filename.clone());
+    model.mime_type = Set(item.mime_type.clone());
+    model.creation_time = Set(item.creation_time.clone());
+    model.blob_hash = Set(hash.to_string());
+    model.size = Set(size);
+    model.stored_at = Set(now);
+
+    let model = model.save(&txn).await.map_err(|e| {
+      error!("Failed to save media item: {}", e);
+      DatabaseError::new("Failed to save media item")
+    })?;
+
+    txn.commit().await.map_err(|e| {
+      error!("Failed to commit transaction: {}", e);
+      DatabaseError::new("Failed to commit transaction")
+    })?;
+
+    model.try_into_model().map_err(|e| {
+      error!("Failed to read media item: {}", e);
+      DatabaseError::new("Failed to read media item")
+    })
+  }
+
+  /// Get up to `limit` media items with an id above `after_id`, ordered by id
+  pub async fn get_media_items_after(
+    &self,
+    after_id: i32,
+    limit: u64,
+  ) -> Result<Vec<media_items::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    media_items::Entity::find()
+      .filter(media_items::Column::Id.gt(after_id))
+      .order_by_asc(media_items::Column::Id)
+      .limit(limit)
+      .all(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch media items: {}", e);
+        DatabaseError::new("Failed to fetch media items")
+      })
+  }
+
+  /// Set the layout path of a media item and the hash of the blob the copy
+  /// there was made from
+  ///
+  /// Returns false if another item already has that path or a DatabaseError
+  /// if the operation failed
+  pub async fn set_media_item_path(
+    &self,
+    id: i32,
+    path: Option<String>,
+    hash: Option<String>,
+  ) -> Result<bool, DatabaseError> {
+    let db = self.connection().await?;
+
+    let res = media_items::Entity::update_many()
+      .col_expr(media_items::Column::Path, Expr::value(path))
+      .col_expr(media_items::Column::PathHash, Expr::value(hash))
+      .filter(media_items::Column::Id.eq(id))
+      .exec(db)
+      .await;
+
+    match res {
+      Ok(_) => Ok(true),
+      Err(e) if matches!(e.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => Ok(false),
+      Err(e) => {
+        error!("Failed to set media item path: {}", e);
+        Err(DatabaseError::new("Failed to set media item path"))
+      }
+    }
+  }
+
+  /// Remove a media item of a user, releasing its blob
+  ///
+  /// Returns Ok(()) if the item was removed (or did not exist) or a
+  /// DatabaseError if the operation failed
+  pub async fn delete_media_item(&self, user_id: i32, google_id: &str) -> Result<(), DatabaseError> {
+    let db = self.connection().await?;
+
+    let txn = db.begin().await.map_err(|e| {
+      error!("Failed to start transaction: {}", e);
+      DatabaseError::new("Failed to start transaction")
+    })?;
+
+    let item = media_items::Entity::find()
+      .filter(media_items::Column::UserId.eq(user_id))
+      .filter(media_items::Column::GoogleId.eq(google_id))
+      .lock_exclusive()
+      .one(&txn)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch media item: {}", e);
+        DatabaseError::new("Failed to fetch media item")
+      })?;
+
+    if let Some(item) = item {
+      Self::add_blob_refs(&txn, &item.blob_hash, -1).await?;
+      media_items::Entity::delete_by_id(item.id).exec(&txn).await.map_err(|e| {
+        error!("Failed to delete media item: {}", e);
+        DatabaseError::new("Failed to delete media item")
+      })?;
+    }
+
+    txn.commit().await.map_err(|e| {
+      error!("Failed to commit transaction: {}", e);
+      DatabaseError::new("Failed to commit transaction")
+    })?;
+
     Ok(())
   }
+
+  /// Release the blobs of every media item of a user, used before the user
+  /// (and with it their items) is deleted
+  async fn release_user_media<C: ConnectionTrait>(conn: &C, user_id: i32) -> Result<(), DatabaseError> {
+    let hashes: Vec<String> = media_items::Entity::find()
+      .select_only()
+      .column(media_items::Column::BlobHash)
+      .filter(media_items::Column::UserId.eq(user_id))
+      .into_tuple()
+      .all(conn)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch media items: {}", e);
+        DatabaseError::new("Failed to fetch media items")
+      })?;
+
+    let mut counts: HashMap<String, i32> = HashMap::new();
+    for hash in hashes {
+      *counts.entry(hash).or_default() += 1;
+    }
+
+    for (hash, count) in counts {
+      Self::add_blob_refs(conn, &hash, -count).await?;
+    }
+
+    Ok(())
+  }
+
+  async fn add_blob_refs<C: ConnectionTrait>(conn: &C, hash: &str, delta: i32) -> Result<(), DatabaseError> {
+    blobs::Entity::update_many()
+      .col_expr(blobs::Column::RefCount, Expr::col(blobs::Column::RefCount).add(delta))
+      .filter(blobs::Column::Hash.eq(hash))
+      .exec(conn)
+      .await
+      .map_err(|e| {
+        error!("Failed to update blob references: {}", e);
+        DatabaseError::new("Failed to update blob references")
+      })?;
+
+    Ok(())
+  }
+
+  /// Get the hashes of every blob in the database
+  pub async fn get_blob_hashes(&self) -> Result<HashSet<String>, DatabaseError> {
+    let db = self.connection().await?;
+
+    let hashes: Vec<String> =
+      blobs::Entity::find().select_only().column(blobs::Column::Hash).into_tuple().all(db).await.map_err(|e| {
+        error!("Failed to fetch blob hashes: {}", e);
+        DatabaseError::new("Failed to fetch blob hashes")
+      })?;
+
+    Ok(hashes.into_iter().collect())
+  }
+
+  /// Get every blob that is no longer referenced by a media item
+  pub async fn get_unreferenced_blobs(&self) -> Result<Vec<blobs::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    blobs::Entity::find().filter(blobs::Column::RefCount.lte(0)).all(db).await.map_err(|e| {
+      error!("Failed to fetch unreferenced blobs: {}", e);
+      DatabaseError::new("Failed to fetch unreferenced blobs")
+    })
+  }
+
+  /// Delete a blob row if nothing references it anymore
+  ///
+  /// Returns true if the blob was deleted
+  pub async fn delete_blob(&self, hash: &str) -> Result<bool, DatabaseError> {
+    let db = self.connection().await?;
+
+    let res = blobs::Entity::delete_many()
+      .filter(blobs::Column::Hash.eq(hash))
+      .filter(blobs::Column::RefCount.lte(0))
+      .exec(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to delete blob: {}", e);
+        DatabaseError::new("Failed to delete blob")
+      })?;
+
+    Ok(res.rows_affected > 0)
+  }
+
+  /// Save an album of a user and replace its items with `item_ids`, in album
+  /// order
+  ///
+  /// Returns the album or a DatabaseError if the operation failed
+  pub async fn set_album(
+    &self,
+    user_id: i32,
+    album: &AlbumInfo,
+    item_ids: &[String],
+  ) -> Result<albums::Model, DatabaseError> {
+    let db = self.connection().await?;
+
+    let txn = db.begin().await.map_err(|e| {
+      error!("Failed to start transaction: {}", e);
+      DatabaseError::new("Failed to start transaction")
+    })?;
+
+    let existing = albums::Entity::find()
+      .filter(albums::Column::UserId.eq(user_id))
+      .filter(albums::Column::GoogleId.eq(album.google_id.clone()))
+      .one(&txn)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch album: {}", e);
+        DatabaseError::new("Failed to fetch album")
+      })?;
+
+    let mut model = match existing {
+      Some(m) => m.into_active_model(),
+      None =>
+        albums::ActiveModel { user_id: Set(user_id), google_id: Set(album.google_id.clone()), ..Default::default() },
+    };
+
+    model.title = Set(album.title.clone());
+    model.shared = Set(album.shared);
+    model.cover_media_item_id = Set(album.cover_media_item_id.clone());
+    model.item_count = Set(item_ids.len() as i32);
+    model.synced_at = Set(unix_timestamp());
+
+    let model = model.save(&txn).await.map_err(|e| {
+      error!("Failed to save album: {}", e);
+      DatabaseError::new("Failed to save album")
+    })?;
+    let model = model.try_into_model().map_err(|e| {
+      error!("Failed to read album: {}", e);
+      DatabaseError::new("Failed to read album")
+    })?;
+
+    album_items::Entity::delete_many().filter(album_items::Column::AlbumId.eq(model.id)).exec(&txn).await.map_err(
+      |e| {
+        error!("Failed to clear album items: {}", e);
+        DatabaseError::new("Failed to clear album items")
+      },
+    )?;
+
+    // The same item can not be in an album twice, but be careful anyway
+    let mut seen = HashSet::new();
+    let items: Vec<album_items::ActiveModel> = item_ids
+      .iter()
+      .filter(|id| seen.insert(*id))
+      .enumerate()
+      .map(|(i, id)| album_items::ActiveModel {
+        album_id: Set(model.id),
+        media_item_google_id: Set(id.clone()),
+        position: Set(i as i32),
+      })
+      .collect();
+
+    for chunk in items.chunks(1000) {
+      album_items::Entity::insert_many(chunk.to_vec()).exec_without_returning(&txn).await.map_err(|e| {
+        error!("Failed to insert album items: {}", e);
+        DatabaseError::new("Failed to insert album items")
+      })?;
+    }
+
+    txn.commit().await.map_err(|e| {
+      error!("Failed to commit transaction: {}", e);
+      DatabaseError::new("Failed to commit transaction")
+    })?;
+
+    Ok(model)
+  }
+
+  /// Delete the albums of a user that are not in `keep`, used to forget albums
+  /// that were deleted in google photos
+  ///
+  /// Returns the number of deleted albums
+  pub async fn delete_albums_except(&self, user_id: i32, keep: &[String]) -> Result<u64, DatabaseError> {
+    let db = self.connection().await?;
+
+    let res = albums::Entity::delete_many()
+      .filter(albums::Column::UserId.eq(user_id))
+      .filter(albums::Column::GoogleId.is_not_in(keep.iter().cloned()))
+      .exec(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to delete albums: {}", e);
+        DatabaseError::new("Failed to delete albums")
+      })?;
+
+    Ok(res.rows_affected)
+  }
+
+  /// Get every album of a user, ordered by title
+  pub async fn get_albums(&self, user_id: i32) -> Result<Vec<albums::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    albums::Entity::find()
+      .filter(albums::Column::UserId.eq(user_id))
+      .order_by_asc(albums::Column::Title)
+      .all(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch albums: {}", e);
+        DatabaseError::new("Failed to fetch albums")
+      })
+  }
+
+  /// Get the google ids of the items in an album of a user, in album order
+  ///
+  /// Returns Ok(None) if the user has no such album
+  pub async fn get_album_items(&self, user_id: i32, album_id: i32) -> Result<Option<Vec<String>>, DatabaseError> {
+    let db = self.connection().await?;
+
+    let album =
+      albums::Entity::find_by_id(album_id).filter(albums::Column::UserId.eq(user_id)).one(db).await.map_err(|e| {
+        error!("Failed to fetch album: {}", e);
+        DatabaseError::new("Failed to fetch album")
+      })?;
+    if album.is_none() {
+      return Ok(None);
+    }
+
+    let ids: Vec<String> = album_items::Entity::find()
+      .select_only()
+      .column(album_items::Column::MediaItemGoogleId)
+      .filter(album_items::Column::AlbumId.eq(album_id))
+      .order_by_asc(album_items::Column::Position)
+      .into_tuple()
+      .all(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch album items: {}", e);
+        DatabaseError::new("Failed to fetch album items")
+      })?;
+
+    Ok(Some(ids))
+  }
+
+  /// Get the first album (oldest archived) of a user that contains a media
+  /// item
+  pub async fn get_item_album(&self, user_id: i32, google_id: &str) -> Result<Option<albums::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    albums::Entity::find()
+      .join(JoinType::InnerJoin, albums::Relation::AlbumItems.def())
+      .filter(albums::Column::UserId.eq(user_id))
+      .filter(album_items::Column::MediaItemGoogleId.eq(google_id))
+      .order_by_asc(albums::Column::Id)
+      .one(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch album of media item: {}", e);
+        DatabaseError::new("Failed to fetch album of media item")
+      })
+  }
+
+  /// Get the media items of a user that could not be downloaded yet because
+  /// google is still processing them
+  pub async fn get_pending_media_items(&self, user_id: i32) -> Result<Vec<pending_media_items::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    pending_media_items::Entity::find()
+      .filter(pending_media_items::Column::UserId.eq(user_id))
+      .order_by_asc(pending_media_items::Column::NextAttemptAt)
+      .all(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch pending media items: {}", e);
+        DatabaseError::new("Failed to fetch pending media items")
+      })
+  }
+
+  /// Record that a media item of a user is not ready to be downloaded, with
+  /// its google side `status` and when to check it again. Every call counts
+  /// as an attempt
+  ///
+  /// Returns the pending item or a DatabaseError if the operation failed
+  pub async fn set_media_item_pending(
+    &self,
+    user_id: i32,
+    google_id: &str,
+    status: &str,
+    next_attempt_at: i64,
+  ) -> Result<pending_media_items::Model, DatabaseError> {
+    let db = self.connection().await?;
+
+    let existing =
+      pending_media_items::Entity::find_by_id((user_id, google_id.to_string())).one(db).await.map_err(|e| {
+        error!("Failed to fetch pending media item: {}", e);
+        DatabaseError::new("Failed to fetch pending media item")
+      })?;
+
+    let model = match existing {
+      Some(m) => {
+        let attempts = m.attempts + 1;
+        let mut model = m.into_active_model();
+        model.attempts = Set(attempts);
+        model.status = Set(status.to_string());
+        model.next_attempt_at = Set(next_attempt_at);
+        model.update(db).await
+      }
+      None =>
+        pending_media_items::ActiveModel {
+          user_id: Set(user_id),
+          google_id: Set(google_id.to_string()),
+          status: Set(status.to_string()),
+          attempts: Set(1),
+          first_seen_at: Set(unix_timestamp()),
+          next_attempt_at: Set(next_attempt_at),
+        }
+        .insert(db)
+        .await,
+    };
+
+    model.map_err(|e| {
+      error!("Failed to save pending media item: {}", e);
+      DatabaseError::new("Failed to save pending media item")
+    })
+  }
+
+  /// Forget a pending media item, once it is archived or gone from google
+  pub async fn delete_pending_media_item(&self, user_id: i32, google_id: &str) -> Result<(), DatabaseError> {
+    let db = self.connection().await?;
+
+    pending_media_items::Entity::delete_by_id((user_id, google_id.to_string())).exec(db).await.map_err(|e| {
+      error!("Failed to delete pending media item: {}", e);
+      DatabaseError::new("Failed to delete pending media item")
+    })?;
+
+    Ok(())
+  }
+
+  /// Record a sync job that starts now for a user, `filters` and `progress`
+  /// are JSON
+  ///
+  /// Returns the new job or a DatabaseError if the operation failed
+  pub async fn new_sync_job(
+    &self,
+    user_id: i32,
+    filters: Option<String>,
+    progress: String,
+  ) -> Result<sync_jobs::Model, DatabaseError> {
+    let db = self.connection().await?;
+    let now = unix_timestamp();
+
+    sync_jobs::ActiveModel {
+      user_id: Set(user_id),
+      status: Set(SyncJobStatus::Running.to_string()),
+      filters: Set(filters),
+      page_token: Set(None),
+      progress: Set(progress),
+      started_at: Set(now),
+      updated_at: Set(now),
+      finished_at: Set(None),
+      error: Set(None),
+      ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map_err(|e| {
+      error!("Failed to create sync job: {}", e);
+      DatabaseError::new("Failed to create sync job")
+    })
+  }
+
+  /// Save where a sync job is at: the token of the page it has to list next
+  /// (None for the first one) and its progress as JSON. Finished and failed
+  /// jobs get their finish time set
+  pub async fn checkpoint_sync_job(
+    &self,
+    id: i32,
+    status: SyncJobStatus,
+    page_token: Option<String>,
+    progress: String,
+    error: Option<String>,
+  ) -> Result<(), DatabaseError> {
+    let db = self.connection().await?;
+    let now = unix_timestamp();
+
+    let finished_at = match status {
+      SyncJobStatus::Finished | SyncJobStatus::Failed => Some(now),
+      SyncJobStatus::Running | SyncJobStatus::Interrupted => None,
+    };
+
+    sync_jobs::Entity::update_many()
+      .col_expr(sync_jobs::Column::Status, Expr::value(status.to_string()))
+      .col_expr(sync_jobs::Column::PageToken, Expr::value(page_token))
+      .col_expr(sync_jobs::Column::Progress, Expr::value(progress))
+      .col_expr(sync_jobs::Column::UpdatedAt, Expr::value(now))
+      .col_expr(sync_jobs::Column::FinishedAt, Expr::value(finished_at))
+      .col_expr(sync_jobs::Column::Error, Expr::value(error))
+      .filter(sync_jobs::Column::Id.eq(id))
+      .exec(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to checkpoint sync job {}: {}", id, e);
+        DatabaseError::new("Failed to checkpoint sync job")
+      })?;
+
+    Ok(())
+  }
+
+  /// Get the sync jobs with a status, oldest first
+  pub async fn get_sync_jobs(&self, status: SyncJobStatus) -> Result<Vec<sync_jobs::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    sync_jobs::Entity::find()
+      .filter(sync_jobs::Column::Status.eq(status.to_string()))
+      .order_by_asc(sync_jobs::Column::Id)
+      .all(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch sync jobs: {}", e);
+        DatabaseError::new("Failed to fetch sync jobs")
+      })
+  }
+
+  /// Get the sync schedules of a user, oldest first
+  pub async fn get_sync_schedules(&self, user_id: i32) -> Result<Vec<sync_schedules::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    sync_schedules::Entity::find()
+      .filter(sync_schedules::Column::UserId.eq(user_id))
+      .order_by_asc(sync_schedules::Column::Id)
+      .all(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch sync schedules: {}", e);
+        DatabaseError::new("Failed to fetch sync schedules")
+      })
+  }
+
+  /// Get a sync schedule of a user
+  ///
+  /// Returns the schedule, None if the user has no schedule with that id, or a
+  /// DatabaseError if the query failed
+  pub async fn get_sync_schedule(&self, user_id: i32, id: i32) -> Result<Option<sync_schedules::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    sync_schedules::Entity::find_by_id(id).filter(sync_schedules::Column::UserId.eq(user_id)).one(db).await.map_err(
+      |e| {
+        error!("Failed to fetch sync schedule: {}", e);
+        DatabaseError::new("Failed to fetch sync schedule")
+      },
+    )
+  }
+
+  /// Creates a sync schedule for a user, `filters` are JSON
+  ///
+  /// Returns the new schedule or a DatabaseError if the operation failed
+  pub async fn new_sync_schedule(
+    &self,
+    user_id: i32,
+    cron: String,
+    enabled: bool,
+    filters: Option<String>,
+    next_run_at: Option<i64>,
+  ) -> Result<sync_schedules::Model, DatabaseError> {
+    let db = self.connection().await?;
+
+    sync_schedules::ActiveModel {
+      user_id: Set(user_id),
+      cron: Set(cron),
+      enabled: Set(enabled),
+      filters: Set(filters),
+      last_run_at: Set(None),
+      next_run_at: Set(next_run_at),
+      created_at: Set(unix_timestamp()),
+      ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map_err(|e| {
+      error!("Failed to create sync schedule: {}", e);
+      DatabaseError::new("Failed to create sync schedule")
+    })
+  }
+
+  /// Save changes to a sync schedule
+  ///
+  /// Returns the updated schedule or a DatabaseError if the operation failed
+  pub async fn update_sync_schedule(
+    &self,
+    schedule: sync_schedules::Model,
+  ) -> Result<sync_schedules::Model, DatabaseError> {
+    let db = self.connection().await?;
+
+    schedule.into_active_model().reset_all().update(db).await.map_err(|e| {
+      error!("Failed to update sync schedule: {}", e);
+      DatabaseError::new("Failed to update sync schedule")
+    })
+  }
+
+  /// Delete a sync schedule of a user
+  ///
+  /// Returns whether the user had a schedule with that id or a DatabaseError
+  /// if the operation failed
+  pub async fn delete_sync_schedule(&self, user_id: i32, id: i32) -> Result<bool, DatabaseError> {
+    let db = self.connection().await?;
+
+    let res = sync_schedules::Entity::delete_many()
+      .filter(sync_schedules::Column::Id.eq(id))
+      .filter(sync_schedules::Column::UserId.eq(user_id))
+      .exec(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to delete sync schedule: {}", e);
+        DatabaseError::new("Failed to delete sync schedule")
+      })?;
+
+    Ok(res.rows_affected > 0)
+  }
+
+  /// Get the enabled sync schedules that were due to run at `now` (a unix
+  /// timestamp) or before
+  pub async fn get_due_sync_schedules(&self, now: i64) -> Result<Vec<sync_schedules::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    sync_schedules::Entity::find()
+      .filter(sync_schedules::Column::Enabled.eq(true))
+      .filter(sync_schedules::Column::NextRunAt.lte(now))
+      .order_by_asc(sync_schedules::Column::NextRunAt)
+      .all(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch due sync schedules: {}", e);
+        DatabaseError::new("Failed to fetch due sync schedules")
+      })
+  }
+
+  /// Save when a sync schedule last started a job, if it did, and when it is
+  /// due next
+  pub async fn set_sync_schedule_run(
+    &self,
+    id: i32,
+    last_run_at: Option<i64>,
+    next_run_at: Option<i64>,
+  ) -> Result<(), DatabaseError> {
+    let db = self.connection().await?;
+
+    let mut update = sync_schedules::Entity::update_many()
+      .col_expr(sync_schedules::Column::NextRunAt, Expr::value(next_run_at))
+      .filter(sync_schedules::Column::Id.eq(id));
+    if let Some(last_run_at) = last_run_at {
+      update = update.col_expr(sync_schedules::Column::LastRunAt, Expr::value(last_run_at));
+    }
+
+    update.exec(db).await.map_err(|e| {
+      error!("Failed to update sync schedule {}: {}", id, e);
+      DatabaseError::new("Failed to update sync schedule")
+    })?;
+
+    Ok(())
+  }
+
+  /// Record a verification of the archive that starts now, `options` are JSON.
+  /// `started_by` is None when it was started from the command line
+  ///
+  /// Returns the new run or a DatabaseError if the operation failed
+  pub async fn new_verify_run(
+    &self,
+    started_by: Option<i32>,
+    options: String,
+  ) -> Result<verify_runs::Model, DatabaseError> {
+    let db = self.connection().await?;
+
+    verify_runs::ActiveModel {
+      started_by: Set(started_by),
+      status: Set(VerifyRunStatus::Running.to_string()),
+      options: Set(options),
+      report: Set(None),
+      started_at: Set(unix_timestamp()),
+      finished_at: Set(None),
+      error: Set(None),
+      ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map_err(|e| {
+      error!("Failed to create verify run: {}", e);
+      DatabaseError::new("Failed to create verify run")
+    })
+  }
+
+  /// Save the outcome of a verify run, `report` is JSON
+  pub async fn finish_verify_run(
+    &self,
+    id: i32,
+    status: VerifyRunStatus,
+    report: Option<String>,
+    error: Option<String>,
+  ) -> Result<(), DatabaseError> {
+    let db = self.connection().await?;
+
+    verify_runs::Entity::update_many()
+      .col_expr(verify_runs::Column::Status, Expr::value(status.to_string()))
+      .col_expr(verify_runs::Column::Report, Expr::value(report))
+      .col_expr(verify_runs::Column::FinishedAt, Expr::value(unix_timestamp()))
+      .col_expr(verify_runs::Column::Error, Expr::value(error))
+      .filter(verify_runs::Column::Id.eq(id))
+      .exec(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to finish verify run {}: {}", id, e);
+        DatabaseError::new("Failed to finish verify run")
+      })?;
+
+    Ok(())
+  }
+
+  /// Mark every verify run that is still running as failed with `error`, for
+  /// runs that were cut off by a restart
+  ///
+  /// Returns the number of runs marked
+  pub async fn fail_running_verify_runs(&self, error: &str) -> Result<u64, DatabaseError> {
+    let db = self.connection().await?;
+
+    let res = verify_runs::Entity::update_many()
+      .col_expr(verify_runs::Column::Status, Expr::value(VerifyRunStatus::Failed.to_string()))
+      .col_expr(verify_runs::Column::FinishedAt, Expr::value(unix_timestamp()))
+      .col_expr(verify_runs::Column::Error, Expr::value(error))
+      .filter(verify_runs::Column::Status.eq(VerifyRunStatus::Running.to_string()))
+      .exec(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to update verify runs: {}", e);
+        DatabaseError::new("Failed to update verify runs")
+      })?;
+
+    Ok(res.rows_affected)
+  }
+
+  /// Get a verify run by id
+  pub async fn get_verify_run(&self, id: i32) -> Result<Option<verify_runs::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    verify_runs::Entity::find_by_id(id).one(db).await.map_err(|e| {
+      error!("Failed to fetch verify run: {}", e);
+      DatabaseError::new("Failed to fetch verify run")
+    })
+  }
+
+  /// Get the last `limit` verify runs, newest first, without their reports
+  pub async fn get_verify_runs(&self, limit: u64) -> Result<Vec<verify_runs::Model>, DatabaseError> {
+    let db = self.connection().await?;
+
+    let runs =
+      verify_runs::Entity::find().order_by_desc(verify_runs::Column::Id).limit(limit).all(db).await.map_err(|e| {
+        error!("Failed to fetch verify runs: {}", e);
+        DatabaseError::new("Failed to fetch verify runs")
+      })?;
+
+    Ok(runs.into_iter().map(|r| verify_runs::Model { report: None, ..r }).collect())
+  }
+
+  /// Get the dedup savings of every user with archived media and of the whole
+  /// archive
+  ///
+  /// Returns the per user stats and the overall stats or a DatabaseError if
+  /// the query failed
+  pub async fn get_dedup_stats(&self) -> Result<(Vec<DedupStats>, DedupStats), DatabaseError> {
+    let db = self.connection().await?;
+
+    let rows = media_items::Entity::find()
+      .select_only()
+      .column(media_items::Column::UserId)
+      .column_as(Expr::cust("COUNT(*)"), "items")
+      .column_as(Expr::cust("CAST(SUM(media_items.size) AS BIGINT)"), "logical_bytes")
+      .column_as(
+        Expr::cust("CAST(SUM(CAST(blobs.size AS DOUBLE PRECISION) / GREATEST(blobs.ref_count, 1)) AS BIGINT)"),
+        "stored_bytes",
+      )
+      .column_as(Expr::cust(LAYOUT_BYTES), "layout_bytes")
+      .join(JoinType::InnerJoin, media_items::Relation::Blobs.def())
+      .group_by(media_items::Column::UserId)
+      .order_by_asc(media_items::Column::UserId)
+      .into_model::<DedupRow>()
+      .all(db)
+      .await
+      .map_err(|e| {
+        error!("Failed to fetch dedup stats: {}", e);
+        DatabaseError::new("Failed to fetch dedup stats")
+      })?;
+
+    let users = rows
+      .into_iter()
+      .map(|r| DedupStats {
+        user_id: Some(r.user_id),
+        items: r.items,
+        logical_bytes: r.logical_bytes,
+        stored_bytes: r.stored_bytes,
+        saved_bytes: r.logical_bytes - r.stored_bytes,
+        layout_bytes: r.layout_bytes,
+      })
+      .collect();
+
+    let items = media_items::Entity::find()
+      .select_only()
+      .column_as(Expr::cust("COUNT(*)"), "count")
+      .column_as(Expr::cust("CAST(COALESCE(SUM(size), 0) AS BIGINT)"), "bytes")
+      .column_as(Expr::cust(LAYOUT_BYTES), "layout_bytes")
+      .into_model::<TotalRow>()
+      .one(db)
+      .await;
+    let stored = blobs::Entity::find()
+      .select_only()
+      .column_as(Expr::cust("COUNT(*)"), "count")
+      .column_as(Expr::cust("CAST(COALESCE(SUM(size), 0) AS BIGINT)"), "bytes")
+      .column_as(Expr::cust("CAST(0 AS BIGINT)"), "layout_bytes")
+      .filter(blobs::Column::RefCount.gt(0))
+      .into_model::<TotalRow>()
+      .one(db)
+      .await;
+
+    let (items, stored) = match (items, stored) {
+      (Ok(Some(i)), Ok(Some(s))) => (i, s),
+      (Err(e), _) | (_, Err(e)) => {
+        error!("Failed to fetch dedup totals: {}", e);
+        return Err(DatabaseError::new("Failed to fetch dedup totals"));
+      }
+      _ => return Err(DatabaseError::new("Failed to fetch dedup totals")),
+    };
+
+    let overall = DedupStats {
+      user_id: None,
+      items: items.count,
+      logical_bytes: items.bytes,
+      stored_bytes: stored.bytes,
+      saved_bytes: items.bytes - stored.bytes,
+      layout_bytes: items.layout_bytes,
+    };
+
+    Ok((users, overall))
+  }
+}
+
+#[derive(FromQueryResult)]
+struct DedupRow {
+  user_id: i32,
+  items: i64,
+  logical_bytes: i64,
+  stored_bytes: i64,
+  layout_bytes: i64,
+}
+
+#[derive(FromQueryResult)]
+struct TotalRow {
+  count: i64,
+  bytes: i64,
+  layout_bytes: i64,
+}
+
+/// Size of the items that have a browsable copy
+const LAYOUT_BYTES: &str =
+  "CAST(COALESCE(SUM(CASE WHEN media_items.path IS NULL THEN 0 ELSE media_items.size END), 0) AS BIGINT)";
+
+fn unix_timestamp() -> i64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default()
 }
@@ -2,4 +2,15 @@
 
 pub mod prelude;
 
+pub mod album_items;
+pub mod albums;
+pub mod audit_events;
+pub mod blobs;
+pub mod google_accounts;
+pub mod invites;
+pub mod media_items;
+pub mod pending_media_items;
+pub mod sync_jobs;
+pub mod sync_schedules;
 pub mod users;
+pub mod verify_runs;
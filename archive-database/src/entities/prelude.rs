@@ -1,3 +1,14 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.0.1
 
+pub use super::album_items::Entity as AlbumItems;
+pub use super::albums::Entity as Albums;
+pub use super::audit_events::Entity as AuditEvents;
+pub use super::blobs::Entity as Blobs;
+pub use super::google_accounts::Entity as GoogleAccounts;
+pub use super::invites::Entity as Invites;
+pub use super::media_items::Entity as MediaItems;
+pub use super::pending_media_items::Entity as PendingMediaItems;
+pub use super::sync_jobs::Entity as SyncJobs;
+pub use super::sync_schedules::Entity as SyncSchedules;
 pub use super::users::Entity as Users;
+pub use super::verify_runs::Entity as VerifyRuns;
@@ -12,9 +12,67 @@ pub struct Model {
   pub username: String,
   pub password_hash: String,
   pub created_at: Option<i64>,
+  pub role: String,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+  #[sea_orm(has_many = "super::albums::Entity")]
+  Albums,
+  #[sea_orm(has_one = "super::google_accounts::Entity")]
+  GoogleAccounts,
+  #[sea_orm(has_many = "super::media_items::Entity")]
+  MediaItems,
+  #[sea_orm(has_many = "super::pending_media_items::Entity")]
+  PendingMediaItems,
+  #[sea_orm(has_many = "super::sync_jobs::Entity")]
+  SyncJobs,
+  #[sea_orm(has_many = "super::sync_schedules::Entity")]
+  SyncSchedules,
+  #[sea_orm(has_many = "super::verify_runs::Entity")]
+  VerifyRuns,
+}
+
+impl Related<super::albums::Entity> for Entity {
+  fn to() -> RelationDef {
+    Relation::Albums.def()
+  }
+}
+
+impl Related<super::google_accounts::Entity> for Entity {
+  fn to() -> RelationDef {
+    Relation::GoogleAccounts.def()
+  }
+}
+
+impl Related<super::media_items::Entity> for Entity {
+  fn to() -> RelationDef {
+    Relation::MediaItems.def()
+  }
+}
+
+impl Related<super::pending_media_items::Entity> for Entity {
+  fn to() -> RelationDef {
+    Relation::PendingMediaItems.def()
+  }
+}
+
+impl Related<super::sync_jobs::Entity> for Entity {
+  fn to() -> RelationDef {
+    Relation::SyncJobs.def()
+  }
+}
+
+impl Related<super::sync_schedules::Entity> for Entity {
+  fn to() -> RelationDef {
+    Relation::SyncSchedules.def()
+  }
+}
+
+impl Related<super::verify_runs::Entity> for Entity {
+  fn to() -> RelationDef {
+    Relation::VerifyRuns.def()
+  }
+}
 
 impl ActiveModelBehavior for ActiveModel {}
@@ -1,8 +1,12 @@
 use core::fmt;
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
-use crate::entities::users;
+use crate::entities::{google_accounts, users};
+
+/// Scope needed to read the google photos library
+pub const PHOTOS_READONLY_SCOPE: &str = "https://www.googleapis.com/auth/photoslibrary.readonly";
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct User {
@@ -11,11 +15,24 @@ pub struct User {
   session_token: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+  Member,
+  Admin,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct GUser {
   auth_token: String,
   name: String,
   pfp_url: String,
+  scopes: Vec<String>,
+  /// Never sent to the browser
+  #[serde(skip)]
+  refresh_token: Option<String>,
+  /// Unix time the auth token stops working
+  expires_at: Option<i64>,
 }
 
 impl User {
@@ -26,6 +43,7 @@ impl User {
         password_hash: password_hash.to_string(),
         id: -1,
         created_at: None,
+        role: Role::Member.to_string(),
       },
       guser: None,
       session_token: None,
@@ -52,6 +70,11 @@ impl User {
     self.model.created_at
   }
 
+  #[inline]
+  pub fn get_role(&self) -> Role {
+    Role::from_str(&self.model.role).unwrap_or(Role::Member)
+  }
+
   #[inline]
   pub fn get_guser(&self) -> Option<GUser> {
     self.guser.clone()
@@ -72,20 +95,129 @@ impl User {
     self.model.password_hash = new_password_hash.to_string()
   }
 
+  #[inline]
+  pub fn set_role(&mut self, role: Role) {
+    self.model.role = role.to_string()
+  }
+
   #[inline]
   pub fn set_guser(&mut self, guser: GUser) {
     self.guser = Some(guser);
   }
 
+  #[inline]
+  pub fn remove_guser(&mut self) {
+    self.guser = None;
+  }
+
   #[inline]
   pub fn set_session_token<S: ToString>(&mut self, session_token: S) {
     self.session_token = Some(session_token.to_string())
   }
 }
 
+impl fmt::Display for Role {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Member => write!(f, "member"),
+      Self::Admin => write!(f, "admin"),
+    }
+  }
+}
+
+impl FromStr for Role {
+  type Err = DatabaseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "member" => Ok(Self::Member),
+      "admin" => Ok(Self::Admin),
+      _ => Err(DatabaseError::new(format!("Unknown role '{}'", s))),
+    }
+  }
+}
+
+/// How creating a user went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Registration {
+  Created,
+  /// Another user has the username
+  UsernameTaken,
+  /// The invite code does not exist, was used or expired
+  InvalidInvite,
+}
+
+/// Where a sync job is at. Interrupted jobs were stopped by a shutdown and
+/// are resumed from their checkpoint when the server starts again
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncJobStatus {
+  Running,
+  Interrupted,
+  Finished,
+  Failed,
+}
+
+impl fmt::Display for SyncJobStatus {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Running => write!(f, "running"),
+      Self::Interrupted => write!(f, "interrupted"),
+      Self::Finished => write!(f, "finished"),
+      Self::Failed => write!(f, "failed"),
+    }
+  }
+}
+
+impl FromStr for SyncJobStatus {
+  type Err = DatabaseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "running" => Ok(Self::Running),
+      "interrupted" => Ok(Self::Interrupted),
+      "finished" => Ok(Self::Finished),
+      "failed" => Ok(Self::Failed),
+      _ => Err(DatabaseError::new(format!("Unknown sync job status '{}'", s))),
+    }
+  }
+}
+
+/// State of an archive verification run
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyRunStatus {
+  Running,
+  Finished,
+  Failed,
+}
+
+impl fmt::Display for VerifyRunStatus {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Running => write!(f, "running"),
+      Self::Finished => write!(f, "finished"),
+      Self::Failed => write!(f, "failed"),
+    }
+  }
+}
+
+impl FromStr for VerifyRunStatus {
+  type Err = DatabaseError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "running" => Ok(Self::Running),
+      "finished" => Ok(Self::Finished),
+      "failed" => Ok(Self::Failed),
+      _ => Err(DatabaseError::new(format!("Unknown verify run status '{}'", s))),
+    }
+  }
+}
+
 impl GUser {
   pub fn new(auth_token: String, username: String, pfp_url: String) -> Self {
-    Self { auth_token, name: username, pfp_url }
+    Self { auth_token, name: username, pfp_url, scopes: Vec::new(), refresh_token: None, expires_at: None }
   }
 
   pub fn get_name(&self) -> &str {
@@ -100,6 +232,30 @@ impl GUser {
     &self.pfp_url
   }
 
+  /// The token to get a new auth token with once it expires, None for accounts
+  /// linked without offline access
+  pub fn get_refresh_token(&self) -> Option<&str> {
+    self.refresh_token.as_deref()
+  }
+
+  pub fn get_expires_at(&self) -> Option<i64> {
+    self.expires_at
+  }
+
+  /// The scopes the user actually granted on the consent screen
+  pub fn get_scopes(&self) -> &[String] {
+    &self.scopes
+  }
+
+  pub fn has_scope(&self, scope: &str) -> bool {
+    self.scopes.iter().any(|s| s == scope)
+  }
+
+  /// Whether the archiver is allowed to read the user's photo library
+  pub fn has_photos_access(&self) -> bool {
+    self.has_scope(PHOTOS_READONLY_SCOPE)
+  }
+
   pub fn set_auth_token<S: ToString>(&mut self, auth_token: S) {
     self.auth_token = auth_token.to_string();
   }
@@ -111,6 +267,18 @@ impl GUser {
   pub fn set_pfp_url<S: ToString>(&mut self, pfp_url: S) {
     self.pfp_url = pfp_url.to_string();
   }
+
+  pub fn set_scopes(&mut self, scopes: Vec<String>) {
+    self.scopes = scopes;
+  }
+
+  pub fn set_refresh_token(&mut self, refresh_token: Option<String>) {
+    self.refresh_token = refresh_token;
+  }
+
+  pub fn set_expires_at(&mut self, expires_at: Option<i64>) {
+    self.expires_at = expires_at;
+  }
 }
 
 impl From<users::Model> for User {
@@ -119,6 +287,64 @@ impl From<users::Model> for User {
   }
 }
 
+impl From<google_accounts::Model> for GUser {
+  fn from(value: google_accounts::Model) -> Self {
+    Self {
+      auth_token: value.access_token,
+      name: value.name,
+      pfp_url: value.pfp_url,
+      scopes: value.scopes.split_whitespace().map(|s| s.to_string()).collect(),
+      refresh_token: value.refresh_token,
+      expires_at: value.token_expires_at,
+    }
+  }
+}
+
+/// Filters for searching the audit log, every set field has to match
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AuditFilter {
+  pub action: Option<String>,
+  pub actor_id: Option<i32>,
+  pub target_id: Option<i32>,
+  pub since: Option<i64>,
+  pub until: Option<i64>,
+}
+
+/// Google metadata of a media item that is being archived
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MediaItemInfo {
+  pub google_id: String,
+  pub filename: String,
+  pub mime_type: String,
+  pub creation_time: Option<String>,
+}
+
+/// Google metadata of an album of a user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AlbumInfo {
+  pub google_id: String,
+  pub title: String,
+  pub shared: bool,
+  pub cover_media_item_id: Option<String>,
+}
+
+/// How much space deduplication saves for a user, or for the whole archive
+/// when `user_id` is None
+///
+/// A blob shared by several items is split evenly between them, so the stored
+/// bytes of all users add up to the stored bytes of the archive
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct DedupStats {
+  pub user_id: Option<i32>,
+  pub items: i64,
+  pub logical_bytes: i64,
+  pub stored_bytes: i64,
+  pub saved_bytes: i64,
+  /// Size of the browsable layout copies. Local storage hard links them to
+  /// the blobs, on object stores they take this much space on top
+  pub layout_bytes: i64,
+}
+
 #[derive(Debug)]
 pub struct DatabaseError {
   message: String,
@@ -0,0 +1,28 @@
+use core::fmt;
+
+#[derive(Debug, Clone)]
+pub enum StorageError {
+  NotFound(String),
+  InvalidKey(String),
+  IoError(String),
+  BackendError(String),
+}
+
+impl fmt::Display for StorageError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::NotFound(m) => write!(f, "Not found: {}", m),
+      Self::InvalidKey(m) => write!(f, "Invalid key: {}", m),
+      Self::IoError(m) => write!(f, "IO error: {}", m),
+      Self::BackendError(m) => write!(f, "Backend error: {}", m),
+    }
+  }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+  fn from(value: std::io::Error) -> Self {
+    Self::IoError(value.to_string())
+  }
+}
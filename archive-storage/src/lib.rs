@@ -0,0 +1,92 @@
+pub mod error;
+pub mod local;
+pub mod s3;
+
+use std::{ops::Range, sync::Arc};
+
+use archive_config::StorageConfig;
+use async_trait::async_trait;
+use error::StorageError;
+use local::LocalStorage;
+use s3::S3Storage;
+use serde::Serialize;
+use tokio::io::AsyncRead;
+
+pub type SharedStorage = Arc<dyn StorageBackend>;
+pub type ByteStream = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Information about a stored object
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ObjectMeta {
+  pub key: String,
+  pub size: u64,
+  /// Unix timestamp of the last modification, if the backend knows it
+  pub modified: Option<i64>,
+}
+
+/// Somewhere to keep archived media.
+///
+/// Keys are `/` separated relative paths (ex: `blobs/ab/abcdef...`), backends
+/// reject keys that are empty, absolute or contain `.` or `..` components
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+  /// Name of the backend, for logging
+  fn name(&self) -> &str;
+
+  /// Write everything in `stream` to `key`, replacing any existing object.
+  /// Readers never see a partially written object
+  ///
+  /// Returns the number of bytes written
+  async fn put(&self, key: &str, stream: ByteStream) -> Result<u64, StorageError>;
+
+  /// Read the bytes in `range` of `key`, or the whole object if `range` is
+  /// None
+  async fn get_range(&self, key: &str, range: Option<Range<u64>>) -> Result<ByteStream, StorageError>;
+
+  /// Delete `key`, deleting a key that does not exist is not an error
+  async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+  /// List every object whose key starts with `prefix`
+  async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError>;
+
+  /// Get information about `key`, Ok(None) if it does not exist
+  async fn stat(&self, key: &str) -> Result<Option<ObjectMeta>, StorageError>;
+
+  /// Check that objects can be written and deleted, by writing a small one
+  /// under `health/`
+  async fn check_writable(&self) -> Result<(), StorageError> {
+    let key = "health/probe";
+    self.put(key, Box::new(&b"ok"[..])).await?;
+    self.delete(key).await
+  }
+
+  /// Copy `from` to `to`, replacing `to` if it exists
+  ///
+  /// Returns the number of bytes copied
+  async fn copy(&self, from: &str, to: &str) -> Result<u64, StorageError> {
+    let stream = self.get_range(from, None).await?;
+    self.put(to, stream).await
+  }
+}
+
+/// Create the storage backend selected in the config
+pub fn from_config(config: &StorageConfig) -> Result<SharedStorage, StorageError> {
+  match config {
+    StorageConfig::Local { path } => Ok(Arc::new(LocalStorage::new(path)?)),
+    StorageConfig::S3 { endpoint, bucket, region, access_key, secret_key, path_style, prefix } =>
+      Ok(Arc::new(S3Storage::new(endpoint, bucket, region, access_key, secret_key, *path_style, prefix)?)),
+  }
+}
+
+/// Make sure `key` is a safe relative path
+pub(crate) fn validate_key(key: &str) -> Result<(), StorageError> {
+  if key.is_empty() || key.starts_with('/') || key.contains('\\') || key.contains('\0') {
+    return Err(StorageError::InvalidKey(key.to_string()));
+  }
+
+  if key.split('/').any(|c| c.is_empty() || c == "." || c == "..") {
+    return Err(StorageError::InvalidKey(key.to_string()));
+  }
+
+  Ok(())
+}
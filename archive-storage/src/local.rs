@@ -0,0 +1,192 @@
+use std::{
+  io::SeekFrom,
+  ops::Range,
+  path::{Path, PathBuf},
+  time::UNIX_EPOCH,
+};
+
+use async_trait::async_trait;
+use log::debug;
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::{
+  fs::{self, File},
+  io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{error::StorageError, validate_key, ByteStream, ObjectMeta, StorageBackend};
+
+/// Suffix of files that are still being written
+const TEMP_SUFFIX: &str = ".tmp-put";
+
+/// Stores objects as files under a root directory
+pub struct LocalStorage {
+  root: PathBuf,
+}
+
+impl LocalStorage {
+  pub fn new<P: AsRef<Path>>(root: P) -> Result<Self, StorageError> {
+    let root = root.as_ref().to_path_buf();
+    std::fs::create_dir_all(&root)?;
+    debug!("Using local storage at {}", root.display());
+
+    Ok(Self { root })
+  }
+
+  #[inline]
+  pub fn get_root(&self) -> &Path {
+    &self.root
+  }
+
+  /// Path of the file for `key`
+  pub fn path_for(&self, key: &str) -> Result<PathBuf, StorageError> {
+    validate_key(key)?;
+    Ok(self.root.join(key))
+  }
+
+  fn meta(key: String, metadata: &std::fs::Metadata) -> ObjectMeta {
+    let modified = metadata.modified().ok().and_then(|m| m.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs() as i64);
+    ObjectMeta { key, size: metadata.len(), modified }
+  }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+  fn name(&self) -> &str {
+    "local"
+  }
+
+  async fn put(&self, key: &str, mut stream: ByteStream) -> Result<u64, StorageError> {
+    let path = self.path_for(key)?;
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).await?;
+    }
+
+    // Write next to the target and rename so readers never see half a file
+    let suffix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(8).map(char::from).collect();
+    let temp_path = PathBuf::from(format!("{}.{}{}", path.display(), suffix, TEMP_SUFFIX));
+
+    let res = async {
+      let mut file = File::create(&temp_path).await?;
+      let written = io::copy(&mut stream, &mut file).await?;
+      file.flush().await?;
+      file.sync_all().await?;
+      fs::rename(&temp_path, &path).await?;
+      Ok::<u64, io::Error>(written)
+    }
+    .await;
+
+    match res {
+      Ok(w) => Ok(w),
+      Err(e) => {
+        let _ = fs::remove_file(&temp_path).await;
+        Err(e.into())
+      }
+    }
+  }
+
+  async fn get_range(&self, key: &str, range: Option<Range<u64>>) -> Result<ByteStream, StorageError> {
+    let path = self.path_for(key)?;
+    let mut file = match File::open(&path).await {
+      Ok(f) => f,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(StorageError::NotFound(key.to_string())),
+      Err(e) => return Err(e.into()),
+    };
+
+    match range {
+      Some(r) => {
+        file.seek(SeekFrom::Start(r.start)).await?;
+        Ok(Box::new(file.take(r.end.saturating_sub(r.start))))
+      }
+      None => Ok(Box::new(file)),
+    }
+  }
+
+  async fn delete(&self, key: &str) -> Result<(), StorageError> {
+    let path = self.path_for(key)?;
+    match fs::remove_file(&path).await {
+      Ok(_) => Ok(()),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError> {
+    let mut objects = Vec::new();
+    let mut dirs = vec![self.root.clone()];
+
+    while let Some(dir) = dirs.pop() {
+      let mut entries = match fs::read_dir(&dir).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e.into()),
+      };
+
+      while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_dir() {
+          dirs.push(entry.path());
+          continue;
+        }
+
+        let key = match entry.path().strip_prefix(&self.root).ok().and_then(|p| p.to_str()) {
+          Some(k) => k.replace(std::path::MAIN_SEPARATOR, "/"),
+          None => continue,
+        };
+
+        if key.starts_with(prefix) && !key.ends_with(TEMP_SUFFIX) {
+          objects.push(Self::meta(key, &metadata));
+        }
+      }
+    }
+
+    objects.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(objects)
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<ObjectMeta>, StorageError> {
+    let path = self.path_for(key)?;
+    match fs::metadata(&path).await {
+      Ok(m) if m.is_file() => Ok(Some(Self::meta(key.to_string(), &m))),
+      Ok(_) => Ok(None),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  /// Hard links `to` to `from` so the copy takes no space, falls back to a
+  /// real copy when the filesystem can not link
+  async fn copy(&self, from: &str, to: &str) -> Result<u64, StorageError> {
+    let from_path = self.path_for(from)?;
+    let to_path = self.path_for(to)?;
+
+    let size = match fs::metadata(&from_path).await {
+      Ok(m) => m.len(),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(StorageError::NotFound(from.to_string())),
+      Err(e) => return Err(e.into()),
+    };
+
+    if let Some(parent) = to_path.parent() {
+      fs::create_dir_all(parent).await?;
+    }
+
+    let suffix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(8).map(char::from).collect();
+    let temp_path = PathBuf::from(format!("{}.{}{}", to_path.display(), suffix, TEMP_SUFFIX));
+
+    let res = async {
+      if let Err(e) = fs::hard_link(&from_path, &temp_path).await {
+        debug!("Could not link {} to {}, copying instead: {}", from, to, e);
+        fs::copy(&from_path, &temp_path).await?;
+      }
+      fs::rename(&temp_path, &to_path).await
+    }
+    .await;
+
+    match res {
+      Ok(_) => Ok(size),
+      Err(e) => {
+        let _ = fs::remove_file(&temp_path).await;
+        Err(e.into())
+      }
+    }
+  }
+}
@@ -0,0 +1,401 @@
+use std::{io, ops::Range, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use hmac::{Hmac, Mac};
+use log::{debug, error};
+use reqwest::{Client, Method, StatusCode, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
+
+use crate::{error::StorageError, validate_key, ByteStream, ObjectMeta, StorageBackend};
+
+/// Objects bigger than this are uploaded in parts of this size
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// The biggest object S3 copies with a single `CopyObject`
+const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Stores objects in a S3 compatible bucket, requests are signed with AWS
+/// signature version 4
+pub struct S3Storage {
+  client: Client,
+  endpoint: Url,
+  bucket: String,
+  region: String,
+  access_key: String,
+  secret_key: String,
+  path_style: bool,
+  prefix: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+  #[serde(default)]
+  contents: Vec<ListContents>,
+  #[serde(default)]
+  is_truncated: bool,
+  next_continuation_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ListContents {
+  key: String,
+  size: u64,
+  last_modified: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct InitiateMultipartUploadResult {
+  upload_id: String,
+}
+
+impl S3Storage {
+  pub fn new(
+    endpoint: &str,
+    bucket: &str,
+    region: &str,
+    access_key: &str,
+    secret_key: &str,
+    path_style: bool,
+    prefix: &str,
+  ) -> Result<Self, StorageError> {
+    let endpoint = Url::parse(endpoint).map_err(|e| StorageError::BackendError(format!("Invalid endpoint: {}", e)))?;
+    if endpoint.host_str().is_none() {
+      return Err(StorageError::BackendError("Endpoint has no host".to_string()));
+    }
+
+    let prefix = match prefix.trim_matches('/') {
+      "" => String::new(),
+      p => format!("{}/", p),
+    };
+
+    let client = Client::builder()
+      .connect_timeout(Duration::from_secs(10))
+      .build()
+      .map_err(|e| StorageError::BackendError(e.to_string()))?;
+
+    debug!("Using S3 storage at {} (bucket {})", endpoint, bucket);
+
+    Ok(Self {
+      client,
+      endpoint,
+      bucket: bucket.to_string(),
+      region: region.to_string(),
+      access_key: access_key.to_string(),
+      secret_key: secret_key.to_string(),
+      path_style,
+      prefix,
+    })
+  }
+
+  fn host(&self) -> String {
+    let host = self.endpoint.host_str().unwrap_or_default();
+    let host = match self.endpoint.port() {
+      Some(p) => format!("{}:{}", host, p),
+      None => host.to_string(),
+    };
+
+    if self.path_style {
+      host
+    } else {
+      format!("{}.{}", self.bucket, host)
+    }
+  }
+
+  /// Path of the bucket (or an object in it) in request urls
+  fn path(&self, key: Option<&str>) -> String {
+    let key = key.map(|k| uri_encode(&format!("{}{}", self.prefix, k), false)).unwrap_or_default();
+    if self.path_style {
+      format!("/{}/{}", self.bucket, key)
+    } else {
+      format!("/{}", key)
+    }
+  }
+
+  /// Sign and send a request
+  async fn send(
+    &self,
+    method: Method,
+    path: &str,
+    query: &[(&str, String)],
+    headers: &[(&str, String)],
+    body: Option<Bytes>,
+  ) -> Result<reqwest::Response, StorageError> {
+    let now = chrono::Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let host = self.host();
+    let payload_hash = hex::encode(Sha256::digest(body.as_deref().unwrap_or_default()));
+
+    let mut query: Vec<(String, String)> =
+      query.iter().map(|(k, v)| (uri_encode(k, true), uri_encode(v, true))).collect();
+    query.sort();
+    let canonical_query = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&");
+
+    // Every `x-amz-` header has to be signed
+    let mut signed: Vec<(&str, &str)> =
+      vec![("host", &host), ("x-amz-content-sha256", &payload_hash), ("x-amz-date", &amz_date)];
+    signed.extend(headers.iter().filter(|(k, _)| k.starts_with("x-amz-")).map(|(k, v)| (*k, v.trim())));
+    signed.sort();
+    let signed_headers = signed.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(";");
+    let canonical_headers = signed.iter().map(|(k, v)| format!("{}:{}\n", k, v)).collect::<String>();
+    let canonical_request = format!(
+      "{}\n{}\n{}\n{}\n{}\n{}",
+      method.as_str(),
+      path,
+      canonical_query,
+      canonical_headers,
+      signed_headers,
+      payload_hash
+    );
+
+    let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+    let string_to_sign =
+      format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex::encode(Sha256::digest(canonical_request)));
+
+    let signing_key = [date.as_str(), self.region.as_str(), "s3", "aws4_request"]
+      .iter()
+      .fold(format!("AWS4{}", self.secret_key).into_bytes(), |key, part| hmac_sha256(&key, part.as_bytes()));
+    let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+    let authorization = format!(
+      "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+      self.access_key, scope, signed_headers, signature
+    );
+
+    let mut url = format!("{}://{}{}", self.endpoint.scheme(), host, path);
+    if !canonical_query.is_empty() {
+      url = format!("{}?{}", url, canonical_query);
+    }
+
+    let mut req = self
+      .client
+      .request(method, url)
+      .header("authorization", authorization)
+      .header("x-amz-content-sha256", payload_hash)
+      .header("x-amz-date", amz_date);
+    for (k, v) in headers {
+      req = req.header(*k, v);
+    }
+    if let Some(b) = body {
+      req = req.body(b);
+    }
+
+    let res = req.send().await.map_err(|e| StorageError::BackendError(e.to_string()))?;
+    if res.status().is_success() {
+      return Ok(res);
+    }
+
+    let status = res.status();
+    let text = res.text().await.unwrap_or_default();
+    match status {
+      StatusCode::NOT_FOUND => Err(StorageError::NotFound(path.to_string())),
+      _ => {
+        error!("S3 request to {} failed with {}: {}", path, status, text);
+        Err(StorageError::BackendError(format!("S3 returned {}", status)))
+      }
+    }
+  }
+
+  async fn put_multipart(&self, key: &str, first: Vec<u8>, stream: &mut ByteStream) -> Result<u64, StorageError> {
+    let path = self.path(Some(key));
+    let res = self.send(Method::POST, &path, &[("uploads", String::new())], &[], None).await?;
+    let text = res.text().await.map_err(|e| StorageError::BackendError(e.to_string()))?;
+    let upload: InitiateMultipartUploadResult =
+      quick_xml::de::from_str(&text).map_err(|e| StorageError::BackendError(format!("Bad S3 response: {}", e)))?;
+
+    let res = async {
+      let mut etags = Vec::new();
+      let mut written = 0;
+      let mut part = first;
+
+      while !part.is_empty() {
+        written += part.len() as u64;
+        let query = [("partNumber", (etags.len() + 1).to_string()), ("uploadId", upload.upload_id.clone())];
+        let res = self.send(Method::PUT, &path, &query, &[], Some(Bytes::from(part))).await?;
+        let etag = res.headers().get("etag").and_then(|e| e.to_str().ok()).unwrap_or_default().to_string();
+        etags.push(etag);
+
+        part = read_part(stream).await?;
+      }
+
+      let body = format!(
+        "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+        etags
+          .iter()
+          .enumerate()
+          .map(|(i, e)| format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, e))
+          .collect::<String>()
+      );
+      self.send(Method::POST, &path, &[("uploadId", upload.upload_id.clone())], &[], Some(Bytes::from(body))).await?;
+
+      Ok(written)
+    }
+    .await;
+
+    if res.is_err() {
+      let _ = self.send(Method::DELETE, &path, &[("uploadId", upload.upload_id.clone())], &[], None).await;
+    }
+
+    res
+  }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+  fn name(&self) -> &str {
+    "s3"
+  }
+
+  async fn put(&self, key: &str, mut stream: ByteStream) -> Result<u64, StorageError> {
+    validate_key(key)?;
+
+    let first = read_part(&mut stream).await?;
+    if first.len() < PART_SIZE {
+      let size = first.len() as u64;
+      self.send(Method::PUT, &self.path(Some(key)), &[], &[], Some(Bytes::from(first))).await?;
+      return Ok(size);
+    }
+
+    self.put_multipart(key, first, &mut stream).await
+  }
+
+  async fn get_range(&self, key: &str, range: Option<Range<u64>>) -> Result<ByteStream, StorageError> {
+    validate_key(key)?;
+
+    let headers = match &range {
+      Some(r) if r.start >= r.end => return Ok(Box::new(tokio::io::empty())),
+      Some(r) => vec![("range", format!("bytes={}-{}", r.start, r.end - 1))],
+      None => vec![],
+    };
+
+    let res = self.send(Method::GET, &self.path(Some(key)), &[], &headers, None).await?;
+    let stream = res.bytes_stream().map_err(io::Error::other);
+
+    Ok(Box::new(StreamReader::new(stream)))
+  }
+
+  /// Copies in the bucket with `CopyObject`, without downloading the object
+  async fn copy(&self, from: &str, to: &str) -> Result<u64, StorageError> {
+    validate_key(from)?;
+    validate_key(to)?;
+
+    let size = match self.stat(from).await? {
+      Some(m) => m.size,
+      None => return Err(StorageError::NotFound(from.to_string())),
+    };
+    if size > MAX_COPY_SIZE {
+      let stream = self.get_range(from, None).await?;
+      return self.put(to, stream).await;
+    }
+
+    let source = uri_encode(&format!("{}/{}{}", self.bucket, self.prefix, from), false);
+    let headers = [("x-amz-copy-source", format!("/{}", source))];
+    let res = self.send(Method::PUT, &self.path(Some(to)), &[], &headers, None).await?;
+
+    // Errors during the copy come back in the body of a 200
+    let text = res.text().await.map_err(|e| StorageError::BackendError(e.to_string()))?;
+    if text.contains("<Error>") {
+      error!("S3 copy of {} to {} failed: {}", from, to, text);
+      return Err(StorageError::BackendError("S3 copy failed".to_string()));
+    }
+
+    Ok(size)
+  }
+
+  async fn delete(&self, key: &str) -> Result<(), StorageError> {
+    validate_key(key)?;
+
+    match self.send(Method::DELETE, &self.path(Some(key)), &[], &[], None).await {
+      Ok(_) | Err(StorageError::NotFound(_)) => Ok(()),
+      Err(e) => Err(e),
+    }
+  }
+
+  async fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>, StorageError> {
+    let mut objects = Vec::new();
+    let mut continuation_token: Option<String> = None;
+
+    loop {
+      let mut query = vec![("list-type", "2".to_string()), ("prefix", format!("{}{}", self.prefix, prefix))];
+      if let Some(t) = &continuation_token {
+        query.push(("continuation-token", t.clone()));
+      }
+
+      let res = self.send(Method::GET, &self.path(None), &query, &[], None).await?;
+      let text = res.text().await.map_err(|e| StorageError::BackendError(e.to_string()))?;
+      let list: ListBucketResult =
+        quick_xml::de::from_str(&text).map_err(|e| StorageError::BackendError(format!("Bad S3 response: {}", e)))?;
+
+      objects.extend(list.contents.into_iter().filter_map(|c| {
+        Some(ObjectMeta {
+          key: c.key.strip_prefix(&self.prefix)?.to_string(),
+          size: c.size,
+          modified: c.last_modified.and_then(|m| chrono::DateTime::parse_from_rfc3339(&m).ok()).map(|m| m.timestamp()),
+        })
+      }));
+
+      match list.next_continuation_token {
+        Some(t) if list.is_truncated => continuation_token = Some(t),
+        _ => break,
+      }
+    }
+
+    Ok(objects)
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<ObjectMeta>, StorageError> {
+    validate_key(key)?;
+
+    let res = match self.send(Method::HEAD, &self.path(Some(key)), &[], &[], None).await {
+      Ok(r) => r,
+      Err(StorageError::NotFound(_)) => return Ok(None),
+      Err(e) => return Err(e),
+    };
+
+    let headers = res.headers();
+    let size = headers.get("content-length").and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()).unwrap_or(0);
+    let modified = headers
+      .get("last-modified")
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+      .map(|m| m.timestamp());
+
+    Ok(Some(ObjectMeta { key: key.to_string(), size, modified }))
+  }
+}
+
+/// Read up to `PART_SIZE` bytes, less only if the stream ended
+async fn read_part(stream: &mut ByteStream) -> Result<Vec<u8>, StorageError> {
+  let mut buf = Vec::with_capacity(PART_SIZE);
+  stream.take(PART_SIZE as u64).read_to_end(&mut buf).await?;
+  Ok(buf)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+  mac.update(data);
+  mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent encode everything except the unreserved characters (and `/` if
+/// `encode_slash` is false) like AWS expects
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+  let mut encoded = String::with_capacity(s.len());
+  for b in s.bytes() {
+    match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+      b'/' if !encode_slash => encoded.push('/'),
+      _ => encoded.push_str(&format!("%{:02X}", b)),
+    }
+  }
+
+  encoded
+}
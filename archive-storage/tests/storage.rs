@@ -0,0 +1,87 @@
+//! Runs the same checks against every backend. The S3 test needs a server and
+//! is ignored by default, run it with `--ignored` and `S3_TEST_ENDPOINT` set,
+//! ex: against a local MinIO:
+//!
+//! ```sh
+//! docker run -p 9000:9000 minio/minio server /data
+//! S3_TEST_ENDPOINT=http://localhost:9000 S3_TEST_BUCKET=test \
+//!   S3_TEST_ACCESS_KEY=minioadmin S3_TEST_SECRET_KEY=minioadmin cargo test -p archive-storage -- --ignored
+//! ```
+
+use std::{env::var, io::Cursor};
+
+use archive_storage::{local::LocalStorage, s3::S3Storage, StorageBackend};
+use tokio::io::AsyncReadExt;
+
+async fn read_all(storage: &dyn StorageBackend, key: &str, range: Option<std::ops::Range<u64>>) -> Vec<u8> {
+  let mut buf = Vec::new();
+  storage.get_range(key, range).await.unwrap().read_to_end(&mut buf).await.unwrap();
+  buf
+}
+
+async fn exercise(storage: &dyn StorageBackend) {
+  let small = b"hello archive".to_vec();
+  // Big enough for a multipart upload on S3
+  let big: Vec<u8> = (0..9 * 1024 * 1024 + 17).map(|i| (i % 251) as u8).collect();
+
+  assert_eq!(storage.put("test/small.txt", Box::new(Cursor::new(small.clone()))).await.unwrap(), small.len() as u64);
+  assert_eq!(storage.put("test/dir/big.bin", Box::new(Cursor::new(big.clone()))).await.unwrap(), big.len() as u64);
+
+  assert_eq!(read_all(storage, "test/small.txt", None).await, small);
+  assert_eq!(read_all(storage, "test/small.txt", Some(6..13)).await, b"archive");
+  assert_eq!(read_all(storage, "test/dir/big.bin", Some(1000..2000)).await, big[1000..2000]);
+
+  let meta = storage.stat("test/dir/big.bin").await.unwrap().unwrap();
+  assert_eq!(meta.size, big.len() as u64);
+  assert!(storage.stat("test/missing").await.unwrap().is_none());
+
+  let keys: Vec<String> = storage.list("test/").await.unwrap().into_iter().map(|o| o.key).collect();
+  assert!(keys.contains(&"test/small.txt".to_string()));
+  assert!(keys.contains(&"test/dir/big.bin".to_string()));
+
+  storage.put("test/small.txt", Box::new(Cursor::new(b"replaced".to_vec()))).await.unwrap();
+  assert_eq!(read_all(storage, "test/small.txt", None).await, b"replaced");
+
+  assert_eq!(storage.copy("test/small.txt", "test/copy/small.txt").await.unwrap(), 8);
+  assert_eq!(read_all(storage, "test/copy/small.txt", None).await, b"replaced");
+  storage.delete("test/copy/small.txt").await.unwrap();
+  assert_eq!(read_all(storage, "test/small.txt", None).await, b"replaced");
+  assert!(storage.copy("test/missing", "test/copy/missing").await.is_err());
+  assert_eq!(storage.copy("test/dir/big.bin", "test/copy/big.bin").await.unwrap(), big.len() as u64);
+  assert_eq!(read_all(storage, "test/copy/big.bin", Some(1000..2000)).await, big[1000..2000]);
+  storage.delete("test/copy/big.bin").await.unwrap();
+
+  storage.delete("test/small.txt").await.unwrap();
+  storage.delete("test/dir/big.bin").await.unwrap();
+  storage.delete("test/small.txt").await.unwrap();
+  assert!(storage.stat("test/small.txt").await.unwrap().is_none());
+  assert!(storage.list("test/").await.unwrap().is_empty());
+
+  assert!(storage.put("../escape", Box::new(Cursor::new(Vec::new()))).await.is_err());
+  assert!(storage.put("/absolute", Box::new(Cursor::new(Vec::new()))).await.is_err());
+}
+
+#[tokio::test]
+async fn local_storage() {
+  let dir = tempfile::tempdir().unwrap();
+  let storage = LocalStorage::new(dir.path()).unwrap();
+  exercise(&storage).await;
+}
+
+#[tokio::test]
+#[ignore = "needs an S3 server, set S3_TEST_ENDPOINT and run with --ignored"]
+async fn s3_storage() {
+  let endpoint = var("S3_TEST_ENDPOINT").expect("S3_TEST_ENDPOINT is not set");
+
+  let storage = S3Storage::new(
+    &endpoint,
+    &var("S3_TEST_BUCKET").unwrap_or("test".to_string()),
+    &var("S3_TEST_REGION").unwrap_or("us-east-1".to_string()),
+    &var("S3_TEST_ACCESS_KEY").unwrap_or("minioadmin".to_string()),
+    &var("S3_TEST_SECRET_KEY").unwrap_or("minioadmin".to_string()),
+    true,
+    "archive-storage-test",
+  )
+  .unwrap();
+  exercise(&storage).await;
+}
@@ -0,0 +1,246 @@
+use std::{
+  collections::HashMap,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use archive_config::BandwidthConfig;
+use chrono::{Local, NaiveTime};
+use tokio::time::sleep;
+
+/// Bytes a bucket lets through per second, with up to a second worth of burst.
+/// Going over the budget puts the bucket in debt, which is paid back by waiting
+#[derive(Debug)]
+struct TokenBucket {
+  rate: u64,
+  tokens: f64,
+  updated: Instant,
+}
+
+impl TokenBucket {
+  fn new() -> Self {
+    Self { rate: 0, tokens: 0.0, updated: Instant::now() }
+  }
+
+  /// Take `bytes` out of the bucket at `rate` bytes per second (0 is
+  /// unlimited)
+  ///
+  /// Returns how long to wait before using them
+  fn take(&mut self, rate: u64, bytes: u64) -> Duration {
+    self.take_at(rate, bytes, Instant::now())
+  }
+
+  /// Same as [`TokenBucket::take`], with the time passed in
+  fn take_at(&mut self, rate: u64, bytes: u64, now: Instant) -> Duration {
+    if rate == 0 {
+      *self = Self { rate, tokens: 0.0, updated: now };
+      return Duration::ZERO;
+    }
+
+    let capacity = rate as f64;
+    let refill = match self.rate {
+      // Was unlimited, start with a full bucket
+      0 => capacity,
+      _ => now.duration_since(self.updated).as_secs_f64() * capacity,
+    };
+    self.rate = rate;
+    self.tokens = (self.tokens + refill).min(capacity) - bytes as f64;
+    self.updated = now;
+
+    match self.tokens < 0.0 {
+      true => Duration::from_secs_f64(-self.tokens / capacity),
+      false => Duration::ZERO,
+    }
+  }
+}
+
+/// A part of the day with a different global cap
+#[derive(Debug, Clone)]
+struct Window {
+  start: NaiveTime,
+  end: NaiveTime,
+  limit: u64,
+}
+
+impl Window {
+  fn contains(&self, time: NaiveTime) -> bool {
+    match self.start <= self.end {
+      true => self.start <= time && time < self.end,
+      // Goes past midnight
+      false => time >= self.start || time < self.end,
+    }
+  }
+}
+
+/// Caps the download speed of every downloader of a pool together and of
+/// every user on their own
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+  limit: u64,
+  user_limit: u64,
+  users: HashMap<i32, u64>,
+  schedule: Vec<Window>,
+  global: Mutex<TokenBucket>,
+  per_user: Mutex<HashMap<i32, TokenBucket>>,
+}
+
+impl BandwidthLimiter {
+  pub fn unlimited() -> Self {
+    Self::from_config(&BandwidthConfig::default()).unwrap()
+  }
+
+  /// Build a limiter from the `downloader.bandwidth` config
+  ///
+  /// Returns an error message if a schedule time is not `HH:MM`
+  pub fn from_config(config: &BandwidthConfig) -> Result<Self, String> {
+    let parse = |t: &str| {
+      NaiveTime::parse_from_str(t, "%H:%M").map_err(|e| format!("Invalid bandwidth schedule time {}: {}", t, e))
+    };
+
+    let mut schedule = Vec::with_capacity(config.schedule.len());
+    for window in &config.schedule {
+      schedule.push(Window { start: parse(&window.start)?, end: parse(&window.end)?, limit: window.limit });
+    }
+
+    Ok(Self {
+      limit: config.limit,
+      user_limit: config.user_limit,
+      users: config.users.iter().map(|u| (u.user_id, u.limit)).collect(),
+      schedule,
+      global: Mutex::new(TokenBucket::new()),
+      per_user: Mutex::new(HashMap::new()),
+    })
+  }
+
+  /// Cap of all downloads together right now, the first matching schedule
+  /// window wins
+  pub fn get_limit(&self) -> u64 {
+    let now = Local::now().time();
+    self.schedule.iter().find(|w| w.contains(now)).map(|w| w.limit).unwrap_or(self.limit)
+  }
+
+  /// Cap of the downloads of a user
+  pub fn get_user_limit(&self, user_id: i32) -> u64 {
+    self.users.get(&user_id).copied().unwrap_or(self.user_limit)
+  }
+
+  /// Account for `bytes` downloaded for a user, waiting as long as it takes
+  /// to stay under the caps
+  pub async fn consume(&self, user_id: Option<i32>, bytes: u64) {
+    let limit = self.get_limit();
+    let mut wait = self.global.lock().unwrap_or_else(|e| e.into_inner()).take(limit, bytes);
+
+    if let Some(user_id) = user_id {
+      let limit = self.get_user_limit(user_id);
+      let mut per_user = self.per_user.lock().unwrap_or_else(|e| e.into_inner());
+      let user_wait = per_user.entry(user_id).or_insert_with(TokenBucket::new).take(limit, bytes);
+      wait = wait.max(user_wait);
+    }
+
+    if !wait.is_zero() {
+      sleep(wait).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn time(t: &str) -> NaiveTime {
+    NaiveTime::parse_from_str(t, "%H:%M").unwrap()
+  }
+
+  fn window(start: &str, end: &str) -> Window {
+    Window { start: time(start), end: time(end), limit: 1 }
+  }
+
+  #[test]
+  fn unlimited_never_waits() {
+    let mut bucket = TokenBucket::new();
+    assert_eq!(bucket.take(0, u64::MAX), Duration::ZERO);
+    assert_eq!(bucket.take(0, u64::MAX), Duration::ZERO);
+  }
+
+  #[test]
+  fn starts_full_after_unlimited() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new();
+    assert_eq!(bucket.take_at(0, 10_000, start), Duration::ZERO);
+
+    // A second worth of burst right away
+    assert_eq!(bucket.take_at(1000, 1000, start), Duration::ZERO);
+    assert_eq!(bucket.take_at(1000, 500, start), Duration::from_millis(500));
+  }
+
+  #[test]
+  fn debt_is_paid_by_waiting() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new();
+    assert_eq!(bucket.take_at(1000, 3000, start), Duration::from_secs(2));
+
+    // Half a second later a second and a half of debt is left
+    assert_eq!(bucket.take_at(1000, 0, start + Duration::from_millis(500)), Duration::from_millis(1500));
+    // Paid back, with nothing to spare
+    assert_eq!(bucket.take_at(1000, 0, start + Duration::from_secs(2)), Duration::ZERO);
+    assert_eq!(bucket.take_at(1000, 100, start + Duration::from_secs(2)), Duration::from_millis(100));
+  }
+
+  #[test]
+  fn refill_is_capped_at_a_second() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new();
+    bucket.take_at(1000, 1000, start);
+
+    // Idling for a minute does not allow a minute worth of burst
+    let later = start + Duration::from_secs(60);
+    assert_eq!(bucket.take_at(1000, 1000, later), Duration::ZERO);
+    assert_eq!(bucket.take_at(1000, 1000, later), Duration::from_secs(1));
+  }
+
+  #[test]
+  fn unlimited_forgets_debt() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new();
+    assert_eq!(bucket.take_at(1000, 5000, start), Duration::from_secs(4));
+    assert_eq!(bucket.take_at(0, 5000, start), Duration::ZERO);
+    assert_eq!(bucket.take_at(1000, 1000, start), Duration::ZERO);
+  }
+
+  #[test]
+  fn rate_change_applies_to_the_debt() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new();
+    assert_eq!(bucket.take_at(1000, 2000, start), Duration::from_secs(1));
+    // The same debt takes half as long at twice the rate
+    assert_eq!(bucket.take_at(2000, 0, start), Duration::from_millis(500));
+  }
+
+  #[test]
+  fn window_within_a_day() {
+    let w = window("09:00", "17:00");
+    assert!(!w.contains(time("08:59")));
+    assert!(w.contains(time("09:00")));
+    assert!(w.contains(time("16:59")));
+    assert!(!w.contains(time("17:00")));
+  }
+
+  #[test]
+  fn window_past_midnight() {
+    let w = window("22:00", "06:00");
+    assert!(w.contains(time("22:00")));
+    assert!(w.contains(time("23:59")));
+    assert!(w.contains(time("00:00")));
+    assert!(w.contains(time("05:59")));
+    assert!(!w.contains(time("06:00")));
+    assert!(!w.contains(time("12:00")));
+    assert!(!w.contains(time("21:59")));
+  }
+
+  #[test]
+  fn empty_window() {
+    let w = window("12:00", "12:00");
+    assert!(!w.contains(time("12:00")));
+    assert!(!w.contains(time("00:00")));
+  }
+}
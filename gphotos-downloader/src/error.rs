@@ -3,4 +3,19 @@ pub enum DownloaderError {
   PoolError(String),
   RequestError(String),
   ApiError(String),
+  /// The `baseUrl` of a media item expired, it has to be fetched again
+  ExpiredUrl(String),
+  /// Search filters the API would reject
+  InvalidFilter(String),
+  /// No downloader was free before the timeout
+  Timeout,
+  /// The acquire was cancelled before a downloader was free
+  Cancelled,
+  /// The pool is shutting down
+  ShutDown,
+  /// The daily API budget is used up until the reset at the given unix
+  /// timestamp
+  QuotaExhausted(i64),
+  /// Google kept answering with 429
+  RateLimited(String),
 }
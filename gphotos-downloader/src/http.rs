@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use archive_config::HttpConfig;
+use reqwest::{redirect::Policy, Client, ClientBuilder, IntoUrl, Proxy, RequestBuilder};
+
+/// A reqwest client shared by everything that talks to google, so connections
+/// (and their TLS sessions) are reused between requests. Cloning it is cheap
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+  client: Client,
+  /// Same settings, but never follows redirects
+  oauth: Client,
+  request_timeout: Duration,
+}
+
+impl Default for HttpClient {
+  fn default() -> Self {
+    Self::new(&HttpConfig::default()).unwrap()
+  }
+}
+
+impl HttpClient {
+  /// Build the client from the `http` config
+  ///
+  /// Returns an error message if the proxy is invalid or the TLS backend
+  /// could not be set up
+  pub fn new(config: &HttpConfig) -> Result<Self, String> {
+    let build = |b: ClientBuilder| b.build().map_err(|e| format!("Failed to build the HTTP client: {}", e));
+    let client = build(Self::builder(config)?)?;
+    let oauth = build(Self::builder(config)?.redirect(Policy::none()))?;
+
+    Ok(Self { client, oauth, request_timeout: Duration::from_secs(config.request_timeout_secs) })
+  }
+
+  fn builder(config: &HttpConfig) -> Result<ClientBuilder, String> {
+    let mut builder = Client::builder()
+      .user_agent(&config.user_agent)
+      .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+      .read_timeout(Duration::from_secs(config.read_timeout_secs))
+      .pool_idle_timeout(Duration::from_secs(config.idle_timeout_secs));
+
+    if config.tcp_keepalive_secs > 0 {
+      builder = builder.tcp_keepalive(Duration::from_secs(config.tcp_keepalive_secs));
+    }
+
+    builder = match config.http2 {
+      true => builder.http2_adaptive_window(true),
+      false => builder.http1_only(),
+    };
+
+    if let Some(proxy) = &config.proxy {
+      builder = builder.proxy(Proxy::all(proxy).map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?);
+    }
+
+    Ok(builder)
+  }
+
+  /// The underlying client, for libraries that take a reqwest client
+  #[inline]
+  pub fn get_client(&self) -> &Client {
+    &self.client
+  }
+
+  /// The client for OAuth token exchanges, following a redirect there could
+  /// send the authorization code somewhere else
+  #[inline]
+  pub fn get_oauth_client(&self) -> &Client {
+    &self.oauth
+  }
+
+  /// Start an API GET request, limited by the request timeout
+  pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+    self.client.get(url).timeout(self.request_timeout)
+  }
+
+  /// Start an API POST request, limited by the request timeout
+  pub fn post<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+    self.client.post(url).timeout(self.request_timeout)
+  }
+
+  /// Start a download, which can take as long as it needs as long as data
+  /// keeps coming
+  pub fn download<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+    self.client.get(url)
+  }
+}
@@ -1,85 +1,386 @@
+pub mod bandwidth;
 pub mod error;
+pub mod http;
+pub mod quota;
+pub mod stats;
 pub mod structs;
 
-use std::{collections::VecDeque, sync::Arc, task};
+use std::{
+  collections::{HashMap, VecDeque},
+  future::{pending, Future},
+  pin::pin,
+  sync::{Arc, Mutex, MutexGuard},
+  task,
+  time::{Duration, Instant},
+};
 
+use bandwidth::BandwidthLimiter;
 use error::DownloaderError;
-use log::trace;
-use reqwest::{Client, Response};
-use serde_json::{from_str, to_string, Value};
-use structs::{DownloaderGuard, MediaItemsResponse};
-use tokio::sync::{
-  oneshot::{channel, Sender},
-  Mutex, OwnedSemaphorePermit, Semaphore,
+use http::HttpClient;
+use log::{debug, trace, warn};
+use quota::{QuotaStatus, QuotaTracker};
+use reqwest::{RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use serde_json::{from_str, json, to_string, Value};
+use stats::PoolStats;
+use structs::{
+  AlbumsResponse, BatchGetResponse, DownloaderGuard, Filters, MediaItem, MediaItemsResponse, SharedAlbumsResponse,
+};
+use tokio::{
+  sync::{
+    oneshot::{channel, Receiver, Sender},
+    Notify,
+  },
+  time::sleep,
 };
+use tokio_util::sync::CancellationToken;
 use uid::IdU8;
 
-pub struct DownloaderPool {
-  pool: Mutex<VecDeque<Downloader>>,
-  semaphore: Arc<Semaphore>,
-  pending_tasks: Mutex<VecDeque<DownloadTask>>,
+/// Most ids `mediaItems:batchGet` accepts in one call
+pub const MAX_BATCH_GET_IDS: usize = 50;
+
+/// How often a rate limited request is retried before giving up
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+/// How urgently a downloader is needed. Interactive requests (someone waiting
+/// on a page) are served before background syncs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+  Background,
+  Interactive,
 }
 
 type DownloadTask = Sender<Result<DownloaderGuard, DownloaderError>>;
 
+/// Waiters of one owner, by priority
+#[derive(Default)]
+struct OwnerQueue {
+  interactive: VecDeque<DownloadTask>,
+  background: VecDeque<DownloadTask>,
+}
+
+impl OwnerQueue {
+  fn len(&self) -> usize {
+    self.interactive.len() + self.background.len()
+  }
+
+  fn queue(&mut self, priority: Priority) -> &mut VecDeque<DownloadTask> {
+    match priority {
+      Priority::Interactive => &mut self.interactive,
+      Priority::Background => &mut self.background,
+    }
+  }
+
+  /// Drop the waiters that stopped waiting
+  fn prune(&mut self) {
+    self.interactive.retain(|t| !t.is_closed());
+    self.background.retain(|t| !t.is_closed());
+  }
+}
+
+#[derive(Default)]
+struct PoolState {
+  idle: VecDeque<Downloader>,
+  waiting: HashMap<i32, OwnerQueue>,
+  /// Owners with waiters, the front one is served next
+  rotation: VecDeque<i32>,
+  shut_down: bool,
+}
+
+impl PoolState {
+  /// Take the next waiter: the first owner in the rotation with an
+  /// interactive waiter, or else the first owner in the rotation. The served
+  /// owner goes to the back so a user with a big backlog can not starve the
+  /// others
+  fn next_waiter(&mut self) -> Option<(i32, DownloadTask)> {
+    let pos = self
+      .rotation
+      .iter()
+      .position(|o| self.waiting.get(o).is_some_and(|q| !q.interactive.is_empty()))
+      .unwrap_or_default();
+    let owner = self.rotation.remove(pos)?;
+
+    let queue = self.waiting.get_mut(&owner)?;
+    let task = queue.interactive.pop_front().or_else(|| queue.background.pop_front());
+
+    match queue.len() {
+      0 => {
+        self.waiting.remove(&owner);
+      }
+      _ => self.rotation.push_back(owner),
+    }
+
+    task.map(|t| (owner, t))
+  }
+
+  /// Forget the waiters of `owner` that stopped waiting
+  fn prune(&mut self, owner: i32) {
+    if let Some(queue) = self.waiting.get_mut(&owner) {
+      queue.prune();
+      if queue.len() == 0 {
+        self.waiting.remove(&owner);
+        self.rotation.retain(|o| *o != owner);
+      }
+    }
+  }
+}
+
+enum Enqueued {
+  Ready(Result<DownloaderGuard, DownloaderError>),
+  Waiting(Receiver<Result<DownloaderGuard, DownloaderError>>),
+}
+
+/// Hands out downloaders, fairly between owners (users) when there are not
+/// enough for everyone
+pub struct DownloaderPool {
+  size: usize,
+  /// Never held across an await, so guards can give their downloader back
+  /// when they are dropped
+  state: Mutex<PoolState>,
+  returned: Notify,
+  quota: Arc<QuotaTracker>,
+  stats: Arc<PoolStats>,
+}
+
 impl DownloaderPool {
   pub fn new(pool_size: usize) -> Arc<Self> {
-    let mut downloaders = VecDeque::with_capacity(pool_size);
+    Self::from_parts(
+      pool_size,
+      HttpClient::default(),
+      Arc::new(BandwidthLimiter::unlimited()),
+      Arc::new(QuotaTracker::unlimited(pool_size)),
+    )
+  }
+
+  /// Create a pool whose downloaders share one HTTP client, the caps of
+  /// `bandwidth` and the API budget of `quota`
+  pub fn from_parts(
+    pool_size: usize,
+    http: HttpClient,
+    bandwidth: Arc<BandwidthLimiter>,
+    quota: Arc<QuotaTracker>,
+  ) -> Arc<Self> {
+    let stats = Arc::new(PoolStats::default());
+    let mut idle = VecDeque::with_capacity(pool_size);
     for _ in 0..pool_size {
-      downloaders.push_back(Downloader::new());
+      idle.push_back(Downloader::new(http.clone(), bandwidth.clone(), quota.clone(), stats.clone()));
     }
     Arc::new(Self {
-      pool: Mutex::new(downloaders),
-      semaphore: Arc::new(Semaphore::new(pool_size)),
-      pending_tasks: Mutex::new(VecDeque::new()),
+      size: pool_size,
+      state: Mutex::new(PoolState { idle, ..Default::default() }),
+      returned: Notify::new(),
+      quota,
+      stats,
     })
   }
 
-  pub async fn acquire(self: Arc<Self>) -> Result<DownloaderGuard, DownloaderError> {
-    let permit = self.semaphore.clone().try_acquire_owned();
+  /// Get a downloader for `owner`, waiting for one to be returned if they are
+  /// all in use. Waiting owners are served in turns, interactive requests
+  /// first
+  pub async fn acquire(self: Arc<Self>, owner: i32, priority: Priority) -> Result<DownloaderGuard, DownloaderError> {
+    self.acquire_until(owner, priority, pending(), DownloaderError::Cancelled).await
+  }
 
-    if let Ok(p) = permit {
-      let downloader = {
-        let mut pool = self.pool.lock().await;
-        pool.pop_front()
-      };
+  /// Same as [`DownloaderPool::acquire`], but gives up after `timeout`
+  pub async fn acquire_timeout(
+    self: Arc<Self>,
+    owner: i32,
+    priority: Priority,
+    timeout: Duration,
+  ) -> Result<DownloaderGuard, DownloaderError> {
+    self.acquire_until(owner, priority, sleep(timeout), DownloaderError::Timeout).await
+  }
 
-      if downloader.is_some() {
-        return Ok(DownloaderGuard { downloader, pool: self.clone(), _permit: p });
+  /// Same as [`DownloaderPool::acquire`], but gives up when `cancel` is
+  /// cancelled
+  pub async fn acquire_cancellable(
+    self: Arc<Self>,
+    owner: i32,
+    priority: Priority,
+    cancel: &CancellationToken,
+  ) -> Result<DownloaderGuard, DownloaderError> {
+    self.acquire_until(owner, priority, cancel.cancelled(), DownloaderError::Cancelled).await
+  }
+
+  async fn acquire_until<F: Future<Output = ()>>(
+    self: Arc<Self>,
+    owner: i32,
+    priority: Priority,
+    give_up: F,
+    error: DownloaderError,
+  ) -> Result<DownloaderGuard, DownloaderError> {
+    let started = Instant::now();
+    let mut rx = match self.enqueue(owner, priority) {
+      Enqueued::Ready(res) => return res.inspect(|_| self.stats.waited(started.elapsed())),
+      Enqueued::Waiting(rx) => rx,
+    };
+
+    let res = tokio::select! {
+      biased;
+      res = &mut rx => res.map_err(|_| DownloaderError::PoolError("Task queue cancelled".to_owned()))?,
+      _ = give_up => {
+        // A downloader may have been handed over in the meantime
+        rx.close();
+        match rx.try_recv() {
+          Ok(res) => res,
+          Err(_) => {
+            self.lock().prune(owner);
+            Err(error)
+          }
+        }
+      }
+    };
+    res.inspect(|_| self.stats.waited(started.elapsed()))
+  }
+
+  fn enqueue(self: &Arc<Self>, owner: i32, priority: Priority) -> Enqueued {
+    let mut state = self.lock();
+    if state.shut_down {
+      return Enqueued::Ready(Err(DownloaderError::ShutDown));
+    }
+
+    // Only skip the line if there is no line
+    if state.waiting.is_empty() {
+      if let Some(mut downloader) = state.idle.pop_front() {
+        downloader.owner = Some(owner);
+        return Enqueued::Ready(Ok(DownloaderGuard { downloader: Some(downloader), pool: self.clone() }));
       }
     }
 
     let (tx, rx) = channel();
-    {
-      let mut pending_tasks = self.pending_tasks.lock().await;
-      pending_tasks.push_back(tx);
+    if !state.waiting.contains_key(&owner) {
+      state.rotation.push_back(owner);
     }
+    state.waiting.entry(owner).or_default().queue(priority).push_back(tx);
 
-    rx.await.map_err(|_| DownloaderError::PoolError("Task queue cancelled".to_owned()))?
+    Enqueued::Waiting(rx)
   }
 
-  async fn return_to_pool(self: Arc<Self>, downloader: Downloader) {
-    let mut pool = self.pool.lock().await;
-    pool.push_back(downloader);
+  /// Number of waiting acquires of every owner that has any
+  pub fn get_queue_depths(&self) -> HashMap<i32, usize> {
+    let mut state = self.lock();
+    state.waiting.values_mut().for_each(OwnerQueue::prune);
+    state.waiting.iter().filter(|(_, q)| q.len() > 0).map(|(o, q)| (*o, q.len())).collect()
+  }
+
+  /// Number of waiting acquires of `owner`
+  pub fn get_queue_depth(&self, owner: i32) -> usize {
+    let mut state = self.lock();
+    state.prune(owner);
+    state.waiting.get(&owner).map(|q| q.len()).unwrap_or_default()
+  }
 
-    let mut pending = self.pending_tasks.lock().await;
-    if let Some(task) = pending.pop_front() {
-      let p = self.semaphore.clone().acquire_owned().await.unwrap();
-      let downloader = pool.pop_front().unwrap();
-      let _ = task.send(Ok(DownloaderGuard { downloader: Some(downloader), pool: self.clone(), _permit: p }));
+  /// API requests made today and how many are left
+  pub fn get_quota_status(&self) -> QuotaStatus {
+    self.quota.get_status()
+  }
+
+  /// What the downloaders of the pool did so far
+  #[inline]
+  pub fn get_stats(&self) -> &PoolStats {
+    &self.stats
+  }
+
+  /// Number of downloaders nobody is using
+  pub fn get_available(&self) -> usize {
+    self.lock().idle.len()
+  }
+
+  #[inline]
+  pub fn get_size(&self) -> usize {
+    self.size
+  }
+
+  /// Stop handing out downloaders and wait for the ones in use to be
+  /// returned. Waiting and later acquires fail with `ShutDown`
+  pub async fn shutdown(&self) {
+    let waiters: Vec<DownloadTask> = {
+      let mut state = self.lock();
+      state.shut_down = true;
+      state.rotation.clear();
+      state.waiting.drain().flat_map(|(_, q)| q.interactive.into_iter().chain(q.background)).collect()
+    };
+    for waiter in waiters {
+      let _ = waiter.send(Err(DownloaderError::ShutDown));
+    }
+
+    loop {
+      let mut returned = pin!(self.returned.notified());
+      returned.as_mut().enable();
+
+      let available = self.get_available();
+      if available == self.size {
+        break;
+      }
+      debug!("Waiting for {} downloaders to be returned", self.size - available);
+      returned.await;
     }
   }
+
+  fn lock(&self) -> MutexGuard<'_, PoolState> {
+    // The state is always left consistent, so a panic elsewhere does not
+    // matter
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  /// Hand a downloader to the next waiter, skipping the ones that stopped
+  /// waiting, or put it back if nobody waits
+  pub(crate) fn return_to_pool(self: &Arc<Self>, mut downloader: Downloader) {
+    let mut state = self.lock();
+
+    while let Some((owner, task)) = state.next_waiter() {
+      if task.is_closed() {
+        continue;
+      }
+
+      downloader.owner = Some(owner);
+      match task.send(Ok(DownloaderGuard { downloader: Some(downloader), pool: self.clone() })) {
+        Ok(_) => return,
+        // The waiter went away while we were sending
+        Err(res) => downloader = res.ok().and_then(|mut g| g.downloader.take()).unwrap(),
+      }
+    }
+
+    downloader.owner = None;
+    state.idle.push_back(downloader);
+    drop(state);
+    self.returned.notify_waiters();
+  }
 }
 
 #[derive(Debug, Clone)]
 pub struct Downloader {
   access_token: Option<String>,
   id: IdU8<Self>,
+  /// Who the downloader was handed out to
+  owner: Option<i32>,
+  http: HttpClient,
+  bandwidth: Arc<BandwidthLimiter>,
+  quota: Arc<QuotaTracker>,
+  stats: Arc<PoolStats>,
 }
 
 impl Downloader {
-  pub fn new() -> Self {
-    Self { access_token: None, id: IdU8::<Self>::new() }
+  pub fn new(
+    http: HttpClient,
+    bandwidth: Arc<BandwidthLimiter>,
+    quota: Arc<QuotaTracker>,
+    stats: Arc<PoolStats>,
+  ) -> Self {
+    Self { access_token: None, id: IdU8::<Self>::new(), owner: None, http, bandwidth, quota, stats }
+  }
+
+  /// Wait until `bytes` more of a download fit under the bandwidth caps of
+  /// the pool and of the owner
+  pub async fn throttle(&self, bytes: usize) {
+    self.stats.downloaded(bytes as u64);
+    self.bandwidth.consume(self.owner, bytes as u64).await;
+  }
+
+  /// Counters shared by the downloaders of the pool
+  #[inline]
+  pub fn get_stats(&self) -> &PoolStats {
+    &self.stats
   }
 
   pub fn set_token<S: ToString>(&mut self, token: S) {
@@ -95,21 +396,212 @@ impl Downloader {
   }
 
   pub async fn list_photos(&self, next_page_token: Option<String>) -> Result<MediaItemsResponse, DownloaderError> {
-    if self.access_token.is_none() {
-      todo!()
-    }
-    let token = self.access_token.as_ref().unwrap();
-
-    let client = Client::new();
-    let res = client
-      .get("https://photoslibrary.googleapis.com/v1/mediaItems")
-      .bearer_auth(token)
-      .send()
-      .await
-      .map_err(|e| DownloaderError::RequestError(e.to_string()))?;
-
-    let text = res.text().await.unwrap();
-    MediaItemsResponse::try_from(text)
-      .map_err(|e| DownloaderError::ApiError(format!("Bad json from gAPI: {}", e.to_string())))
+    let mut query = vec![("pageSize", "100".to_string())];
+    if let Some(t) = next_page_token {
+      query.push(("pageToken", t));
+    }
+
+    let req =
+      self.http.get("https://photoslibrary.googleapis.com/v1/mediaItems").query(&query).bearer_auth(self.token()?);
+    let res = self.send(req, "mediaItems.list").await?;
+
+    Self::parse_response(res, "mediaItems.list").await
+  }
+
+  /// Get up to [`MAX_BATCH_GET_IDS`] media items at once, mostly to refresh
+  /// expired `baseUrl`s
+  ///
+  /// Returns a result per id, in the same order, or a DownloaderError if the
+  /// request failed
+  pub async fn batch_get(&self, ids: &[String]) -> Result<BatchGetResponse, DownloaderError> {
+    if ids.len() > MAX_BATCH_GET_IDS {
+      return Err(DownloaderError::ApiError(format!("batchGet takes at most {} ids", MAX_BATCH_GET_IDS)));
+    }
+
+    let query: Vec<(&str, &String)> = ids.iter().map(|id| ("mediaItemIds", id)).collect();
+
+    let req = self
+      .http
+      .get("https://photoslibrary.googleapis.com/v1/mediaItems:batchGet")
+      .query(&query)
+      .bearer_auth(self.token()?);
+    let res = self.send(req, "batchGet").await?;
+
+    Self::parse_response(res, "batchGet").await
+  }
+
+  /// List a page of the albums of the user (`albums.list`)
+  ///
+  /// Returns the albums or a DownloaderError if the request failed
+  pub async fn list_albums(&self, next_page_token: Option<String>) -> Result<AlbumsResponse, DownloaderError> {
+    let mut query = vec![("pageSize", "50".to_string())];
+    if let Some(t) = next_page_token {
+      query.push(("pageToken", t));
+    }
+
+    let req = self.http.get("https://photoslibrary.googleapis.com/v1/albums").query(&query).bearer_auth(self.token()?);
+    let res = self.send(req, "albums.list").await?;
+
+    Self::parse_response(res, "albums.list").await
+  }
+
+  /// List a page of the albums shared with the user (`sharedAlbums.list`)
+  ///
+  /// Returns the albums or a DownloaderError if the request failed
+  pub async fn list_shared_albums(
+    &self,
+    next_page_token: Option<String>,
+  ) -> Result<SharedAlbumsResponse, DownloaderError> {
+    let mut query = vec![("pageSize", "50".to_string())];
+    if let Some(t) = next_page_token {
+      query.push(("pageToken", t));
+    }
+
+    let req =
+      self.http.get("https://photoslibrary.googleapis.com/v1/sharedAlbums").query(&query).bearer_auth(self.token()?);
+    let res = self.send(req, "sharedAlbums.list").await?;
+
+    Self::parse_response(res, "sharedAlbums.list").await
+  }
+
+  /// List a page of the media items in an album (`mediaItems:search` with an
+  /// `albumId`), in album order
+  ///
+  /// Returns the media items or a DownloaderError if the request failed
+  pub async fn list_album_items(
+    &self,
+    album_id: &str,
+    next_page_token: Option<String>,
+  ) -> Result<MediaItemsResponse, DownloaderError> {
+    let mut body = json!({ "albumId": album_id, "pageSize": 100 });
+    if let Some(t) = next_page_token {
+      body["pageToken"] = json!(t);
+    }
+
+    let req = self
+      .http
+      .post("https://photoslibrary.googleapis.com/v1/mediaItems:search")
+      .bearer_auth(self.token()?)
+      .header("content-type", "application/json")
+      .body(body.to_string());
+    let res = self.send(req, "mediaItems:search").await?;
+
+    Self::parse_response(res, "mediaItems:search").await
+  }
+
+  /// Search the library of the user (`mediaItems:search` with filters)
+  ///
+  /// Returns a page of matching media items or a DownloaderError if the
+  /// filters are invalid or the request failed
+  pub async fn search(
+    &self,
+    filters: &Filters,
+    next_page_token: Option<String>,
+  ) -> Result<MediaItemsResponse, DownloaderError> {
+    filters.validate()?;
+
+    let mut body = json!({ "filters": filters, "pageSize": 100 });
+    if let Some(t) = next_page_token {
+      body["pageToken"] = json!(t);
+    }
+
+    let req = self
+      .http
+      .post("https://photoslibrary.googleapis.com/v1/mediaItems:search")
+      .bearer_auth(self.token()?)
+      .header("content-type", "application/json")
+      .body(body.to_string());
+    let res = self.send(req, "mediaItems:search").await?;
+
+    Self::parse_response(res, "mediaItems:search").await
+  }
+
+  /// Send a Library API request, counting it against the daily budget and
+  /// retrying it with a backoff when google rate limits it
+  async fn send(&self, req: RequestBuilder, call: &str) -> Result<Response, DownloaderError> {
+    let mut attempt = 0;
+
+    loop {
+      let req = req.try_clone().ok_or(DownloaderError::RequestError(format!("{} can not be retried", call)))?;
+      self.quota.reserve()?;
+
+      let res = {
+        let _permit = self.quota.permit().await;
+        let res = req.send().await;
+        self.stats.responded(call, res.as_ref().ok().map(|r| r.status().as_u16()));
+        res.map_err(|e| DownloaderError::RequestError(e.to_string()))?
+      };
+
+      if res.status().as_u16() != 429 {
+        if res.status().is_success() {
+          self.quota.succeeded();
+        }
+        return Ok(res);
+      }
+
+      let retry_after = res.headers().get("retry-after").and_then(|r| r.to_str().ok()).and_then(|r| r.parse().ok());
+      let body = res.text().await.unwrap_or_default();
+      // The next reserve fails until the reset
+      let daily = body.contains("per day");
+      self.quota.rate_limited(daily);
+      if daily {
+        continue;
+      }
+
+      attempt += 1;
+      if attempt > MAX_RATE_LIMIT_RETRIES {
+        return Err(DownloaderError::RateLimited(format!("{} was rate limited {} times", call, attempt)));
+      }
+
+      let backoff = Duration::from_secs(retry_after.unwrap_or(2u64.pow(attempt)).min(60));
+      warn!("{} was rate limited, retrying in {:?}", call, backoff);
+      sleep(backoff).await;
+    }
+  }
+
+  fn token(&self) -> Result<&str, DownloaderError> {
+    match &self.access_token {
+      Some(t) => Ok(t),
+      None => Err(DownloaderError::RequestError("No access token set".to_owned())),
+    }
+  }
+
+  async fn parse_response<T: DeserializeOwned>(res: Response, call: &str) -> Result<T, DownloaderError> {
+    if !res.status().is_success() {
+      return Err(DownloaderError::ApiError(format!("{} failed with {}", call, res.status())));
+    }
+
+    let text = res.text().await.map_err(|e| DownloaderError::RequestError(e.to_string()))?;
+    from_str(&text).map_err(|e| DownloaderError::ApiError(format!("Bad json from gAPI: {}", e)))
+  }
+
+  /// Start downloading the original bytes of a media item, from `offset` on
+  /// if it is not 0. Servers that do not support ranges answer with the whole
+  /// file (200) instead of the rest of it (206)
+  ///
+  /// Returns the response to read the body from or a DownloaderError if the
+  /// request failed
+  pub async fn download(&self, item: &MediaItem, offset: u64) -> Result<Response, DownloaderError> {
+    // `=d` gets the original photo with its metadata, `=dv` the video
+    let url = match item.mime_type.starts_with("video/") {
+      true => format!("{}=dv", item.base_url),
+      false => format!("{}=d", item.base_url),
+    };
+
+    let mut req = self.http.download(url);
+    if offset > 0 {
+      req = req.header("range", format!("bytes={}-", offset));
+    }
+
+    let res = req.send().await;
+    self.stats.responded("download", res.as_ref().ok().map(|r| r.status().as_u16()));
+    let res = res.map_err(|e| DownloaderError::RequestError(e.to_string()))?;
+
+    match res.status().as_u16() {
+      200..=299 => Ok(res),
+      // Base urls stop working after about an hour
+      403 | 404 => Err(DownloaderError::ExpiredUrl(item.id.clone())),
+      s => Err(DownloaderError::ApiError(format!("Download of {} failed with {}", item.id, s))),
+    }
   }
 }
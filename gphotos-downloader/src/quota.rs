@@ -0,0 +1,182 @@
+use std::sync::{
+  atomic::{AtomicUsize, Ordering},
+  Mutex,
+};
+
+use archive_config::QuotaConfig;
+use chrono::{DateTime, Days, NaiveTime, Utc};
+use log::warn;
+use serde::Serialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::error::DownloaderError;
+
+/// Successful requests in a row needed before another concurrent request is
+/// allowed again after a 429
+const INCREASE_AFTER: usize = 20;
+
+/// Requests used of the daily budget
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct QuotaStatus {
+  pub used: u64,
+  /// 0 when there is no budget
+  pub budget: u64,
+  /// Unix timestamp of the next reset
+  pub reset_at: i64,
+  /// Set when google said the quota is used up before the budget was
+  pub exhausted: bool,
+  /// API requests allowed at the same time right now
+  pub concurrency: usize,
+}
+
+#[derive(Debug)]
+struct Day {
+  used: u64,
+  reset_at: DateTime<Utc>,
+  exhausted: bool,
+}
+
+/// Keeps the API requests of every downloader of a pool under the daily
+/// budget of the project, and backs off on the number of requests running at
+/// the same time when google answers with 429
+#[derive(Debug)]
+pub struct QuotaTracker {
+  budget: u64,
+  reset_time: NaiveTime,
+  day: Mutex<Day>,
+  semaphore: Semaphore,
+  max_concurrency: usize,
+  concurrency: AtomicUsize,
+  /// Permits to forget instead of giving back, after the concurrency was
+  /// lowered
+  debt: AtomicUsize,
+  successes: AtomicUsize,
+}
+
+impl QuotaTracker {
+  pub fn new(config: &QuotaConfig, max_concurrency: usize) -> Self {
+    let max_concurrency = max_concurrency.max(1);
+    let reset_time = NaiveTime::from_hms_opt(config.reset_hour_utc % 24, 0, 0).unwrap_or_default();
+
+    Self {
+      budget: config.daily_requests,
+      reset_time,
+      day: Mutex::new(Day { used: 0, reset_at: next_reset(reset_time, Utc::now()), exhausted: false }),
+      semaphore: Semaphore::new(max_concurrency),
+      max_concurrency,
+      concurrency: AtomicUsize::new(max_concurrency),
+      debt: AtomicUsize::new(0),
+      successes: AtomicUsize::new(0),
+    }
+  }
+
+  pub fn unlimited(max_concurrency: usize) -> Self {
+    Self::new(&QuotaConfig { daily_requests: 0, ..Default::default() }, max_concurrency)
+  }
+
+  pub fn get_status(&self) -> QuotaStatus {
+    let day = self.day();
+    QuotaStatus {
+      used: day.used,
+      budget: self.budget,
+      reset_at: day.reset_at.timestamp(),
+      exhausted: day.exhausted,
+      concurrency: self.concurrency.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Count a request against the budget
+  ///
+  /// Returns `QuotaExhausted` with the time of the reset when the budget is
+  /// used up, it is up to the caller to come back then without holding on to
+  /// its downloader
+  pub(crate) fn reserve(&self) -> Result<(), DownloaderError> {
+    let mut day = self.day();
+    if !day.exhausted && (self.budget == 0 || day.used < self.budget) {
+      day.used += 1;
+      return Ok(());
+    }
+
+    Err(DownloaderError::QuotaExhausted(day.reset_at.timestamp()))
+  }
+
+  /// Wait for a free request slot
+  pub(crate) async fn permit(&self) -> RequestPermit<'_> {
+    // The semaphore is never closed
+    let permit = self.semaphore.acquire().await.unwrap();
+    RequestPermit { tracker: self, permit: Some(permit) }
+  }
+
+  /// Google rate limited a request, lower the concurrency. `daily` is set
+  /// when the daily quota is the reason
+  pub(crate) fn rate_limited(&self, daily: bool) {
+    self.successes.store(0, Ordering::Relaxed);
+
+    if daily {
+      warn!("Google reports the daily API quota as used up");
+      self.day().exhausted = true;
+    }
+
+    let concurrency = self.concurrency.load(Ordering::Relaxed);
+    let lowered = (concurrency / 2).max(1);
+    if lowered < concurrency
+      && self.concurrency.compare_exchange(concurrency, lowered, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+    {
+      self.debt.fetch_add(concurrency - lowered, Ordering::Relaxed);
+      warn!("Rate limited by google, allowing {} concurrent requests", lowered);
+    }
+  }
+
+  /// A request went through, allow one more concurrent request after enough
+  /// of them
+  pub(crate) fn succeeded(&self) {
+    if self.successes.fetch_add(1, Ordering::Relaxed) + 1 < INCREASE_AFTER {
+      return;
+    }
+    self.successes.store(0, Ordering::Relaxed);
+
+    let concurrency = self.concurrency.load(Ordering::Relaxed);
+    if concurrency < self.max_concurrency
+      && self.concurrency.compare_exchange(concurrency, concurrency + 1, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+    {
+      // Cancel a permit that was going to be forgotten, or add one back
+      let paid = self.debt.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |d| d.checked_sub(1)).is_ok();
+      if !paid {
+        self.semaphore.add_permits(1);
+      }
+    }
+  }
+
+  fn day(&self) -> std::sync::MutexGuard<'_, Day> {
+    let mut day = self.day.lock().unwrap_or_else(|e| e.into_inner());
+    let now = Utc::now();
+    if now >= day.reset_at {
+      *day = Day { used: 0, reset_at: next_reset(self.reset_time, now), exhausted: false };
+    }
+    day
+  }
+}
+
+/// A slot for one API request, given back (or forgotten if the concurrency
+/// was lowered) when dropped
+pub(crate) struct RequestPermit<'a> {
+  tracker: &'a QuotaTracker,
+  permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for RequestPermit<'_> {
+  fn drop(&mut self) {
+    let forget = self.tracker.debt.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |d| d.checked_sub(1)).is_ok();
+    if let (true, Some(permit)) = (forget, self.permit.take()) {
+      permit.forget();
+    }
+  }
+}
+
+fn next_reset(time: NaiveTime, now: DateTime<Utc>) -> DateTime<Utc> {
+  let today = now.date_naive().and_time(time).and_utc();
+  match today > now {
+    true => today,
+    false => today.checked_add_days(Days::new(1)).unwrap_or(today),
+  }
+}
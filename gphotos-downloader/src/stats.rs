@@ -0,0 +1,135 @@
+use std::{
+  collections::BTreeMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+  },
+  time::Duration,
+};
+
+/// Upper bounds of the buckets of the time spent waiting for a downloader, in
+/// seconds
+pub const WAIT_BUCKETS: [f64; 11] = [0.001, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Counts durations into buckets, like a prometheus histogram
+#[derive(Debug)]
+pub struct Histogram {
+  bounds: &'static [f64],
+  buckets: Vec<AtomicU64>,
+  sum_micros: AtomicU64,
+  count: AtomicU64,
+}
+
+/// The state of a histogram at one point in time
+#[derive(Debug, Clone, Default)]
+pub struct HistogramSnapshot {
+  /// Upper bound in seconds and how many observations were at most that,
+  /// without the `+Inf` bucket (which is `count`)
+  pub buckets: Vec<(f64, u64)>,
+  /// In seconds
+  pub sum: f64,
+  pub count: u64,
+}
+
+impl Histogram {
+  pub fn new(bounds: &'static [f64]) -> Self {
+    Self {
+      bounds,
+      buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+      sum_micros: AtomicU64::new(0),
+      count: AtomicU64::new(0),
+    }
+  }
+
+  pub fn observe(&self, duration: Duration) {
+    let secs = duration.as_secs_f64();
+    if let Some(i) = self.bounds.iter().position(|b| secs <= *b) {
+      self.buckets[i].fetch_add(1, Ordering::Relaxed);
+    }
+    self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    self.count.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> HistogramSnapshot {
+    let mut total = 0;
+    let buckets = self
+      .bounds
+      .iter()
+      .zip(&self.buckets)
+      .map(|(b, c)| {
+        total += c.load(Ordering::Relaxed);
+        (*b, total)
+      })
+      .collect();
+
+    HistogramSnapshot {
+      buckets,
+      sum: self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+      count: self.count.load(Ordering::Relaxed),
+    }
+  }
+}
+
+/// What the downloaders of a pool did since it was created
+#[derive(Debug)]
+pub struct PoolStats {
+  wait: Histogram,
+  bytes: AtomicU64,
+  items: AtomicU64,
+  /// Responses by call and status, `error` when there was no response
+  responses: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+/// The counters of a pool at one point in time
+#[derive(Debug, Clone, Default)]
+pub struct PoolStatsSnapshot {
+  pub wait: HistogramSnapshot,
+  pub bytes: u64,
+  pub items: u64,
+  /// Call, status and count
+  pub responses: Vec<(String, String, u64)>,
+}
+
+impl Default for PoolStats {
+  fn default() -> Self {
+    Self {
+      wait: Histogram::new(&WAIT_BUCKETS),
+      bytes: AtomicU64::new(0),
+      items: AtomicU64::new(0),
+      responses: Mutex::new(BTreeMap::new()),
+    }
+  }
+}
+
+impl PoolStats {
+  /// Count a media item that was archived with one of the downloaders
+  pub fn item_archived(&self) {
+    self.items.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> PoolStatsSnapshot {
+    let responses = self.responses.lock().unwrap_or_else(|e| e.into_inner());
+    PoolStatsSnapshot {
+      wait: self.wait.snapshot(),
+      bytes: self.bytes.load(Ordering::Relaxed),
+      items: self.items.load(Ordering::Relaxed),
+      responses: responses.iter().map(|((c, s), n)| (c.clone(), s.clone(), *n)).collect(),
+    }
+  }
+
+  pub(crate) fn waited(&self, duration: Duration) {
+    self.wait.observe(duration);
+  }
+
+  pub(crate) fn downloaded(&self, bytes: u64) {
+    self.bytes.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  /// Count a response to `call`, `None` if the request failed before there
+  /// was one
+  pub(crate) fn responded(&self, call: &str, status: Option<u16>) {
+    let status = status.map(|s| s.to_string()).unwrap_or_else(|| "error".to_string());
+    let mut responses = self.responses.lock().unwrap_or_else(|e| e.into_inner());
+    *responses.entry((call.to_string(), status)).or_default() += 1;
+  }
+}
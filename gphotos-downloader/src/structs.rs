@@ -1,14 +1,11 @@
-use std::sync::Arc;
-
-use serde_json::from_str;
-use tokio::sync::OwnedSemaphorePermit;
+use std::{fmt, sync::Arc};
 
 use crate::{error::DownloaderError, Downloader, DownloaderPool};
+use serde_json::from_str;
 
 pub struct DownloaderGuard {
   pub(crate) downloader: Option<Downloader>,
   pub(crate) pool: Arc<DownloaderPool>,
-  pub(crate) _permit: OwnedSemaphorePermit,
 }
 
 impl DownloaderGuard {
@@ -20,59 +17,331 @@ impl DownloaderGuard {
 impl Drop for DownloaderGuard {
   fn drop(&mut self) {
     if let Some(d) = self.downloader.take() {
-      let pool = self.pool.clone();
-      tokio::spawn(async move { pool.return_to_pool(d).await });
+      self.pool.return_to_pool(d);
     }
   }
 }
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// EXIF data of a photo, every field is missing when the photo has none (ex:
+/// screenshots)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Photo {
-  #[serde(rename = "apertureFNumber")]
-  pub aperture_f_number: f64,
-  #[serde(rename = "cameraMake")]
-  pub camera_make: String,
-  #[serde(rename = "cameraModel")]
-  pub camera_model: String,
-  #[serde(rename = "exposureTime")]
-  pub exposure_time: String,
-  #[serde(rename = "focalLength")]
-  pub focal_length: f64,
-  #[serde(rename = "isoEquivalent")]
-  pub iso_equivalent: u32,
+  #[serde(rename = "apertureFNumber", default, skip_serializing_if = "Option::is_none")]
+  pub aperture_f_number: Option<f64>,
+  #[serde(rename = "cameraMake", default, skip_serializing_if = "Option::is_none")]
+  pub camera_make: Option<String>,
+  #[serde(rename = "cameraModel", default, skip_serializing_if = "Option::is_none")]
+  pub camera_model: Option<String>,
+  #[serde(rename = "exposureTime", default, skip_serializing_if = "Option::is_none")]
+  pub exposure_time: Option<String>,
+  #[serde(rename = "focalLength", default, skip_serializing_if = "Option::is_none")]
+  pub focal_length: Option<f64>,
+  #[serde(rename = "isoEquivalent", default, skip_serializing_if = "Option::is_none")]
+  pub iso_equivalent: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Where google is in processing an uploaded video, it can only be
+/// downloaded once it is `Ready`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum VideoProcessingStatus {
+  #[default]
+  Unspecified,
+  Processing,
+  Ready,
+  Failed,
+}
+
+impl fmt::Display for VideoProcessingStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Unspecified => write!(f, "UNSPECIFIED"),
+      Self::Processing => write!(f, "PROCESSING"),
+      Self::Ready => write!(f, "READY"),
+      Self::Failed => write!(f, "FAILED"),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Video {
+  #[serde(rename = "cameraMake", default, skip_serializing_if = "Option::is_none")]
+  pub camera_make: Option<String>,
+  #[serde(rename = "cameraModel", default, skip_serializing_if = "Option::is_none")]
+  pub camera_model: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub fps: Option<f64>,
+  #[serde(default)]
+  pub status: VideoProcessingStatus,
+}
+
+/// Only one of `photo` and `video` is set, depending on the kind of item
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MediaMetadata {
   #[serde(rename = "creationTime")]
   pub creation_time: String,
+  #[serde(default)]
   pub height: String,
+  #[serde(default)]
   pub width: String,
-  pub photo: Photo,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub photo: Option<Photo>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub video: Option<Video>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Who added an item to a shared album
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContributorInfo {
+  #[serde(rename = "profilePictureBaseUrl", default)]
+  pub profile_picture_base_url: String,
+  #[serde(rename = "displayName", default)]
+  pub display_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MediaItem {
   #[serde(rename = "baseUrl")]
   pub base_url: String,
   pub filename: String,
   pub id: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
   #[serde(rename = "mediaMetadata")]
   pub media_metadata: MediaMetadata,
   #[serde(rename = "mimeType")]
   pub mime_type: String,
-  #[serde(rename = "productUrl")]
+  #[serde(rename = "productUrl", default)]
   pub product_url: String,
+  /// Only set for items of shared albums
+  #[serde(rename = "contributorInfo", default, skip_serializing_if = "Option::is_none")]
+  pub contributor_info: Option<ContributorInfo>,
+}
+
+impl MediaItem {
+  /// Status of the video processing, None for photos
+  pub fn get_video_status(&self) -> Option<VideoProcessingStatus> {
+    self.media_metadata.video.as_ref().map(|v| v.status)
+  }
+
+  /// Can the item be downloaded, videos can not until google processed them
+  pub fn is_ready(&self) -> bool {
+    self.get_video_status().is_none_or(|s| s == VideoProcessingStatus::Ready)
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MediaItemsResponse {
-  #[serde(rename = "mediaItems")]
+  #[serde(rename = "mediaItems", default)]
   pub media_items: Vec<MediaItem>,
   #[serde(rename = "nextPageToken")]
-  pub next_page_token: String,
+  pub next_page_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Album {
+  pub id: String,
+  #[serde(default)]
+  pub title: String,
+  #[serde(rename = "productUrl")]
+  pub product_url: Option<String>,
+  #[serde(rename = "mediaItemsCount")]
+  pub media_items_count: Option<String>,
+  #[serde(rename = "coverPhotoBaseUrl")]
+  pub cover_photo_base_url: Option<String>,
+  #[serde(rename = "coverPhotoMediaItemId")]
+  pub cover_photo_media_item_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AlbumsResponse {
+  #[serde(default)]
+  pub albums: Vec<Album>,
+  #[serde(rename = "nextPageToken")]
+  pub next_page_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SharedAlbumsResponse {
+  #[serde(rename = "sharedAlbums", default)]
+  pub shared_albums: Vec<Album>,
+  #[serde(rename = "nextPageToken")]
+  pub next_page_token: Option<String>,
+}
+
+/// A calendar date, 0 in a field matches any value (ex: month 0 is the whole
+/// year)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+  #[serde(default)]
+  pub year: i32,
+  #[serde(default)]
+  pub month: u32,
+  #[serde(default)]
+  pub day: u32,
+}
+
+/// Dates between `start_date` and `end_date`, both included
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateRange {
+  #[serde(rename = "startDate")]
+  pub start_date: Date,
+  #[serde(rename = "endDate")]
+  pub end_date: Date,
+}
+
+/// Matches items created on any of `dates` or in any of `ranges`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DateFilter {
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub dates: Vec<Date>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub ranges: Vec<DateRange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ContentCategory {
+  None,
+  Landscapes,
+  Receipts,
+  Cityscapes,
+  Landmarks,
+  Selfies,
+  People,
+  Pets,
+  Weddings,
+  Birthdays,
+  Documents,
+  Travel,
+  Animals,
+  Food,
+  Sport,
+  Night,
+  Performances,
+  Whiteboards,
+  Screenshots,
+  Utility,
+  Arts,
+  Crafts,
+  Fashion,
+  Houses,
+  Gardens,
+  Flowers,
+  Holidays,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContentFilter {
+  #[serde(rename = "includedContentCategories", default, skip_serializing_if = "Vec::is_empty")]
+  pub included_content_categories: Vec<ContentCategory>,
+  #[serde(rename = "excludedContentCategories", default, skip_serializing_if = "Vec::is_empty")]
+  pub excluded_content_categories: Vec<ContentCategory>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum MediaType {
+  AllMedia,
+  Video,
+  Photo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MediaTypeFilter {
+  #[serde(rename = "mediaTypes")]
+  pub media_types: Vec<MediaType>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Feature {
+  None,
+  Favorites,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeatureFilter {
+  #[serde(rename = "includedFeatures")]
+  pub included_features: Vec<Feature>,
+}
+
+/// Filters for `mediaItems:search`, every set filter has to match
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filters {
+  #[serde(rename = "dateFilter", default, skip_serializing_if = "Option::is_none")]
+  pub date_filter: Option<DateFilter>,
+  #[serde(rename = "contentFilter", default, skip_serializing_if = "Option::is_none")]
+  pub content_filter: Option<ContentFilter>,
+  #[serde(rename = "mediaTypeFilter", default, skip_serializing_if = "Option::is_none")]
+  pub media_type_filter: Option<MediaTypeFilter>,
+  #[serde(rename = "featureFilter", default, skip_serializing_if = "Option::is_none")]
+  pub feature_filter: Option<FeatureFilter>,
+  /// Also return items the user archived
+  #[serde(rename = "includeArchivedMedia", default)]
+  pub include_archived_media: bool,
+}
+
+impl Filters {
+  /// Check the filters against the limits of the API
+  pub fn validate(&self) -> Result<(), DownloaderError> {
+    let invalid = |m: &str| Err(DownloaderError::InvalidFilter(m.to_owned()));
+
+    if let Some(d) = &self.date_filter {
+      if d.dates.len() > 5 || d.ranges.len() > 5 {
+        return invalid("At most 5 dates and 5 date ranges can be used");
+      }
+      for date in d.dates.iter().chain(d.ranges.iter().flat_map(|r| [&r.start_date, &r.end_date])) {
+        if date.month > 12 || date.day > 31 || (date.year == 0 && date.month == 0 && date.day != 0) {
+          return invalid("Invalid date");
+        }
+      }
+    }
+
+    if let Some(c) = &self.content_filter {
+      if c.included_content_categories.len() > 10 || c.excluded_content_categories.len() > 10 {
+        return invalid("At most 10 included and 10 excluded content categories can be used");
+      }
+      if c.included_content_categories.iter().any(|i| c.excluded_content_categories.contains(i)) {
+        return invalid("A content category can not be included and excluded");
+      }
+    }
+
+    if let Some(m) = &self.media_type_filter {
+      if m.media_types.len() != 1 {
+        return invalid("Exactly one media type has to be used");
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// Error of a single item in a batch request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Status {
+  #[serde(default)]
+  pub code: i32,
+  #[serde(default)]
+  pub message: String,
+}
+
+/// Either the media item or why it could not be returned
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MediaItemResult {
+  pub status: Option<Status>,
+  #[serde(rename = "mediaItem")]
+  pub media_item: Option<MediaItem>,
+}
+
+/// Response of `mediaItems:batchGet`, the results are in the same order as the
+/// requested ids
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchGetResponse {
+  #[serde(rename = "mediaItemResults", default)]
+  pub media_item_results: Vec<MediaItemResult>,
 }
 
 impl TryFrom<String> for MediaItemsResponse {
@@ -0,0 +1,200 @@
+//! Checks that every downloader handed out by the pool comes back, whatever
+//! happens to the waiters
+
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use gphotos_downloader::{error::DownloaderError, DownloaderPool, Priority};
+use tokio::{task::yield_now, time::sleep};
+use tokio_util::sync::CancellationToken;
+
+/// Let spawned tasks run until they are waiting on the pool
+async fn settle() {
+  for _ in 0..10 {
+    yield_now().await;
+  }
+  sleep(Duration::from_millis(10)).await;
+}
+
+#[tokio::test]
+async fn acquire_and_release() {
+  let pool = DownloaderPool::new(2);
+
+  let a = pool.clone().acquire(1, Priority::Background).await.unwrap();
+  let b = pool.clone().acquire(2, Priority::Interactive).await.unwrap();
+  assert_eq!(pool.get_available(), 0);
+
+  drop(a);
+  assert_eq!(pool.get_available(), 1);
+  drop(b);
+  assert_eq!(pool.get_available(), 2);
+}
+
+#[tokio::test]
+async fn timeout_leaves_no_waiter() {
+  let pool = DownloaderPool::new(1);
+  let held = pool.clone().acquire(1, Priority::Background).await.unwrap();
+
+  let res = pool.clone().acquire_timeout(2, Priority::Interactive, Duration::from_millis(20)).await;
+  assert!(matches!(res, Err(DownloaderError::Timeout)));
+  assert_eq!(pool.get_queue_depth(2), 0);
+
+  drop(held);
+  assert_eq!(pool.get_available(), 1);
+}
+
+#[tokio::test]
+async fn cancellation_leaves_no_waiter() {
+  let pool = DownloaderPool::new(1);
+  let held = pool.clone().acquire(1, Priority::Background).await.unwrap();
+
+  let cancel = CancellationToken::new();
+  let waiter = {
+    let pool = pool.clone();
+    let cancel = cancel.clone();
+    tokio::spawn(async move { pool.acquire_cancellable(2, Priority::Background, &cancel).await })
+  };
+  settle().await;
+  assert_eq!(pool.get_queue_depth(2), 1);
+
+  cancel.cancel();
+  assert!(matches!(waiter.await.unwrap(), Err(DownloaderError::Cancelled)));
+  assert!(pool.get_queue_depths().is_empty());
+
+  drop(held);
+  assert_eq!(pool.get_available(), 1);
+}
+
+#[tokio::test]
+async fn dropped_waiters_are_skipped() {
+  let pool = DownloaderPool::new(1);
+  let held = pool.clone().acquire(1, Priority::Background).await.unwrap();
+
+  // Waiters whose future is dropped without being cancelled
+  let mut aborted = Vec::new();
+  for owner in 2..5 {
+    let pool = pool.clone();
+    aborted.push(tokio::spawn(async move { pool.acquire(owner, Priority::Background).await }));
+  }
+  let alive = {
+    let pool = pool.clone();
+    tokio::spawn(async move { pool.acquire(5, Priority::Background).await.map(|_| ()) })
+  };
+  settle().await;
+  for task in &aborted {
+    task.abort();
+  }
+  settle().await;
+
+  // The downloader skips the dead waiters instead of getting lost with them
+  drop(held);
+  alive.await.unwrap().unwrap();
+  assert_eq!(pool.get_available(), 1);
+  assert!(pool.get_queue_depths().is_empty());
+}
+
+#[tokio::test]
+async fn owners_take_turns() {
+  let pool = DownloaderPool::new(1);
+  let held = pool.clone().acquire(0, Priority::Background).await.unwrap();
+  let order = Arc::new(Mutex::new(Vec::new()));
+
+  let mut tasks = Vec::new();
+  for (owner, priority) in [
+    (1, Priority::Background),
+    (1, Priority::Background),
+    (1, Priority::Background),
+    (2, Priority::Background),
+    (3, Priority::Interactive),
+  ] {
+    let pool = pool.clone();
+    let order = order.clone();
+    tasks.push(tokio::spawn(async move {
+      let guard = pool.acquire(owner, priority).await.unwrap();
+      order.lock().unwrap().push(owner);
+      drop(guard);
+    }));
+    settle().await;
+  }
+  assert_eq!(pool.get_queue_depth(1), 3);
+  assert_eq!(pool.get_queue_depths().len(), 3);
+
+  drop(held);
+  for task in tasks {
+    task.await.unwrap();
+  }
+
+  // Interactive first, then owner 1 and 2 in turns, then the rest of owner 1
+  assert_eq!(*order.lock().unwrap(), vec![3, 1, 2, 1, 1]);
+  assert_eq!(pool.get_available(), 1);
+}
+
+#[tokio::test]
+async fn shutdown_drains_in_flight_work() {
+  let pool = DownloaderPool::new(2);
+  let held = pool.clone().acquire(1, Priority::Background).await.unwrap();
+  let held_too = pool.clone().acquire(2, Priority::Background).await.unwrap();
+
+  let waiter = {
+    let pool = pool.clone();
+    tokio::spawn(async move { pool.acquire(3, Priority::Interactive).await.map(|_| ()) })
+  };
+  settle().await;
+
+  let release = tokio::spawn(async move {
+    sleep(Duration::from_millis(50)).await;
+    drop(held);
+    sleep(Duration::from_millis(50)).await;
+    drop(held_too);
+  });
+
+  pool.shutdown().await;
+  assert_eq!(pool.get_available(), 2);
+  assert!(matches!(waiter.await.unwrap(), Err(DownloaderError::ShutDown)));
+  assert!(matches!(pool.clone().acquire(1, Priority::Background).await, Err(DownloaderError::ShutDown)));
+  release.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn no_capacity_leak_under_churn() {
+  let pool = DownloaderPool::new(3);
+
+  let mut tasks = Vec::new();
+  for i in 0..200 {
+    let pool = pool.clone();
+    tasks.push(tokio::spawn(async move {
+      let owner = i % 7;
+      let priority = if i % 5 == 0 { Priority::Interactive } else { Priority::Background };
+      let res = match i % 3 {
+        0 => pool.acquire(owner, priority).await,
+        1 => pool.acquire_timeout(owner, priority, Duration::from_millis((i % 4) as u64)).await,
+        _ => {
+          let cancel = CancellationToken::new();
+          if i % 2 == 0 {
+            cancel.cancel();
+          }
+          pool.acquire_cancellable(owner, priority, &cancel).await
+        }
+      };
+      if let Ok(guard) = res {
+        sleep(Duration::from_micros(200)).await;
+        drop(guard);
+      }
+    }));
+  }
+
+  // Abort some of them at random points, while waiting or while holding
+  for (i, task) in tasks.iter().enumerate() {
+    if i % 11 == 0 {
+      task.abort();
+    }
+  }
+  for task in tasks {
+    let _ = task.await;
+  }
+
+  assert_eq!(pool.get_available(), pool.get_size());
+  assert!(pool.get_queue_depths().is_empty());
+}
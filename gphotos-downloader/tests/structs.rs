@@ -0,0 +1,97 @@
+//! Parses responses shaped like the ones the Photos Library API sends, with the
+//! ids and urls replaced
+
+use gphotos_downloader::structs::{MediaItem, MediaItemsResponse, VideoProcessingStatus};
+
+fn item(fixture: &str) -> MediaItem {
+  serde_json::from_str(fixture).unwrap()
+}
+
+#[test]
+fn photo_with_exif() {
+  let item = item(include_str!("fixtures/photo_exif.json"));
+  let photo = item.media_metadata.photo.unwrap();
+
+  assert_eq!(item.filename, "PXL_20240714_194208123.jpg");
+  assert_eq!(item.description.as_deref(), Some("Sunset from the pier"));
+  assert_eq!(item.media_metadata.creation_time, "2024-07-14T19:42:08Z");
+  assert_eq!(item.media_metadata.width, "4032");
+  assert!(item.media_metadata.video.is_none());
+  assert!(item.contributor_info.is_none());
+
+  assert_eq!(photo.camera_make.as_deref(), Some("Google"));
+  assert_eq!(photo.camera_model.as_deref(), Some("Pixel 7"));
+  assert_eq!(photo.aperture_f_number, Some(1.85));
+  assert_eq!(photo.focal_length, Some(6.81));
+  assert_eq!(photo.iso_equivalent, Some(45));
+  assert_eq!(photo.exposure_time.as_deref(), Some("0.002732s"));
+}
+
+#[test]
+fn photo_without_exif() {
+  let item = item(include_str!("fixtures/photo_no_exif.json"));
+  let photo = item.media_metadata.photo.unwrap();
+
+  assert!(item.description.is_none());
+  assert!(photo.camera_make.is_none());
+  assert!(photo.aperture_f_number.is_none());
+  assert!(photo.iso_equivalent.is_none());
+  assert!(photo.exposure_time.is_none());
+}
+
+#[test]
+fn video_ready() {
+  let item = item(include_str!("fixtures/video_ready.json"));
+  let video = item.media_metadata.video.unwrap();
+
+  assert!(item.media_metadata.photo.is_none());
+  assert_eq!(video.fps, Some(29.97));
+  assert_eq!(video.status, VideoProcessingStatus::Ready);
+  assert_eq!(video.camera_model.as_deref(), Some("Pixel 7"));
+}
+
+#[test]
+fn video_processing() {
+  let item = item(include_str!("fixtures/video_processing.json"));
+  let video = item.media_metadata.video.unwrap();
+
+  assert_eq!(video.status, VideoProcessingStatus::Processing);
+  assert!(video.fps.is_none());
+  assert!(video.camera_make.is_none());
+}
+
+#[test]
+fn shared_album_page() {
+  let page: MediaItemsResponse = serde_json::from_str(include_str!("fixtures/shared_album_items.json")).unwrap();
+
+  assert_eq!(page.media_items.len(), 2);
+  assert!(page.next_page_token.is_some());
+
+  let contributor = page.media_items[0].contributor_info.as_ref().unwrap();
+  assert_eq!(contributor.display_name, "Sam");
+  assert!(page.media_items[0].media_metadata.photo.as_ref().unwrap().exposure_time.is_none());
+
+  // Whole numbers are sent without a fraction
+  assert_eq!(page.media_items[1].media_metadata.video.as_ref().unwrap().fps, Some(30.0));
+}
+
+#[test]
+fn empty_page() {
+  let page: MediaItemsResponse = serde_json::from_str("{}").unwrap();
+
+  assert!(page.media_items.is_empty());
+  assert!(page.next_page_token.is_none());
+}
+
+#[test]
+fn round_trip() {
+  let item = item(include_str!("fixtures/video_processing.json"));
+  let json = serde_json::to_value(&item).unwrap();
+
+  assert_eq!(json["mediaMetadata"]["video"]["status"], "PROCESSING");
+  assert!(json["mediaMetadata"].get("photo").is_none());
+  assert!(json.get("contributorInfo").is_none());
+
+  let parsed: MediaItem = serde_json::from_value(json).unwrap();
+  assert_eq!(parsed.media_metadata.video.unwrap().status, VideoProcessingStatus::Processing);
+}
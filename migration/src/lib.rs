@@ -1,13 +1,44 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20241201_000002_create_google_accounts;
+mod m20241201_000003_add_google_account_scopes;
+mod m20241201_000004_add_user_roles;
+mod m20241201_000005_create_invites;
+mod m20241201_000006_create_audit_events;
+mod m20241201_000007_create_media_items;
+mod m20241201_000008_add_media_item_paths;
+mod m20241201_000009_create_albums;
+mod m20241201_000010_create_pending_media_items;
+mod m20241201_000011_create_sync_jobs;
+mod m20241201_000012_create_sync_schedules;
+mod m20241201_000013_create_verify_runs;
+mod m20241201_000014_promote_first_admin;
+mod m20241201_000015_add_google_account_refresh_tokens;
+mod m20241201_000016_add_media_item_path_hashes;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
   fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-    // vec![Box::new(m20220101_000001_create_table::Migration)]
-    vec![]
+    vec![
+      Box::new(m20220101_000001_create_table::Migration),
+      Box::new(m20241201_000002_create_google_accounts::Migration),
+      Box::new(m20241201_000003_add_google_account_scopes::Migration),
+      Box::new(m20241201_000004_add_user_roles::Migration),
+      Box::new(m20241201_000005_create_invites::Migration),
+      Box::new(m20241201_000006_create_audit_events::Migration),
+      Box::new(m20241201_000007_create_media_items::Migration),
+      Box::new(m20241201_000008_add_media_item_paths::Migration),
+      Box::new(m20241201_000009_create_albums::Migration),
+      Box::new(m20241201_000010_create_pending_media_items::Migration),
+      Box::new(m20241201_000011_create_sync_jobs::Migration),
+      Box::new(m20241201_000012_create_sync_schedules::Migration),
+      Box::new(m20241201_000013_create_verify_runs::Migration),
+      Box::new(m20241201_000014_promote_first_admin::Migration),
+      Box::new(m20241201_000015_add_google_account_refresh_tokens::Migration),
+      Box::new(m20241201_000016_add_media_item_path_hashes::Migration),
+    ]
   }
 }
@@ -6,20 +6,30 @@ pub struct Migration;
 #[async_trait::async_trait]
 impl MigrationTrait for Migration {
   async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
-    // Replace the sample below with your own migration scripts
-    todo!();
+    manager
+      .create_table(
+        Table::create()
+          .table(Users::Table)
+          .if_not_exists()
+          .col(pk_auto(Users::Id))
+          .col(string_uniq(Users::Username))
+          .col(string(Users::PasswordHash))
+          .col(big_integer_null(Users::CreatedAt))
+          .to_owned(),
+      )
+      .await
   }
 
   async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
-    // Replace the sample below with your own migration scripts
-    todo!();
+    manager.drop_table(Table::drop().table(Users::Table).to_owned()).await
   }
 }
 
 #[derive(DeriveIden)]
-enum Post {
+pub(crate) enum Users {
   Table,
   Id,
-  Title,
-  Text,
+  Username,
+  PasswordHash,
+  CreatedAt,
 }
@@ -0,0 +1,48 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20220101_000001_create_table::Users;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .create_table(
+        Table::create()
+          .table(GoogleAccounts::Table)
+          .if_not_exists()
+          .col(pk_auto(GoogleAccounts::Id))
+          .col(integer_uniq(GoogleAccounts::UserId))
+          .col(string(GoogleAccounts::Name))
+          .col(string(GoogleAccounts::PfpUrl))
+          .col(text(GoogleAccounts::AccessToken))
+          .col(big_integer(GoogleAccounts::LinkedAt))
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_google_accounts_user_id")
+              .from(GoogleAccounts::Table, GoogleAccounts::UserId)
+              .to(Users::Table, Users::Id)
+              .on_delete(ForeignKeyAction::Cascade),
+          )
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_table(Table::drop().table(GoogleAccounts::Table).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+pub(crate) enum GoogleAccounts {
+  Table,
+  Id,
+  UserId,
+  Name,
+  PfpUrl,
+  AccessToken,
+  LinkedAt,
+}
@@ -0,0 +1,26 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20241201_000002_create_google_accounts::GoogleAccounts;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .alter_table(
+        Table::alter().table(GoogleAccounts::Table).add_column_if_not_exists(text(Scopes::Scopes).default("")).to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.alter_table(Table::alter().table(GoogleAccounts::Table).drop_column(Scopes::Scopes).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum Scopes {
+  Scopes,
+}
@@ -0,0 +1,26 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20220101_000001_create_table::Users;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .alter_table(
+        Table::alter().table(Users::Table).add_column_if_not_exists(string(Role::Role).default("member")).to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.alter_table(Table::alter().table(Users::Table).drop_column(Role::Role).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum Role {
+  Role,
+}
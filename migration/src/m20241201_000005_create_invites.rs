@@ -0,0 +1,57 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20220101_000001_create_table::Users;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .create_table(
+        Table::create()
+          .table(Invites::Table)
+          .if_not_exists()
+          .col(string(Invites::Code).primary_key())
+          .col(string(Invites::Role))
+          .col(integer_null(Invites::CreatedBy))
+          .col(big_integer(Invites::CreatedAt))
+          .col(big_integer(Invites::ExpiresAt))
+          .col(integer_null(Invites::UsedBy))
+          .col(big_integer_null(Invites::UsedAt))
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_invites_created_by")
+              .from(Invites::Table, Invites::CreatedBy)
+              .to(Users::Table, Users::Id)
+              .on_delete(ForeignKeyAction::SetNull),
+          )
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_invites_used_by")
+              .from(Invites::Table, Invites::UsedBy)
+              .to(Users::Table, Users::Id)
+              .on_delete(ForeignKeyAction::SetNull),
+          )
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_table(Table::drop().table(Invites::Table).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum Invites {
+  Table,
+  Code,
+  Role,
+  CreatedBy,
+  CreatedAt,
+  ExpiresAt,
+  UsedBy,
+  UsedAt,
+}
@@ -0,0 +1,52 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .create_table(
+        Table::create()
+          .table(AuditEvents::Table)
+          .if_not_exists()
+          .col(pk_auto(AuditEvents::Id))
+          .col(big_integer(AuditEvents::CreatedAt))
+          .col(string(AuditEvents::Action))
+          .col(integer_null(AuditEvents::ActorId))
+          .col(integer_null(AuditEvents::TargetId))
+          .col(string_null(AuditEvents::Ip))
+          .col(text_null(AuditEvents::Details))
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_index(
+        Index::create()
+          .name("idx_audit_events_created_at")
+          .table(AuditEvents::Table)
+          .col(AuditEvents::CreatedAt)
+          .if_not_exists()
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_table(Table::drop().table(AuditEvents::Table).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum AuditEvents {
+  Table,
+  Id,
+  CreatedAt,
+  Action,
+  ActorId,
+  TargetId,
+  Ip,
+  Details,
+}
@@ -0,0 +1,108 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20220101_000001_create_table::Users;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .create_table(
+        Table::create()
+          .table(Blobs::Table)
+          .if_not_exists()
+          .col(string(Blobs::Hash).primary_key())
+          .col(big_integer(Blobs::Size))
+          .col(integer(Blobs::RefCount).default(0))
+          .col(big_integer(Blobs::CreatedAt))
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_table(
+        Table::create()
+          .table(MediaItems::Table)
+          .if_not_exists()
+          .col(pk_auto(MediaItems::Id))
+          .col(integer(MediaItems::UserId))
+          .col(string(MediaItems::GoogleId))
+          .col(string(MediaItems::Filename))
+          .col(string(MediaItems::MimeType))
+          .col(string_null(MediaItems::CreationTime))
+          .col(string(MediaItems::BlobHash))
+          .col(big_integer(MediaItems::Size))
+          .col(big_integer(MediaItems::StoredAt))
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_media_items_user_id")
+              .from(MediaItems::Table, MediaItems::UserId)
+              .to(Users::Table, Users::Id)
+              .on_delete(ForeignKeyAction::Cascade),
+          )
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_media_items_blob_hash")
+              .from(MediaItems::Table, MediaItems::BlobHash)
+              .to(Blobs::Table, Blobs::Hash)
+              .on_delete(ForeignKeyAction::Restrict),
+          )
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_index(
+        Index::create()
+          .name("idx_media_items_user_google_id")
+          .table(MediaItems::Table)
+          .col(MediaItems::UserId)
+          .col(MediaItems::GoogleId)
+          .unique()
+          .if_not_exists()
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_index(
+        Index::create()
+          .name("idx_media_items_blob_hash")
+          .table(MediaItems::Table)
+          .col(MediaItems::BlobHash)
+          .if_not_exists()
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_table(Table::drop().table(MediaItems::Table).to_owned()).await?;
+    manager.drop_table(Table::drop().table(Blobs::Table).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+pub(crate) enum Blobs {
+  Table,
+  Hash,
+  Size,
+  RefCount,
+  CreatedAt,
+}
+
+#[derive(DeriveIden)]
+pub(crate) enum MediaItems {
+  Table,
+  Id,
+  UserId,
+  GoogleId,
+  Filename,
+  MimeType,
+  CreationTime,
+  BlobHash,
+  Size,
+  StoredAt,
+}
@@ -0,0 +1,37 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20241201_000007_create_media_items::MediaItems;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .alter_table(Table::alter().table(MediaItems::Table).add_column_if_not_exists(string_null(Path::Path)).to_owned())
+      .await?;
+
+    manager
+      .create_index(
+        Index::create()
+          .name("idx_media_items_path")
+          .table(MediaItems::Table)
+          .col(Path::Path)
+          .unique()
+          .if_not_exists()
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_index(Index::drop().name("idx_media_items_path").table(MediaItems::Table).to_owned()).await?;
+    manager.alter_table(Table::alter().table(MediaItems::Table).drop_column(Path::Path).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum Path {
+  Path,
+}
@@ -0,0 +1,105 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20220101_000001_create_table::Users;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .create_table(
+        Table::create()
+          .table(Albums::Table)
+          .if_not_exists()
+          .col(pk_auto(Albums::Id))
+          .col(integer(Albums::UserId))
+          .col(string(Albums::GoogleId))
+          .col(string(Albums::Title))
+          .col(boolean(Albums::Shared).default(false))
+          .col(string_null(Albums::CoverMediaItemId))
+          .col(integer(Albums::ItemCount).default(0))
+          .col(big_integer(Albums::SyncedAt))
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_albums_user_id")
+              .from(Albums::Table, Albums::UserId)
+              .to(Users::Table, Users::Id)
+              .on_delete(ForeignKeyAction::Cascade),
+          )
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_index(
+        Index::create()
+          .name("idx_albums_user_google_id")
+          .table(Albums::Table)
+          .col(Albums::UserId)
+          .col(Albums::GoogleId)
+          .unique()
+          .if_not_exists()
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_table(
+        Table::create()
+          .table(AlbumItems::Table)
+          .if_not_exists()
+          .col(integer(AlbumItems::AlbumId))
+          .col(string(AlbumItems::MediaItemGoogleId))
+          .col(integer(AlbumItems::Position))
+          .primary_key(Index::create().col(AlbumItems::AlbumId).col(AlbumItems::MediaItemGoogleId))
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_album_items_album_id")
+              .from(AlbumItems::Table, AlbumItems::AlbumId)
+              .to(Albums::Table, Albums::Id)
+              .on_delete(ForeignKeyAction::Cascade),
+          )
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_index(
+        Index::create()
+          .name("idx_album_items_media_item_google_id")
+          .table(AlbumItems::Table)
+          .col(AlbumItems::MediaItemGoogleId)
+          .if_not_exists()
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_table(Table::drop().table(AlbumItems::Table).to_owned()).await?;
+    manager.drop_table(Table::drop().table(Albums::Table).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum Albums {
+  Table,
+  Id,
+  UserId,
+  GoogleId,
+  Title,
+  Shared,
+  CoverMediaItemId,
+  ItemCount,
+  SyncedAt,
+}
+
+#[derive(DeriveIden)]
+enum AlbumItems {
+  Table,
+  AlbumId,
+  MediaItemGoogleId,
+  Position,
+}
@@ -0,0 +1,60 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20220101_000001_create_table::Users;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .create_table(
+        Table::create()
+          .table(PendingMediaItems::Table)
+          .if_not_exists()
+          .col(integer(PendingMediaItems::UserId))
+          .col(string(PendingMediaItems::GoogleId))
+          .col(string(PendingMediaItems::Status))
+          .col(integer(PendingMediaItems::Attempts).default(0))
+          .col(big_integer(PendingMediaItems::FirstSeenAt))
+          .col(big_integer(PendingMediaItems::NextAttemptAt))
+          .primary_key(Index::create().col(PendingMediaItems::UserId).col(PendingMediaItems::GoogleId))
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_pending_media_items_user_id")
+              .from(PendingMediaItems::Table, PendingMediaItems::UserId)
+              .to(Users::Table, Users::Id)
+              .on_delete(ForeignKeyAction::Cascade),
+          )
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_index(
+        Index::create()
+          .name("idx_pending_media_items_next_attempt_at")
+          .table(PendingMediaItems::Table)
+          .col(PendingMediaItems::NextAttemptAt)
+          .if_not_exists()
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_table(Table::drop().table(PendingMediaItems::Table).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum PendingMediaItems {
+  Table,
+  UserId,
+  GoogleId,
+  Status,
+  Attempts,
+  FirstSeenAt,
+  NextAttemptAt,
+}
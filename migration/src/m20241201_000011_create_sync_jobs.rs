@@ -0,0 +1,67 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20220101_000001_create_table::Users;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .create_table(
+        Table::create()
+          .table(SyncJobs::Table)
+          .if_not_exists()
+          .col(pk_auto(SyncJobs::Id))
+          .col(integer(SyncJobs::UserId))
+          .col(string(SyncJobs::Status))
+          .col(text_null(SyncJobs::Filters))
+          .col(text_null(SyncJobs::PageToken))
+          .col(text(SyncJobs::Progress))
+          .col(big_integer(SyncJobs::StartedAt))
+          .col(big_integer(SyncJobs::UpdatedAt))
+          .col(big_integer_null(SyncJobs::FinishedAt))
+          .col(text_null(SyncJobs::Error))
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_sync_jobs_user_id")
+              .from(SyncJobs::Table, SyncJobs::UserId)
+              .to(Users::Table, Users::Id)
+              .on_delete(ForeignKeyAction::Cascade),
+          )
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_index(
+        Index::create()
+          .name("idx_sync_jobs_status")
+          .table(SyncJobs::Table)
+          .col(SyncJobs::Status)
+          .if_not_exists()
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_table(Table::drop().table(SyncJobs::Table).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum SyncJobs {
+  Table,
+  Id,
+  UserId,
+  Status,
+  Filters,
+  PageToken,
+  Progress,
+  StartedAt,
+  UpdatedAt,
+  FinishedAt,
+  Error,
+}
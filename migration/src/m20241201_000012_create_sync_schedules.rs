@@ -0,0 +1,63 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20220101_000001_create_table::Users;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .create_table(
+        Table::create()
+          .table(SyncSchedules::Table)
+          .if_not_exists()
+          .col(pk_auto(SyncSchedules::Id))
+          .col(integer(SyncSchedules::UserId))
+          .col(string(SyncSchedules::Cron))
+          .col(boolean(SyncSchedules::Enabled).default(true))
+          .col(text_null(SyncSchedules::Filters))
+          .col(big_integer_null(SyncSchedules::LastRunAt))
+          .col(big_integer_null(SyncSchedules::NextRunAt))
+          .col(big_integer(SyncSchedules::CreatedAt))
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_sync_schedules_user_id")
+              .from(SyncSchedules::Table, SyncSchedules::UserId)
+              .to(Users::Table, Users::Id)
+              .on_delete(ForeignKeyAction::Cascade),
+          )
+          .to_owned(),
+      )
+      .await?;
+
+    manager
+      .create_index(
+        Index::create()
+          .name("idx_sync_schedules_next_run_at")
+          .table(SyncSchedules::Table)
+          .col(SyncSchedules::NextRunAt)
+          .if_not_exists()
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_table(Table::drop().table(SyncSchedules::Table).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum SyncSchedules {
+  Table,
+  Id,
+  UserId,
+  Cron,
+  Enabled,
+  Filters,
+  LastRunAt,
+  NextRunAt,
+  CreatedAt,
+}
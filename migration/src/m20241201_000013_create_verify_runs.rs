@@ -0,0 +1,52 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20220101_000001_create_table::Users;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .create_table(
+        Table::create()
+          .table(VerifyRuns::Table)
+          .if_not_exists()
+          .col(pk_auto(VerifyRuns::Id))
+          .col(integer_null(VerifyRuns::StartedBy))
+          .col(string(VerifyRuns::Status))
+          .col(text(VerifyRuns::Options))
+          .col(text_null(VerifyRuns::Report))
+          .col(big_integer(VerifyRuns::StartedAt))
+          .col(big_integer_null(VerifyRuns::FinishedAt))
+          .col(text_null(VerifyRuns::Error))
+          .foreign_key(
+            ForeignKey::create()
+              .name("fk_verify_runs_started_by")
+              .from(VerifyRuns::Table, VerifyRuns::StartedBy)
+              .to(Users::Table, Users::Id)
+              .on_delete(ForeignKeyAction::SetNull),
+          )
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.drop_table(Table::drop().table(VerifyRuns::Table).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum VerifyRuns {
+  Table,
+  Id,
+  StartedBy,
+  Status,
+  Options,
+  Report,
+  StartedAt,
+  FinishedAt,
+  Error,
+}
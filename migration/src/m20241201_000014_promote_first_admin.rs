@@ -0,0 +1,39 @@
+use sea_orm_migration::prelude::*;
+
+use crate::m20220101_000001_create_table::Users;
+
+/// Databases that had users before roles existed ended up without an admin,
+/// the oldest account becomes one so invites, resets and the audit log can be
+/// used again
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    let oldest = Query::select().expr(Expr::col(Users::Id).min()).from(Users::Table).to_owned();
+    let admins =
+      Query::select().column(Users::Id).from(Users::Table).and_where(Expr::col(Role::Role).eq("admin")).to_owned();
+
+    manager
+      .exec_stmt(
+        Query::update()
+          .table(Users::Table)
+          .value(Role::Role, "admin")
+          .and_where(Expr::col(Users::Id).in_subquery(oldest))
+          .and_where(Expr::exists(admins).not())
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
+    // Nothing tells the promoted admin apart from one made on purpose
+    Ok(())
+  }
+}
+
+#[derive(DeriveIden)]
+enum Role {
+  Role,
+}
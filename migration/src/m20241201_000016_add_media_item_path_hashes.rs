@@ -0,0 +1,38 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+use crate::m20241201_000007_create_media_items::MediaItems;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+  async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager
+      .alter_table(
+        Table::alter().table(MediaItems::Table).add_column_if_not_exists(string_null(PathHash::PathHash)).to_owned(),
+      )
+      .await?;
+
+    // The copies placed so far were made from the blob the item has now
+    manager
+      .exec_stmt(
+        Query::update()
+          .table(MediaItems::Table)
+          .value(PathHash::PathHash, Expr::col(MediaItems::BlobHash))
+          .and_where(Expr::col(PathHash::Path).is_not_null())
+          .to_owned(),
+      )
+      .await
+  }
+
+  async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+    manager.alter_table(Table::alter().table(MediaItems::Table).drop_column(PathHash::PathHash).to_owned()).await
+  }
+}
+
+#[derive(DeriveIden)]
+enum PathHash {
+  PathHash,
+  Path,
+}
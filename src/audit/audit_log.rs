@@ -0,0 +1,132 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use archive_config::CONFIG;
+use archive_database::database::SharedDatabase;
+use log::{error, info};
+use serde_json::Value;
+use tokio::time::interval;
+use webrs::request::Request;
+
+pub type SharedAuditLog = Arc<AuditLog>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+  Login,
+  LoginFailed,
+  Logout,
+  UserCreated,
+  UserDeleted,
+  PasswordChanged,
+  PasswordReset,
+  InviteCreated,
+  GoogleLinked,
+  GoogleLinkFailed,
+  GoogleUnlinked,
+  PhotosListed,
+  PhotosSearched,
+  SyncStarted,
+  ScheduleCreated,
+  ScheduleModified,
+  ScheduleDeleted,
+  VerifyStarted,
+}
+
+impl AuditAction {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Login => "login",
+      Self::LoginFailed => "login_failed",
+      Self::Logout => "logout",
+      Self::UserCreated => "user_created",
+      Self::UserDeleted => "user_deleted",
+      Self::PasswordChanged => "password_changed",
+      Self::PasswordReset => "password_reset",
+      Self::InviteCreated => "invite_created",
+      Self::GoogleLinked => "google_linked",
+      Self::GoogleLinkFailed => "google_link_failed",
+      Self::GoogleUnlinked => "google_unlinked",
+      Self::PhotosListed => "photos_listed",
+      Self::PhotosSearched => "photos_searched",
+      Self::SyncStarted => "sync_started",
+      Self::ScheduleCreated => "schedule_created",
+      Self::ScheduleModified => "schedule_modified",
+      Self::ScheduleDeleted => "schedule_deleted",
+      Self::VerifyStarted => "verify_started",
+    }
+  }
+}
+
+/// Records security relevant events to the `audit_events` table
+pub struct AuditLog {
+  database: SharedDatabase,
+}
+
+impl AuditLog {
+  pub fn new(database: SharedDatabase) -> SharedAuditLog {
+    let audit_log = Arc::new(Self { database });
+
+    if CONFIG.audit.retention_days > 0 {
+      let purge = Arc::clone(&audit_log);
+      tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(60 * 60));
+        loop {
+          interval.tick().await;
+          let before = chrono::Utc::now().timestamp() - (CONFIG.audit.retention_days * 24 * 60 * 60) as i64;
+          match purge.database.lock().await.purge_audit_events(before).await {
+            Ok(0) => (),
+            Ok(n) => info!("Purged {} audit events older than {} days", n, CONFIG.audit.retention_days),
+            Err(e) => error!("Failed to purge audit events: {}", e),
+          }
+        }
+      });
+    }
+
+    audit_log
+  }
+
+  #[inline]
+  pub fn get_database(&self) -> SharedDatabase {
+    self.database.clone()
+  }
+
+  /// Record an event, failures are logged but never fail the request
+  pub async fn record(
+    &self,
+    req: &Request<'_>,
+    action: AuditAction,
+    actor: Option<i32>,
+    target: Option<i32>,
+    details: Option<Value>,
+  ) {
+    let ip = Self::client_ip(req);
+    let details = details.map(|d| d.to_string());
+
+    if let Err(e) = self.database.lock().await.new_audit_event(action.as_str(), actor, target, ip, details).await {
+      error!("Failed to record audit event {}: {}", action.as_str(), e);
+    }
+  }
+
+  /// The client address as reported by the `trusted_proxies`. The headers
+  /// are only read when proxies are configured, without them anyone could
+  /// set the address
+  pub fn client_ip(req: &Request) -> Option<String> {
+    let proxies = &CONFIG.server.trusted_proxies;
+    if proxies.is_empty() {
+      return None;
+    }
+
+    let headers = req.get_headers();
+    if let Some(forwarded) = headers.get("x-forwarded-for") {
+      // Each proxy appends the address it got the request from, the client is
+      // the last one that is not a proxy of ours. Anything before it is
+      // whatever the client sent
+      let mut hops = forwarded.split(',').map(|h| h.trim().parse::<IpAddr>());
+      return match hops.rfind(|h| !h.as_ref().is_ok_and(|ip| proxies.contains(ip))) {
+        Some(Ok(ip)) => Some(ip.to_string()),
+        Some(Err(_)) | None => None,
+      };
+    }
+
+    headers.get("x-real-ip").and_then(|ip| ip.trim().parse::<IpAddr>().ok()).map(|ip| ip.to_string())
+  }
+}
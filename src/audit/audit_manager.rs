@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use archive_database::structs::{AuditFilter, Role};
+use async_trait::async_trait;
+use serde_json::json;
+use tokio::sync::Mutex;
+use webrs::{api::ApiMethod, request::Request, response::Response};
+
+use crate::{audit::audit_log::SharedAuditLog, user::user_manager::SharedUserManager};
+
+pub type SharedAuditManager = Arc<Mutex<AuditManager>>;
+
+const MAX_PER_PAGE: u64 = 200;
+
+pub struct AuditManager {
+  user_manager: SharedUserManager,
+  audit_log: SharedAuditLog,
+}
+
+impl AuditManager {
+  pub fn new(user_manager: SharedUserManager, audit_log: SharedAuditLog) -> SharedAuditManager {
+    Arc::new(Mutex::new(Self { user_manager, audit_log }))
+  }
+
+  /// List audit events, filtered by the `action`, `actor`, `target`, `since`
+  /// and `until` url params and paged with `page` and `per_page`
+  pub async fn handle_list_events<'s, 'r>(&'s self, req: Request<'r>) -> Option<Response<'r>> {
+    let params = req.get_url_params();
+    let filter = AuditFilter {
+      action: params.get("action").map(|a| a.to_string()),
+      actor_id: params.get("actor").and_then(|a| a.parse().ok()),
+      target_id: params.get("target").and_then(|t| t.parse().ok()),
+      since: params.get("since").and_then(|s| s.parse().ok()),
+      until: params.get("until").and_then(|u| u.parse().ok()),
+    };
+    let page: u64 = params.get("page").and_then(|p| p.parse().ok()).unwrap_or(0);
+    let per_page: u64 = params.get("per_page").and_then(|p| p.parse().ok()).unwrap_or(50).clamp(1, MAX_PER_PAGE);
+
+    let database = self.audit_log.get_database();
+    let res = database.lock().await.get_audit_events(&filter, page, per_page).await;
+    match res {
+      Ok((events, total)) => Some(
+        Response::from_json(200, json!({ "events": events, "total": total, "page": page, "per_page": per_page }))
+          .unwrap(),
+      ),
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+}
+
+#[async_trait]
+impl ApiMethod for AuditManager {
+  fn get_endpoint(&self) -> &str {
+    "/audit"
+  }
+
+  async fn handle_get<'s, 'r>(&'s mut self, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    {
+      let user_manager = self.user_manager.lock().await;
+      let id = match user_manager.validate_request(&req).await {
+        Ok(id) => id,
+        Err(_) => return Some(Response::basic(401, "Unauthorized")),
+      };
+      if let Err(e) = user_manager.require_role(id, Role::Admin) {
+        return Some(Response::from_json(403, e.to_json()).unwrap());
+      }
+    }
+
+    match req.get_endpoint().rsplit("audit/").next() {
+      Some("events") => self.handle_list_events(req).await,
+      _ => Some(Response::basic(404, "Not Found")),
+    }
+  }
+}
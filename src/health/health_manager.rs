@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use archive_database::database::SharedDatabase;
+use archive_storage::SharedStorage;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use webrs::{api::ApiMethod, request::Request, response::Response};
+
+use crate::user::oauth::OAuthParameters;
+
+pub type SharedHealthManager = Arc<Mutex<HealthManager>>;
+
+/// What a probe endpoint answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+  /// `/api/health`, the process is up and serving requests
+  Health,
+  /// `/api/ready`, everything needed to do work is there
+  Ready,
+}
+
+/// Probes for orchestrators and load balancers, they do not need a login
+pub struct HealthManager {
+  probe: Probe,
+  database: SharedDatabase,
+  storage: SharedStorage,
+}
+
+impl HealthManager {
+  pub fn new(probe: Probe, database: SharedDatabase, storage: SharedStorage) -> SharedHealthManager {
+    Arc::new(Mutex::new(Self { probe, database, storage }))
+  }
+
+  /// Check the database, the storage and the OAuth client secret
+  ///
+  /// Returns whether they are all fine and the result of every check
+  pub async fn check_ready(&self) -> (bool, Value) {
+    let database = match self.database.lock().await.ping().await {
+      Ok(latency) => json!({ "ok": true, "latency_ms": latency.as_millis() as u64 }),
+      Err(e) => json!({ "ok": false, "error": e.get_message() }),
+    };
+
+    let storage = match self.storage.check_writable().await {
+      Ok(_) => json!({ "ok": true, "backend": self.storage.name() }),
+      Err(e) => json!({ "ok": false, "backend": self.storage.name(), "error": e.to_string() }),
+    };
+
+    let oauth = match OAuthParameters::load() {
+      Ok(_) => json!({ "ok": true }),
+      Err(e) => json!({ "ok": false, "error": e }),
+    };
+
+    let ready = [&database, &storage, &oauth].iter().all(|c| c["ok"] == true);
+    (ready, json!({ "database": database, "storage": storage, "oauth": oauth }))
+  }
+}
+
+#[async_trait]
+impl ApiMethod for HealthManager {
+  fn get_endpoint(&self) -> &str {
+    match self.probe {
+      Probe::Health => "/health",
+      Probe::Ready => "/ready",
+    }
+  }
+
+  async fn handle_get<'s, 'r>(&'s mut self, _req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    match self.probe {
+      Probe::Health => Some(Response::from_json(200, json!({ "status": "ok" })).unwrap()),
+      Probe::Ready => {
+        let (ready, checks) = self.check_ready().await;
+        let status = if ready { "ready" } else { "degraded" };
+        Some(Response::from_json(if ready { 200 } else { 503 }, json!({ "status": status, "checks": checks })).unwrap())
+      }
+    }
+  }
+}
@@ -1,18 +1,86 @@
+mod audit;
+mod health;
+mod metrics;
 mod photos;
+mod schedule;
 mod user;
+mod verify;
 
 use std::{
-  env::{set_var, var},
+  env::{args, set_var, var},
   process::exit,
+  sync::Arc,
+  time::Duration,
 };
 
 use archive_config::CONFIG;
-use archive_database::database::PhotoArchiverDatabase;
-use log::{error, info};
-use photos::photo_manager::{self, PhotoManager};
+use archive_database::database::{PhotoArchiverDatabase, SharedDatabase};
+use audit::{audit_log::AuditLog, audit_manager::AuditManager};
+use gphotos_downloader::{bandwidth::BandwidthLimiter, http::HttpClient, quota::QuotaTracker, DownloaderPool};
+use health::health_manager::{HealthManager, Probe};
+use log::{error, info, warn};
+use metrics::metrics_manager::MetricsManager;
+use photos::{
+  layout::Layout,
+  media_store::MediaStore,
+  photo_manager::{self, PhotoManager},
+  sync::JobRunner,
+};
+use schedule::{schedule_manager::ScheduleManager, scheduler::Scheduler};
 use user::user_manager::UserManager;
+use verify::{
+  verifier::{SharedVerifier, Verifier, VerifyOptions},
+  verify_manager::VerifyManager,
+};
 use webrs::server::WebrsHttp;
 
+/// How long to wait between attempts to reach the database when it was not
+/// there at startup
+const DATABASE_RETRY: Duration = Duration::from_secs(10);
+
+/// How long downloaders in use by requests get to come back on shutdown
+const POOL_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keep trying to connect to the database until it works, the server reports
+/// not ready until then. Once it is there, verify runs the last run left
+/// behind are marked failed and interrupted sync jobs are resumed
+async fn reconnect(database: SharedDatabase, jobs: JobRunner, verifier: SharedVerifier) {
+  loop {
+    tokio::time::sleep(DATABASE_RETRY).await;
+    // Connect without the lock so requests keep failing fast meanwhile
+    if let Ok(client) = PhotoArchiverDatabase::connect(&CONFIG.database).await {
+      database.lock().await.set_connection(client);
+      info!("Database is back, leaving degraded mode");
+      verifier.fail_leftover_runs().await;
+      jobs.resume().await;
+      return;
+    }
+  }
+}
+
+/// Wait for ctrl-c or SIGTERM
+async fn shutdown_signal() {
+  #[cfg(unix)]
+  let terminate = async {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+      Ok(mut s) => {
+        s.recv().await;
+      }
+      Err(e) => {
+        error!("Failed to listen for SIGTERM: {}", e);
+        std::future::pending::<()>().await
+      }
+    }
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  tokio::select! {
+    _ = tokio::signal::ctrl_c() => (),
+    _ = terminate => (),
+  }
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
   if var("LOGLEVEL").is_err() {
@@ -32,18 +100,157 @@ async fn main() -> std::io::Result<()> {
   );
 
   let database = PhotoArchiverDatabase::new(CONFIG.database.clone());
-  let user_manager = UserManager::new(http_server.clone(), database.clone());
-  let photo_manager = PhotoManager::new(user_manager.clone());
+  let storage = archive_storage::from_config(&CONFIG.storage).unwrap_or_else(|e| {
+    error!("Failed to initialize storage: {}", e);
+    exit(1)
+  });
+  info!("Storing media with the {} backend", storage.name());
+
+  let http_client = HttpClient::new(&CONFIG.http).unwrap_or_else(|e| {
+    error!("Failed to set up the HTTP client: {}", e);
+    exit(1)
+  });
+
+  let audit_log = AuditLog::new(database.clone());
+  let user_manager = UserManager::new(http_server.clone(), database.clone(), audit_log.clone(), http_client.clone());
+  let layout = match CONFIG.layout.template.as_str() {
+    "" => None,
+    t => Some(Layout::new(t).unwrap_or_else(|e| {
+      error!("Failed to parse the layout template: {}", e);
+      exit(1)
+    })),
+  };
+
+  let bandwidth = BandwidthLimiter::from_config(&CONFIG.downloader.bandwidth).unwrap_or_else(|e| {
+    error!("Failed to parse the bandwidth config: {}", e);
+    exit(1)
+  });
+  let quota = QuotaTracker::new(&CONFIG.downloader.quota, CONFIG.downloader.pool_size);
+  let pool =
+    DownloaderPool::from_parts(CONFIG.downloader.pool_size, http_client.clone(), Arc::new(bandwidth), Arc::new(quota));
 
-  database.lock().await.init().await.unwrap_or_else(|e| {
-    error!("Failed to initialize database: {}", e);
+  let media_store = MediaStore::new(database.clone(), storage.clone(), layout);
+  let jobs = JobRunner::new(media_store.clone(), pool.clone(), http_client);
+  let photo_manager =
+    PhotoManager::new(user_manager.clone(), audit_log.clone(), media_store.clone(), pool.clone(), jobs.clone());
+  let scheduler = Scheduler::from_config(&CONFIG.scheduler, database.clone(), jobs.clone()).unwrap_or_else(|e| {
+    error!("Failed to parse the scheduler config: {}", e);
     exit(1)
   });
+  let schedule_manager =
+    ScheduleManager::new(user_manager.clone(), audit_log.clone(), database.clone(), scheduler.clone());
+  let verifier = Verifier::new(media_store.clone(), jobs.clone());
+  let verify_manager = VerifyManager::new(user_manager.clone(), audit_log.clone(), verifier.clone());
+  let audit_manager = AuditManager::new(user_manager.clone(), audit_log.clone());
+  let metrics_manager = MetricsManager::new(user_manager.clone(), database.clone(), pool.clone());
+  let health_manager = HealthManager::new(Probe::Health, database.clone(), storage.clone());
+  let ready_manager = HealthManager::new(Probe::Ready, database.clone(), storage.clone());
+
+  let database_res = database.lock().await.init().await;
+
+  if let Some("relayout") = args().nth(1).as_deref() {
+    if let Err(e) = database_res {
+      error!("Failed to initialize database: {}", e);
+      exit(1)
+    }
+
+    info!("Moving archived files to the current layout");
+    match media_store.relayout().await {
+      Ok(r) => info!(
+        "Re-layout done: {} moved, {} unchanged, {} removed, {} failed",
+        r.moved, r.unchanged, r.removed, r.failed
+      ),
+      Err(e) => {
+        error!("Re-layout failed: {}", e);
+        exit(1)
+      }
+    }
+    return Ok(());
+  }
+
+  if let Some("verify") = args().nth(1).as_deref() {
+    if let Err(e) = database_res {
+      error!("Failed to initialize database: {}", e);
+      exit(1)
+    }
+    let options = VerifyOptions::from_args(args().skip(2)).unwrap_or_else(|e| {
+      error!("{}", e);
+      exit(1)
+    });
+
+    let report = match verifier.run(None, options).await {
+      Ok(r) => r,
+      Err(e) => {
+        error!("Verify failed: {}", e);
+        exit(1)
+      }
+    };
+    for finding in &report.findings {
+      info!("{}", finding);
+    }
+    if report.repaired + report.unrepaired > report.findings.len() as u64 {
+      info!("Only the first {} of {} findings are listed", report.findings.len(), report.repaired + report.unrepaired);
+    }
+    info!(
+      "Verify done: {} items, {} blobs, {} files, {} problems, {} repaired, {} left",
+      report.items,
+      report.blobs,
+      report.files,
+      report.repaired + report.unrepaired,
+      report.repaired,
+      report.unrepaired
+    );
+    if report.unrepaired > 0 {
+      exit(1)
+    }
+    return Ok(());
+  }
+
+  if let Err(e) = user_manager.lock().await.init().await {
+    error!("Google logins will fail, the server reports not ready until this is fixed: {}", e);
+  }
+
+  match database_res {
+    Ok(_) => {
+      verifier.fail_leftover_runs().await;
+      jobs.resume().await
+    }
+    Err(e) => {
+      warn!("Starting in degraded mode, the server reports not ready until the database is reachable: {}", e);
+      tokio::spawn(reconnect(database.clone(), jobs.clone(), verifier.clone()));
+    }
+  }
+
+  if CONFIG.scheduler.enabled {
+    scheduler.start();
+  }
+  if CONFIG.metrics.enabled && CONFIG.metrics.token.is_none() {
+    warn!("Metrics are enabled without a token, anyone can read /api/metrics");
+  }
 
   http_server.register_method(user_manager.clone()).await;
   http_server.register_method(photo_manager.clone()).await;
+  http_server.register_method(schedule_manager.clone()).await;
+  http_server.register_method(verify_manager.clone()).await;
+  http_server.register_method(audit_manager.clone()).await;
+  http_server.register_method(metrics_manager.clone()).await;
+  http_server.register_method(health_manager.clone()).await;
+  http_server.register_method(ready_manager.clone()).await;
+
+  tokio::select! {
+    res = http_server.start() => {
+      if let Err(e) = res {
+        error!("HTTP server stopped: {}", e);
+      }
+    }
+    _ = shutdown_signal() => info!("Shutting down"),
+  }
+
+  jobs.shutdown(Duration::from_secs(CONFIG.server.shutdown_grace_secs)).await;
+  if tokio::time::timeout(POOL_DRAIN_TIMEOUT, pool.shutdown()).await.is_err() {
+    warn!("Some downloaders were still in use when shutting down");
+  }
+  database.lock().await.close().await;
 
-  // let http_server_clone = http_server.clone();
-  let _ = http_server.start().await;
   Ok(())
 }
@@ -0,0 +1,148 @@
+use std::{fmt::Write, sync::Arc};
+
+use archive_config::CONFIG;
+use archive_database::database::SharedDatabase;
+use async_trait::async_trait;
+use gphotos_downloader::{stats::HistogramSnapshot, DownloaderPool};
+use tokio::sync::Mutex;
+use webrs::{api::ApiMethod, request::Request, response::Response};
+
+use crate::user::user_manager::{SharedUserManager, UserManager};
+
+pub type SharedMetricsManager = Arc<Mutex<MetricsManager>>;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Builds a page in the prometheus text format
+#[derive(Default)]
+struct Exposition {
+  text: String,
+}
+
+impl Exposition {
+  fn header(&mut self, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(self.text, "# HELP photo_archiver_{} {}", name, help);
+    let _ = writeln!(self.text, "# TYPE photo_archiver_{} {}", name, kind);
+  }
+
+  fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+    let _ = write!(self.text, "photo_archiver_{}", name);
+    if !labels.is_empty() {
+      let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+      let _ = write!(self.text, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(self.text, " {}", value);
+  }
+
+  fn gauge(&mut self, name: &str, help: &str, value: f64) {
+    self.header(name, "gauge", help);
+    self.sample(name, &[], value);
+  }
+
+  fn counter(&mut self, name: &str, help: &str, value: f64) {
+    self.header(name, "counter", help);
+    self.sample(name, &[], value);
+  }
+
+  fn histogram(&mut self, name: &str, help: &str, histogram: &HistogramSnapshot) {
+    self.header(name, "histogram", help);
+    let bucket = format!("{}_bucket", name);
+    for (bound, count) in &histogram.buckets {
+      self.sample(&bucket, &[("le", &bound.to_string())], *count as f64);
+    }
+    self.sample(&bucket, &[("le", "+Inf")], histogram.count as f64);
+    self.sample(&format!("{}_sum", name), &[], histogram.sum);
+    self.sample(&format!("{}_count", name), &[], histogram.count as f64);
+  }
+}
+
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub struct MetricsManager {
+  user_manager: SharedUserManager,
+  database: SharedDatabase,
+  pool: Arc<DownloaderPool>,
+}
+
+impl MetricsManager {
+  pub fn new(
+    user_manager: SharedUserManager,
+    database: SharedDatabase,
+    pool: Arc<DownloaderPool>,
+  ) -> SharedMetricsManager {
+    Arc::new(Mutex::new(Self { user_manager, database, pool }))
+  }
+
+  /// Render every metric in the prometheus text format
+  pub async fn render(&self) -> String {
+    let mut page = Exposition::default();
+
+    let size = self.pool.get_size();
+    let in_use = size - self.pool.get_available();
+    page.gauge("pool_size", "Downloaders in the pool", size as f64);
+    page.gauge("pool_in_use", "Downloaders handed out right now", in_use as f64);
+    page.gauge(
+      "pool_utilization_ratio",
+      "Share of the downloaders in use",
+      if size == 0 { 0.0 } else { in_use as f64 / size as f64 },
+    );
+    let queued: usize = self.pool.get_queue_depths().values().sum();
+    page.gauge("pool_queue_length", "Acquires waiting for a downloader", queued as f64);
+
+    let stats = self.pool.get_stats().snapshot();
+    page.histogram("pool_wait_seconds", "Time spent waiting for a downloader", &stats.wait);
+    page.counter("downloaded_bytes_total", "Bytes of media downloaded from google", stats.bytes as f64);
+    page.counter("items_archived_total", "Media items archived, rate() gives items per second", stats.items as f64);
+
+    page.header("api_responses_total", "counter", "Google API responses by call and status, error when none came");
+    for (call, status, count) in &stats.responses {
+      page.sample("api_responses_total", &[("call", call), ("status", status)], *count as f64);
+    }
+
+    let quota = self.pool.get_quota_status();
+    page.gauge("api_quota_used", "API requests counted against today's budget", quota.used as f64);
+    page.gauge("api_quota_budget", "Daily API request budget, 0 when there is none", quota.budget as f64);
+    page.gauge("api_concurrency", "API requests allowed at the same time", quota.concurrency as f64);
+
+    let sessions = {
+      let user_manager = self.user_manager.lock().await;
+      user_manager.get_active_users().iter().filter(|u| u.get_session_token().is_some()).count()
+    };
+    page.gauge("active_sessions", "Users logged in right now", sessions as f64);
+
+    let (pings, latency) = self.database.lock().await.get_latency();
+    page.header("database_ping_seconds", "summary", "Round trips to the database made before every query");
+    page.sample("database_ping_seconds_sum", &[], latency.as_secs_f64());
+    page.sample("database_ping_seconds_count", &[], pings as f64);
+
+    page.text
+  }
+}
+
+#[async_trait]
+impl ApiMethod for MetricsManager {
+  fn get_endpoint(&self) -> &str {
+    "/metrics"
+  }
+
+  async fn handle_get<'s, 'r>(&'s mut self, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    if !CONFIG.metrics.enabled {
+      return Some(Response::basic(404, "Not Found"));
+    }
+
+    if let Some(token) = &CONFIG.metrics.token {
+      if UserManager::get_bearer_token(&req).ok().as_ref() != Some(token) {
+        return Some(Response::basic(401, "Unauthorized"));
+      }
+    }
+
+    let mut res = Response::basic(200, &self.render().await);
+    res.add_header("content-type".to_string(), CONTENT_TYPE);
+    Some(res)
+  }
+}
@@ -0,0 +1,333 @@
+use std::fmt;
+
+use archive_database::entities::media_items;
+use chrono::{DateTime, Datelike, Utc};
+
+/// Longest a single path component can get, most filesystems allow 255 bytes
+const MAX_COMPONENT_LEN: usize = 200;
+
+const VARIABLES: &[&str] =
+  &["user", "user_id", "account", "album", "year", "month", "day", "date", "id", "filename", "name", "ext", "hash"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutError {
+  UnknownVariable(String),
+  InvalidTemplate(String),
+}
+
+impl fmt::Display for LayoutError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::UnknownVariable(v) => write!(f, "Unknown layout variable {{{}}}", v),
+      Self::InvalidTemplate(e) => write!(f, "Invalid layout template: {}", e),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+  Literal(String),
+  Variable(String),
+}
+
+/// Who a media item belongs to, used to fill in `{user}`, `{user_id}` and
+/// `{account}`
+#[derive(Debug, Clone)]
+pub struct LayoutOwner {
+  pub user_id: i32,
+  pub username: String,
+  pub account: Option<String>,
+}
+
+/// A parsed path template like `{user}/{year}/{month}/{filename}`
+#[derive(Debug, Clone)]
+pub struct Layout {
+  tokens: Vec<Token>,
+}
+
+impl Layout {
+  pub fn new(template: &str) -> Result<Self, LayoutError> {
+    if template.starts_with('/') {
+      return Err(LayoutError::InvalidTemplate("Template must be relative".to_string()));
+    }
+
+    let mut tokens = Vec::new();
+    let mut rest = template;
+
+    while !rest.is_empty() {
+      match rest.find('{') {
+        Some(0) => {
+          let end = rest.find('}').ok_or(LayoutError::InvalidTemplate("Unclosed {".to_string()))?;
+          let name = &rest[1..end];
+          if !VARIABLES.contains(&name) {
+            return Err(LayoutError::UnknownVariable(name.to_string()));
+          }
+          tokens.push(Token::Variable(name.to_string()));
+          rest = &rest[end + 1..];
+        }
+        Some(i) => {
+          tokens.push(Token::Literal(rest[..i].to_string()));
+          rest = &rest[i..];
+        }
+        None => {
+          tokens.push(Token::Literal(rest.to_string()));
+          rest = "";
+        }
+      }
+    }
+
+    for token in &tokens {
+      if let Token::Literal(l) = token {
+        if l.contains('}') {
+          return Err(LayoutError::InvalidTemplate("Unopened }".to_string()));
+        }
+        if l.split('/').any(|c| c == "..") {
+          return Err(LayoutError::InvalidTemplate("Template can not contain ..".to_string()));
+        }
+      }
+    }
+
+    if !tokens
+      .iter()
+      .any(|t| matches!(t, Token::Variable(v) if v == "id" || v == "filename" || v == "name" || v == "hash"))
+    {
+      return Err(LayoutError::InvalidTemplate("Template needs {id}, {filename}, {name} or {hash}".to_string()));
+    }
+
+    Ok(Self { tokens })
+  }
+
+  /// Does the template use the variable `name`
+  pub fn uses(&self, name: &str) -> bool {
+    self.tokens.iter().any(|t| matches!(t, Token::Variable(v) if v == name))
+  }
+
+  /// Build the path of an item, `attempt` is added to the filename to get
+  /// around collisions (`IMG_1.jpg`, `IMG_1_1.jpg`, `IMG_1_2.jpg`, ...)
+  pub fn render(&self, owner: &LayoutOwner, album: Option<&str>, item: &media_items::Model, attempt: u32) -> String {
+    let created =
+      item.creation_time.as_deref().and_then(|t| DateTime::parse_from_rfc3339(t).ok()).map(|t| t.with_timezone(&Utc));
+    let (stem, ext) = split_filename(&item.filename, &item.mime_type);
+
+    let value = |name: &str| -> String {
+      match name {
+        "user" => owner.username.clone(),
+        "user_id" => owner.user_id.to_string(),
+        "account" => owner.account.clone().unwrap_or("unlinked".to_string()),
+        "album" => album.unwrap_or("Unsorted").to_string(),
+        "year" => created.map(|c| format!("{:04}", c.year())).unwrap_or("unknown".to_string()),
+        "month" => created.map(|c| format!("{:02}", c.month())).unwrap_or("unknown".to_string()),
+        "day" => created.map(|c| format!("{:02}", c.day())).unwrap_or("unknown".to_string()),
+        "date" => created.map(|c| c.format("%Y-%m-%d").to_string()).unwrap_or("unknown".to_string()),
+        "id" => item.google_id.clone(),
+        "filename" => item.filename.clone(),
+        "name" => stem.clone(),
+        "ext" => ext.clone(),
+        "hash" => item.blob_hash.clone(),
+        _ => String::new(),
+      }
+    };
+
+    let path: String = self
+      .tokens
+      .iter()
+      .map(|t| match t {
+        Token::Literal(l) => l.clone(),
+        Token::Variable(v) => sanitize(&value(v)),
+      })
+      .collect();
+
+    let mut components: Vec<String> =
+      path.split('/').filter(|c| !c.is_empty()).map(|c| truncate(c.trim_end_matches(['.', ' ']))).collect();
+    for c in components.iter_mut() {
+      if c.is_empty() {
+        *c = "_".to_string();
+      }
+    }
+
+    if attempt > 0 {
+      if let Some(last) = components.last_mut() {
+        *last = match last.rsplit_once('.') {
+          Some((s, e)) if !s.is_empty() => format!("{}_{}.{}", s, attempt, e),
+          _ => format!("{}_{}", last, attempt),
+        };
+      }
+    }
+
+    components.join("/")
+  }
+}
+
+/// Replace characters that are not allowed (or are annoying) in file names on
+/// common filesystems and SMB shares
+fn sanitize(value: &str) -> String {
+  let value: String = value
+    .chars()
+    .map(|c| match c {
+      '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+      c if c.is_control() => '_',
+      c => c,
+    })
+    .collect();
+
+  match value.trim() {
+    "" | "." | ".." => "_".to_string(),
+    v => v.trim_start_matches('.').to_string(),
+  }
+}
+
+fn truncate(component: &str) -> String {
+  if component.len() <= MAX_COMPONENT_LEN {
+    return component.to_string();
+  }
+
+  // Keep the extension when cutting a long filename
+  let (stem, ext) = match component.rsplit_once('.') {
+    Some((s, e)) if e.len() < 16 => (s, format!(".{}", e)),
+    _ => (component, String::new()),
+  };
+
+  let mut end = MAX_COMPONENT_LEN - ext.len();
+  while !stem.is_char_boundary(end) {
+    end -= 1;
+  }
+
+  format!("{}{}", &stem[..end], ext)
+}
+
+/// Split a filename into its stem and extension, guessing the extension from
+/// the mime type when the filename has none
+fn split_filename(filename: &str, mime_type: &str) -> (String, String) {
+  match filename.rsplit_once('.') {
+    Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => (stem.to_string(), ext.to_string()),
+    _ => {
+      let ext = match mime_type.rsplit('/').next().unwrap_or_default() {
+        "jpeg" => "jpg",
+        "quicktime" => "mov",
+        "x-msvideo" => "avi",
+        "" => "bin",
+        e => e,
+      };
+      (filename.to_string(), ext.to_string())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn owner() -> LayoutOwner {
+    LayoutOwner { user_id: 7, username: "alice".to_string(), account: Some("alice@example.com".to_string()) }
+  }
+
+  fn item(filename: &str, mime_type: &str, creation_time: Option<&str>) -> media_items::Model {
+    media_items::Model {
+      id: 1,
+      user_id: 7,
+      google_id: "AF1Qip".to_string(),
+      filename: filename.to_string(),
+      mime_type: mime_type.to_string(),
+      creation_time: creation_time.map(|t| t.to_string()),
+      blob_hash: "ab12".to_string(),
+      size: 0,
+      stored_at: 0,
+      path: None,
+      path_hash: None,
+    }
+  }
+
+  fn render(template: &str, item: &media_items::Model, attempt: u32) -> String {
+    Layout::new(template).unwrap().render(&owner(), None, item, attempt)
+  }
+
+  #[test]
+  fn unknown_variable() {
+    assert_eq!(Layout::new("{user}/{colour}/{filename}").unwrap_err(), LayoutError::UnknownVariable("colour".into()));
+  }
+
+  #[test]
+  fn needs_a_unique_variable() {
+    assert!(matches!(Layout::new("{user}/{year}/{month}"), Err(LayoutError::InvalidTemplate(_))));
+    for template in ["{id}", "{filename}", "{name}.{ext}", "{hash}"] {
+      assert!(Layout::new(template).is_ok(), "{}", template);
+    }
+  }
+
+  #[test]
+  fn invalid_templates() {
+    for template in ["/{filename}", "{user}/../{filename}", "{user}/{filename", "{user}}/{filename}"] {
+      assert!(matches!(Layout::new(template), Err(LayoutError::InvalidTemplate(_))), "{}", template);
+    }
+  }
+
+  #[test]
+  fn renders_variables() {
+    let item = item("IMG_1.jpg", "image/jpeg", Some("2023-05-07T23:30:00Z"));
+    assert_eq!(render("{user}/{year}/{month}/{day}/{filename}", &item, 0), "alice/2023/05/07/IMG_1.jpg");
+    assert_eq!(render("{user_id}-{account}/{date}_{id}", &item, 0), "7-alice@example.com/2023-05-07_AF1Qip");
+    assert_eq!(render("{album}/{name}.{ext}", &item, 0), "Unsorted/IMG_1.jpg");
+  }
+
+  #[test]
+  fn missing_creation_time() {
+    let item = item("IMG_1.jpg", "image/jpeg", None);
+    assert_eq!(render("{year}/{date}/{filename}", &item, 0), "unknown/unknown/IMG_1.jpg");
+  }
+
+  #[test]
+  fn sanitizes_values() {
+    let photo = item("a/b:c?.jpg", "image/jpeg", None);
+    assert_eq!(render("{user}/{filename}", &photo, 0), "alice/a_b_c_.jpg");
+
+    let album = Layout::new("{album}/{filename}").unwrap().render(&owner(), Some(".."), &photo, 0);
+    assert_eq!(album, "_/a_b_c_.jpg");
+
+    let hidden = item(".hidden", "text/plain", None);
+    assert_eq!(render("{filename}", &hidden, 0), "hidden");
+  }
+
+  #[test]
+  fn truncates_long_components() {
+    let long = item(&format!("{}.jpg", "a".repeat(300)), "image/jpeg", None);
+    let path = render("{user}/{filename}", &long, 0);
+    let name = path.strip_prefix("alice/").unwrap();
+    assert_eq!(name.len(), MAX_COMPONENT_LEN);
+    assert!(name.ends_with(".jpg"));
+
+    // Never cuts a character in half
+    let wide = item(&format!("{}.jpg", "é".repeat(150)), "image/jpeg", None);
+    let name = render("{filename}", &wide, 0);
+    assert!(name.len() <= MAX_COMPONENT_LEN);
+    assert!(name.ends_with(".jpg"));
+  }
+
+  #[test]
+  fn collision_suffix() {
+    let photo = item("IMG_1.jpg", "image/jpeg", None);
+    assert_eq!(render("{user}/{filename}", &photo, 1), "alice/IMG_1_1.jpg");
+    assert_eq!(render("{user}/{filename}", &photo, 2), "alice/IMG_1_2.jpg");
+
+    let bare = item("README", "text/plain", None);
+    assert_eq!(render("{filename}", &bare, 3), "README_3");
+  }
+
+  #[test]
+  fn split_filenames() {
+    assert_eq!(split_filename("IMG.JPG", "image/jpeg"), ("IMG".to_string(), "JPG".to_string()));
+    assert_eq!(split_filename("archive.tar.gz", ""), ("archive.tar".to_string(), "gz".to_string()));
+    // No extension, guessed from the mime type
+    assert_eq!(split_filename("IMG", "image/jpeg"), ("IMG".to_string(), "jpg".to_string()));
+    assert_eq!(split_filename("clip", "video/quicktime"), ("clip".to_string(), "mov".to_string()));
+    assert_eq!(split_filename("clip", "video/mp4"), ("clip".to_string(), "mp4".to_string()));
+    assert_eq!(split_filename(".profile", ""), (".profile".to_string(), "bin".to_string()));
+    assert_eq!(split_filename("trailing.", "image/png"), ("trailing.".to_string(), "png".to_string()));
+  }
+
+  #[test]
+  fn name_and_ext_without_extension() {
+    let photo = item("IMG_1", "image/jpeg", None);
+    assert_eq!(render("{name}.{ext}", &photo, 0), "IMG_1.jpg");
+    assert_eq!(render("{name}.{ext}", &photo, 1), "IMG_1_1.jpg");
+  }
+}
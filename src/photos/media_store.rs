@@ -0,0 +1,348 @@
+use std::{collections::HashMap, fmt, path::PathBuf, sync::Arc, time::Duration};
+
+use archive_config::CONFIG;
+use archive_database::{database::SharedDatabase, entities::media_items, structs::MediaItemInfo};
+use archive_storage::{error::StorageError, SharedStorage};
+use log::{debug, error, info};
+use serde::Serialize;
+use tokio::{
+  fs::{self, File},
+  io,
+  sync::RwLock,
+  time::interval,
+};
+
+use crate::photos::{
+  layout::{Layout, LayoutOwner},
+  staging::{StagedFile, Stager},
+};
+
+pub type SharedMediaStore = Arc<MediaStore>;
+
+/// How many suffixes are tried before giving up on finding a free layout path
+const MAX_LAYOUT_ATTEMPTS: u32 = 1000;
+
+#[derive(Debug)]
+pub enum MediaStoreError {
+  StorageError(StorageError),
+  DatabaseError(String),
+  IoError(String),
+  LayoutError(String),
+}
+
+impl fmt::Display for MediaStoreError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::StorageError(e) => write!(f, "Storage error: {}", e),
+      Self::DatabaseError(e) => write!(f, "Database error: {}", e),
+      Self::IoError(e) => write!(f, "IO error: {}", e),
+      Self::LayoutError(e) => write!(f, "Layout error: {}", e),
+    }
+  }
+}
+
+impl From<io::Error> for MediaStoreError {
+  fn from(value: io::Error) -> Self {
+    Self::IoError(value.to_string())
+  }
+}
+
+impl From<StorageError> for MediaStoreError {
+  fn from(value: StorageError) -> Self {
+    Self::StorageError(value)
+  }
+}
+
+/// What a re-layout did
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RelayoutReport {
+  pub moved: u64,
+  pub unchanged: u64,
+  pub removed: u64,
+  pub failed: u64,
+}
+
+/// Stores media content addressed by its SHA-256 hash, so every distinct file
+/// is only stored once no matter how many media items point at it
+pub struct MediaStore {
+  database: SharedDatabase,
+  storage: SharedStorage,
+  /// Browsable copies of the items are placed by this layout, None when the
+  /// layout is disabled
+  layout: Option<Layout>,
+  /// Held for reading while blobs are added and for writing while
+  /// unreferenced blobs are removed, so a blob can not be collected between
+  /// deciding to reuse it and referencing it
+  gc_lock: RwLock<()>,
+}
+
+impl MediaStore {
+  pub fn new(database: SharedDatabase, storage: SharedStorage, layout: Option<Layout>) -> SharedMediaStore {
+    let store = Arc::new(Self { database, storage, layout, gc_lock: RwLock::new(()) });
+
+    let gc = Arc::clone(&store);
+    tokio::spawn(async move {
+      let mut interval = interval(Duration::from_secs(60 * 60));
+      loop {
+        interval.tick().await;
+        match gc.collect_garbage().await {
+          Ok(0) => (),
+          Ok(n) => info!("Removed {} unreferenced blobs", n),
+          Err(e) => error!("Failed to remove unreferenced blobs: {}", e),
+        }
+      }
+    });
+
+    store
+  }
+
+  #[inline]
+  pub fn get_database(&self) -> SharedDatabase {
+    self.database.clone()
+  }
+
+  #[inline]
+  pub fn get_storage(&self) -> SharedStorage {
+    self.storage.clone()
+  }
+
+  /// Prefix of the keys of the browsable copies, None when the layout is
+  /// disabled
+  pub fn layout_prefix(&self) -> Option<String> {
+    self.layout.as_ref()?;
+    match CONFIG.layout.root.trim_matches('/') {
+      "" => Some(String::new()),
+      root => Some(format!("{}/", root)),
+    }
+  }
+
+  /// Storage key of the blob with `hash`
+  pub fn blob_key(hash: &str) -> String {
+    format!("blobs/{}/{}/{}", &hash[..2], &hash[2..4], hash)
+  }
+
+  /// Start or resume staging a download of a media item for a user
+  pub async fn stager(&self, user_id: i32, google_id: &str) -> io::Result<Stager> {
+    let dir = PathBuf::from(&CONFIG.downloader.staging_path).join(user_id.to_string());
+    fs::create_dir_all(&dir).await?;
+
+    Stager::open(dir.join(format!("{}.part", google_id))).await
+  }
+
+  /// Store a staged download as a media item of a user, the bytes are only
+  /// written if no other item has the same content
+  ///
+  /// Returns true if the content was already stored or a MediaStoreError if
+  /// storing failed
+  pub async fn commit(
+    &self,
+    owner: &LayoutOwner,
+    item: &MediaItemInfo,
+    staged: StagedFile,
+  ) -> Result<bool, MediaStoreError> {
+    let _guard = self.gc_lock.read().await;
+
+    let blob = self.database.lock().await.get_blob(&staged.hash).await;
+    let deduplicated = blob.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))?.is_some();
+
+    if deduplicated {
+      debug!("{} is already stored as {}, skipping write", item.google_id, staged.hash);
+    } else {
+      let file = File::open(&staged.path).await?;
+      self.storage.put(&Self::blob_key(&staged.hash), Box::new(file)).await?;
+    }
+
+    let res = self.database.lock().await.link_media_item(owner.user_id, item, &staged.hash, staged.size as i64).await;
+    let model = res.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))?;
+
+    if let Err(e) = fs::remove_file(&staged.path).await {
+      error!("Failed to remove staged file {}: {}", staged.path.display(), e);
+    }
+
+    // The item is safely stored at this point, a re-layout can place it later
+    if let Err(e) = self.place(owner, &model).await {
+      error!("Failed to place {} in the layout: {}", model.google_id, e);
+    }
+
+    Ok(deduplicated)
+  }
+
+  /// Write a staged download back to the blob with `hash`, for when the stored
+  /// copy of the blob is lost or damaged. Nothing is written if the download
+  /// has different content
+  ///
+  /// Returns true if the blob was written or a MediaStoreError if storing
+  /// failed
+  pub async fn restore(&self, hash: &str, staged: &StagedFile) -> Result<bool, MediaStoreError> {
+    if staged.hash != hash {
+      return Ok(false);
+    }
+
+    let _guard = self.gc_lock.read().await;
+    let file = File::open(&staged.path).await?;
+    self.storage.put(&Self::blob_key(hash), Box::new(file)).await?;
+
+    if let Err(e) = fs::remove_file(&staged.path).await {
+      error!("Failed to remove staged file {}: {}", staged.path.display(), e);
+    }
+
+    Ok(true)
+  }
+
+  /// Get who owns the library of every user, for placing their items
+  pub async fn get_owners(&self) -> Result<HashMap<i32, LayoutOwner>, MediaStoreError> {
+    let mut owners = HashMap::new();
+    let users = self.database.lock().await.get_all_users().await;
+    for user in users.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))? {
+      let account = self.database.lock().await.get_google_account(user.get_id()).await;
+      let account = account.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))?;
+      owners.insert(
+        user.get_id(),
+        LayoutOwner {
+          user_id: user.get_id(),
+          username: user.get_username(),
+          account: account.map(|a| a.get_name().to_string()),
+        },
+      );
+    }
+
+    Ok(owners)
+  }
+
+  /// Put a browsable copy of an item at its layout path, moving the copy if
+  /// the layout changed since it was placed
+  ///
+  /// Returns true if the copy was written or a MediaStoreError if placing it
+  /// failed
+  pub async fn place(&self, owner: &LayoutOwner, item: &media_items::Model) -> Result<bool, MediaStoreError> {
+    let layout = match &self.layout {
+      Some(l) => l,
+      None => return Ok(false),
+    };
+    let blob_key = Self::blob_key(&item.blob_hash);
+
+    let album = match layout.uses("album") {
+      true => {
+        let album = self.database.lock().await.get_item_album(owner.user_id, &item.google_id).await;
+        album.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))?.map(|a| a.title)
+      }
+      false => None,
+    };
+
+    for attempt in 0..MAX_LAYOUT_ATTEMPTS {
+      let path = layout.render(owner, album.as_deref(), item, attempt);
+      let key = match CONFIG.layout.root.trim_matches('/') {
+        "" => path,
+        root => format!("{}/{}", root, path),
+      };
+
+      if item.path.as_deref() == Some(key.as_str()) {
+        // The item is linked to another blob when google served different
+        // content on a later download
+        let current = item.path_hash.as_deref() == Some(item.blob_hash.as_str());
+        if current && self.storage.stat(&key).await?.is_some() {
+          return Ok(false);
+        }
+        self.storage.copy(&blob_key, &key).await?;
+        if !current {
+          let res =
+            self.database.lock().await.set_media_item_path(item.id, Some(key), Some(item.blob_hash.clone())).await;
+          res.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))?;
+        }
+        return Ok(true);
+      }
+
+      // Taken by another item or by a file that is not part of the archive
+      if self.storage.stat(&key).await?.is_some() {
+        continue;
+      }
+
+      let claimed =
+        self.database.lock().await.set_media_item_path(item.id, Some(key.clone()), Some(item.blob_hash.clone())).await;
+      if !claimed.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))? {
+        continue;
+      }
+
+      self.storage.copy(&blob_key, &key).await?;
+      if let Some(old) = &item.path {
+        self.storage.delete(old).await?;
+      }
+
+      debug!("Placed {} at {}", item.google_id, key);
+      return Ok(true);
+    }
+
+    Err(MediaStoreError::LayoutError(format!("No free path for {}", item.google_id)))
+  }
+
+  /// Move every archived item to the path the current layout gives it, or
+  /// remove the browsable copies when the layout is disabled
+  pub async fn relayout(&self) -> Result<RelayoutReport, MediaStoreError> {
+    let owners = self.get_owners().await?;
+
+    let mut report = RelayoutReport::default();
+    let mut after_id = 0;
+    loop {
+      let items = self.database.lock().await.get_media_items_after(after_id, 500).await;
+      let items = items.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))?;
+      let last = match items.last() {
+        Some(i) => i.id,
+        None => break,
+      };
+
+      for item in items {
+        if self.layout.is_none() {
+          if let Some(path) = &item.path {
+            self.storage.delete(path).await?;
+            let res = self.database.lock().await.set_media_item_path(item.id, None, None).await;
+            res.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))?;
+            report.removed += 1;
+          }
+          continue;
+        }
+
+        let owner = match owners.get(&item.user_id) {
+          Some(o) => o,
+          None => continue,
+        };
+
+        match self.place(owner, &item).await {
+          Ok(true) => report.moved += 1,
+          Ok(false) => report.unchanged += 1,
+          Err(e) => {
+            error!("Failed to move {}: {}", item.google_id, e);
+            report.failed += 1;
+          }
+        }
+      }
+
+      info!("Re-layout progress: {} moved, {} unchanged, {} failed", report.moved, report.unchanged, report.failed);
+      after_id = last;
+    }
+
+    Ok(report)
+  }
+
+  /// Remove every blob no media item references anymore
+  ///
+  /// Returns the number of removed blobs
+  pub async fn collect_garbage(&self) -> Result<u64, MediaStoreError> {
+    let _guard = self.gc_lock.write().await;
+
+    let blobs = self.database.lock().await.get_unreferenced_blobs().await;
+    let blobs = blobs.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))?;
+
+    let mut removed = 0;
+    for blob in blobs {
+      let deleted = self.database.lock().await.delete_blob(&blob.hash).await;
+      if !deleted.map_err(|e| MediaStoreError::DatabaseError(e.get_message()))? {
+        continue;
+      }
+
+      self.storage.delete(&Self::blob_key(&blob.hash)).await?;
+      removed += 1;
+    }
+
+    Ok(removed)
+  }
+}
@@ -1 +1,5 @@
+pub mod layout;
+pub mod media_store;
 pub mod photo_manager;
+pub mod staging;
+pub mod sync;
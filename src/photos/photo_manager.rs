@@ -1,25 +1,268 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use archive_config::CONFIG;
+use archive_database::structs::{DedupStats, Role};
 use async_trait::async_trait;
-use gphotos_downloader::DownloaderPool;
+use gphotos_downloader::{
+  error::DownloaderError,
+  structs::{DownloaderGuard, Filters},
+  DownloaderPool, Priority,
+};
 use log::{error, trace};
-use serde_json::json;
+use serde_json::{json, Value};
 use tokio::sync::Mutex;
 use webrs::{api::ApiMethod, request::Request, response::Response};
 
-use crate::user::user_manager::{self, SharedUserManager};
+use crate::{
+  audit::audit_log::{AuditAction, SharedAuditLog},
+  photos::{
+    layout::LayoutOwner,
+    media_store::SharedMediaStore,
+    sync::{EnqueueError, JobRunner},
+  },
+  user::user_manager::{self, SharedUserManager},
+};
 
 pub type SharedPhotoManager = Arc<Mutex<PhotoManager>>;
 
+/// How long requests wait for a downloader before giving up, every photos
+/// endpoint is blocked meanwhile
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct PhotoManager {
   user_manager: SharedUserManager,
+  audit_log: SharedAuditLog,
+  media_store: SharedMediaStore,
   pool: Arc<DownloaderPool>,
+  jobs: JobRunner,
 }
 
 impl PhotoManager {
-  pub fn new(user_manager: SharedUserManager) -> SharedPhotoManager {
-    Arc::new(Mutex::new(Self { user_manager, pool: DownloaderPool::new(CONFIG.downloader.pool_size) }))
+  pub fn new(
+    user_manager: SharedUserManager,
+    audit_log: SharedAuditLog,
+    media_store: SharedMediaStore,
+    pool: Arc<DownloaderPool>,
+    jobs: JobRunner,
+  ) -> SharedPhotoManager {
+    Arc::new(Mutex::new(Self { user_manager, audit_log, media_store, pool, jobs }))
+  }
+
+  /// Get a downloader for a request of user `id`
+  ///
+  /// Returns the response to send instead if none frees up in time or the
+  /// pool is shut down
+  async fn downloader<'r>(&self, id: i32) -> Result<DownloaderGuard, Response<'r>> {
+    self.pool.clone().acquire_timeout(id, Priority::Interactive, ACQUIRE_TIMEOUT).await.map_err(|e| {
+      let message = match e {
+        DownloaderError::ShutDown => "The server is shutting down".to_string(),
+        DownloaderError::Timeout => "All downloaders are busy, try again later".to_string(),
+        e => format!("{:?}", e),
+      };
+      Response::from_json(503, json!({ "error": message })).unwrap()
+    })
+  }
+
+  /// Get who owns the library of a user and the token to access it
+  ///
+  /// Returns the response to send instead if the user can not access their
+  /// library
+  async fn library_access<'r>(&self, id: i32) -> Result<(LayoutOwner, String), Response<'r>> {
+    let user_manager = self.user_manager.lock().await;
+    let user = user_manager.get_active_users().get(&id).unwrap();
+
+    let token = match user.get_guser() {
+      Some(g) if !g.has_photos_access() =>
+        return Err(
+          Response::from_json(403, json!({ "error": "Google account was linked without photo library access" }))
+            .unwrap(),
+        ),
+      Some(g) => g.get_auth_token().to_string(),
+      None => return Err(Response::from_json(401, json!({ "error": "User is not logged into google" })).unwrap()),
+    };
+
+    let owner = LayoutOwner {
+      user_id: id,
+      username: user.get_username(),
+      account: user.get_guser().map(|g| g.get_name().to_string()),
+    };
+    Ok((owner, token))
+  }
+
+  /// Read the optional `filters` of a request body
+  pub(crate) fn parse_filters<'r>(req: &Request<'r>) -> Result<Option<Filters>, Response<'r>> {
+    let json: Value = serde_json::from_slice(&req.get_data()).unwrap_or(json!({}));
+    if json["filters"].is_null() {
+      return Ok(None);
+    }
+
+    let filters: Filters = serde_json::from_value(json["filters"].clone())
+      .map_err(|e| Response::from_json(400, json!({ "error": format!("Invalid filters: {}", e) })).unwrap())?;
+    if let Err(DownloaderError::InvalidFilter(e)) = filters.validate() {
+      return Err(Response::from_json(400, json!({ "error": e })).unwrap());
+    }
+
+    Ok(Some(filters))
+  }
+
+  /// Start archiving the google photos library of a user in the background,
+  /// only the items matching the `filters` of the body if there are any
+  pub async fn handle_start_sync<'s, 'r>(&'s self, id: i32, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let (owner, token) = match self.library_access(id).await {
+      Ok(a) => a,
+      Err(res) => return Some(res),
+    };
+    let filters = match Self::parse_filters(&req) {
+      Ok(f) => f,
+      Err(res) => return Some(res),
+    };
+
+    let progress = match self.jobs.enqueue(owner, token, filters).await {
+      Ok(p) => p,
+      Err(e) => {
+        let code = match e {
+          EnqueueError::ShuttingDown => 503,
+          EnqueueError::AlreadyRunning => 409,
+          EnqueueError::DatabaseError(_) => 500,
+        };
+        return Some(Response::from_json(code, json!({ "error": e.to_string() })).unwrap());
+      }
+    };
+    self.audit_log.record(&req, AuditAction::SyncStarted, Some(id), Some(id), None).await;
+
+    Some(Response::from_json(202, json!(progress)).unwrap())
+  }
+
+  /// Search the google photos library of a user, the body holds the
+  /// `filters` and the `pageToken` of the page to get
+  pub async fn handle_search<'s, 'r>(&'s self, id: i32, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let (_, token) = match self.library_access(id).await {
+      Ok(a) => a,
+      Err(res) => return Some(res),
+    };
+    let filters = match Self::parse_filters(&req) {
+      Ok(f) => f.unwrap_or_default(),
+      Err(res) => return Some(res),
+    };
+    let json: Value = serde_json::from_slice(&req.get_data()).unwrap_or(json!({}));
+    let page_token = json["pageToken"].as_str().map(|t| t.to_string());
+
+    let mut guard = match self.downloader(id).await {
+      Ok(g) => g,
+      Err(res) => return Some(res),
+    };
+    guard.get().set_token(&token);
+    let page = guard.get().search(&filters, page_token).await;
+    drop(guard);
+
+    match page {
+      Ok(page) => {
+        self.audit_log.record(&req, AuditAction::PhotosSearched, Some(id), Some(id), None).await;
+        Some(
+          Response::from_json(200, json!({ "mediaItems": page.media_items, "nextPageToken": page.next_page_token }))
+            .unwrap(),
+        )
+      }
+      Err(DownloaderError::QuotaExhausted(reset_at)) =>
+        Some(Response::from_json(429, json!({ "error": "Daily API quota used up", "reset_at": reset_at })).unwrap()),
+      Err(e) => {
+        error!("Search for user {} failed: {:?}", id, e);
+        Some(Response::from_json(502, json!({ "error": format!("{:?}", e) })).unwrap())
+      }
+    }
+  }
+
+  /// Get the progress of the last sync of a user
+  pub async fn handle_sync_status<'s, 'r>(&'s self, id: i32) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    Some(Response::from_json(200, json!(self.jobs.get_progress(id))).unwrap())
+  }
+
+  /// Get how many downloader requests of a user are waiting in the pool and
+  /// the state of the daily API quota, admins also get the depth of every
+  /// user's queue
+  pub async fn handle_queue_depth<'s, 'r>(&'s self, id: i32) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let is_admin = self.user_manager.lock().await.require_role(id, Role::Admin).is_ok();
+    let depths = self.pool.get_queue_depths();
+
+    let mut res =
+      json!({ "depth": depths.get(&id).copied().unwrap_or_default(), "quota": self.pool.get_quota_status() });
+    if is_admin {
+      res["users"] = json!(depths);
+    }
+
+    Some(Response::from_json(200, res).unwrap())
+  }
+
+  /// List the archived albums of a user
+  pub async fn handle_list_albums<'s, 'r>(&'s self, id: i32) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let database = self.media_store.get_database();
+    let res = database.lock().await.get_albums(id).await;
+    match res {
+      Ok(albums) => Some(Response::from_json(200, json!({ "albums": albums })).unwrap()),
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+
+  /// Get the google ids of the items in an archived album, in album order
+  pub async fn handle_album_items<'s, 'r>(&'s self, id: i32, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let album_id: i32 = match req.get_url_params().get("id").and_then(|i| i.parse().ok()) {
+      Some(i) => i,
+      None => return Some(Response::from_json(400, json!({ "error": "Missing album id" })).unwrap()),
+    };
+
+    let database = self.media_store.get_database();
+    let res = database.lock().await.get_album_items(id, album_id).await;
+    match res {
+      Ok(Some(items)) => Some(Response::from_json(200, json!({ "id": album_id, "items": items })).unwrap()),
+      Ok(None) => Some(Response::from_json(404, json!({ "error": "Album not found" })).unwrap()),
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+
+  /// Get how much space deduplication saves for the user and the whole
+  /// archive, admins also get the savings of every user
+  pub async fn handle_dedup_stats<'s, 'r>(&'s self, id: i32) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let is_admin = self.user_manager.lock().await.require_role(id, Role::Admin).is_ok();
+
+    let database = self.media_store.get_database();
+    let res = database.lock().await.get_dedup_stats().await;
+    let (users, overall) = match res {
+      Ok(s) => s,
+      Err(e) => return Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    };
+
+    let user = users
+      .iter()
+      .find(|s| s.user_id == Some(id))
+      .cloned()
+      .unwrap_or(DedupStats { user_id: Some(id), ..Default::default() });
+
+    let mut res = json!({ "user": user, "overall": overall });
+    if is_admin {
+      res["users"] = json!(users);
+    }
+
+    Some(Response::from_json(200, res).unwrap())
   }
 
   pub async fn handle_list_photos<'s, 'r>(&'s self, id: i32, req: Request<'r>) -> Option<Response<'r>>
@@ -30,11 +273,25 @@ impl PhotoManager {
     let user = user_manager.get_active_users().get(&id).unwrap();
 
     if let Some(guser) = user.get_guser() {
-      let token = guser.get_auth_token();
-      let mut downloader_guard = self.pool.clone().acquire().await.unwrap();
-      downloader_guard.get().set_token(token);
+      if !guser.has_photos_access() {
+        error!("User {} did not grant access to their photo library", user.get_username());
+        return Some(
+          Response::from_json(403, json!({ "error": "Google account was linked without photo library access" })).unwrap(),
+        );
+      }
+
+      let token = guser.get_auth_token().to_string();
+      drop(user);
+      drop(user_manager);
+
+      let mut downloader_guard = match self.downloader(id).await {
+        Ok(g) => g,
+        Err(res) => return Some(res),
+      };
+      downloader_guard.get().set_token(&token);
       let photos = downloader_guard.get().list_photos(None).await;
       trace!("{:?}", photos);
+      self.audit_log.record(&req, AuditAction::PhotosListed, Some(id), Some(id), None).await;
     } else {
       error!("User {} not logged into google", user.get_username());
       return Some(Response::from_json(401, json!({ "error": "User is not logged into google" })).unwrap());
@@ -60,6 +317,11 @@ impl ApiMethod for PhotoManager {
     };
     match req.get_endpoint().rsplit("photos/").next() {
       Some("list") => self.handle_list_photos(id, req).await,
+      Some("sync") => self.handle_sync_status(id).await,
+      Some("dedup") => self.handle_dedup_stats(id).await,
+      Some("queue") => self.handle_queue_depth(id).await,
+      Some("albums") => self.handle_list_albums(id).await,
+      Some("album") => self.handle_album_items(id, req).await,
       _ => return Some(Response::basic(404, "Not Found")),
     }
   }
@@ -72,6 +334,10 @@ impl ApiMethod for PhotoManager {
       Ok(id) => id,
       Err(_) => return Some(Response::basic(401, "Unauthorized")),
     };
-    None
+    match req.get_endpoint().rsplit("/").next() {
+      Some("sync") => self.handle_start_sync(id, req).await,
+      Some("search") => self.handle_search(id, req).await,
+      _ => Some(Response::basic(404, "Not Found")),
+    }
   }
 }
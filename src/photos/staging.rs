@@ -0,0 +1,165 @@
+use std::{io::SeekFrom, path::PathBuf};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+  fs::{self, File, OpenOptions},
+  io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+/// How often the offset of a download is recorded
+const CHECKPOINT_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// A download in the staging directory that has been fully written and
+/// checked
+#[derive(Debug)]
+pub struct StagedFile {
+  pub path: PathBuf,
+  pub hash: String,
+  pub size: u64,
+}
+
+/// Saved next to a `.part` file so an interrupted download can continue
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PartState {
+  /// Bytes of the part file known to be on disk
+  offset: u64,
+  /// Size of the whole file if the server told us
+  expected_size: Option<u64>,
+}
+
+/// Writes a download to a `.part` file in the staging directory, hashing it on
+/// the way. The offset is recorded so the download can be resumed after a
+/// failure or a restart
+pub struct Stager {
+  path: PathBuf,
+  state_path: PathBuf,
+  file: File,
+  hasher: Sha256,
+  size: u64,
+  expected_size: Option<u64>,
+  checkpointed: u64,
+}
+
+impl Stager {
+  /// Open the part file at `path`, continuing from its recorded offset if
+  /// there is one
+  pub async fn open(path: PathBuf) -> io::Result<Self> {
+    let state_path = PathBuf::from(format!("{}.json", path.display()));
+    let state: PartState = match fs::read(&state_path).await {
+      Ok(s) => serde_json::from_slice(&s).unwrap_or_default(),
+      Err(_) => PartState::default(),
+    };
+
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).await?;
+
+    // Anything after the recorded offset may not have made it to the disk
+    let offset = state.offset.min(file.metadata().await?.len());
+    file.set_len(offset).await?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+      let n = file.read(&mut buf).await?;
+      if n == 0 {
+        break;
+      }
+      hasher.update(&buf[..n]);
+    }
+
+    if offset > 0 {
+      debug!("Resuming {} at {} bytes", path.display(), offset);
+    }
+
+    Ok(Self { path, state_path, file, hasher, size: offset, expected_size: state.expected_size, checkpointed: offset })
+  }
+
+  /// Number of bytes already downloaded
+  #[inline]
+  pub fn get_offset(&self) -> u64 {
+    self.size
+  }
+
+  #[inline]
+  pub fn get_expected_size(&self) -> Option<u64> {
+    self.expected_size
+  }
+
+  #[inline]
+  pub fn set_expected_size(&mut self, expected_size: Option<u64>) {
+    self.expected_size = expected_size;
+  }
+
+  /// Throw away everything downloaded so far
+  pub async fn restart(&mut self) -> io::Result<()> {
+    self.file.set_len(0).await?;
+    self.file.seek(SeekFrom::Start(0)).await?;
+    self.hasher = Sha256::new();
+    self.size = 0;
+    self.expected_size = None;
+    self.checkpoint().await
+  }
+
+  pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+    self.file.write_all(chunk).await?;
+    self.hasher.update(chunk);
+    self.size += chunk.len() as u64;
+
+    if self.size - self.checkpointed >= CHECKPOINT_INTERVAL {
+      self.checkpoint().await?;
+    }
+
+    Ok(())
+  }
+
+  /// Make sure the written bytes are on disk and record the offset
+  pub async fn checkpoint(&mut self) -> io::Result<()> {
+    self.file.flush().await?;
+    self.file.sync_data().await?;
+
+    let state = PartState { offset: self.size, expected_size: self.expected_size };
+    fs::write(&self.state_path, serde_json::to_vec(&state)?).await?;
+    self.checkpointed = self.size;
+
+    Ok(())
+  }
+
+  /// Check the download against the expected size and the bytes on disk
+  /// against the hash of what was received. A download that fails the checks
+  /// is thrown away so the next attempt starts over
+  pub async fn finish(&mut self) -> io::Result<StagedFile> {
+    self.checkpoint().await?;
+    let hash = format!("{:x}", self.hasher.clone().finalize());
+
+    if let Some(expected) = self.expected_size {
+      if expected != self.size {
+        warn!("{} is {} bytes, expected {}", self.path.display(), self.size, expected);
+        self.restart().await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Size mismatch"));
+      }
+    }
+
+    let mut file = File::open(&self.path).await?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+      let n = file.read(&mut buf).await?;
+      if n == 0 {
+        break;
+      }
+      hasher.update(&buf[..n]);
+      size += n as u64;
+    }
+
+    if size != self.size || format!("{:x}", hasher.finalize()) != hash {
+      warn!("{} does not match what was downloaded", self.path.display());
+      self.restart().await?;
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "Hash mismatch"));
+    }
+
+    let _ = fs::remove_file(&self.state_path).await;
+    Ok(StagedFile { path: self.path.clone(), hash, size })
+  }
+}
@@ -0,0 +1,1036 @@
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  fmt,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+
+use archive_config::CONFIG;
+use archive_database::{
+  entities::{pending_media_items, sync_jobs, users},
+  structs::{AlbumInfo, GUser, MediaItemInfo, SyncJobStatus},
+};
+use dashmap::DashMap;
+use gphotos_downloader::{
+  error::DownloaderError,
+  http::HttpClient,
+  structs::{Album, DownloaderGuard, Filters, MediaItem},
+  Downloader, DownloaderPool, Priority, MAX_BATCH_GET_IDS,
+};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, timeout};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::{
+  photos::{
+    layout::LayoutOwner,
+    media_store::SharedMediaStore,
+    staging::{StagedFile, Stager},
+  },
+  user::{oauth::refresh_access_token, user_manager::UserManagerError},
+};
+
+pub type SyncProgressMap = Arc<DashMap<i32, SyncProgress>>;
+
+/// `baseUrl`s are valid for about an hour, refresh them a bit before that
+const URL_MAX_AGE: Duration = Duration::from_secs(50 * 60);
+
+/// How long to wait before looking at a video google is still processing
+/// again, doubled after every attempt up to `PENDING_RETRY_MAX`
+const PENDING_RETRY_BASE: Duration = Duration::from_secs(15 * 60);
+const PENDING_RETRY_MAX: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long cut off jobs get to record their checkpoints once the grace
+/// period is over
+const ABORT_WAIT: Duration = Duration::from_secs(10);
+
+/// How long after the API quota reset paused jobs continue, in case the
+/// clocks disagree
+const QUOTA_RESET_MARGIN: Duration = Duration::from_secs(60);
+
+/// Seconds before a google access token expires to get a new one, so it does
+/// not run out in the middle of a page
+const TOKEN_REFRESH_MARGIN: i64 = 5 * 60;
+
+/// Progress of the last sync of a user
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SyncProgress {
+  pub running: bool,
+  pub started_at: i64,
+  pub finished_at: Option<i64>,
+  pub albums: u64,
+  pub listed: u64,
+  pub stored: u64,
+  pub deduplicated: u64,
+  pub skipped: u64,
+  /// Videos google is still processing, they are tried again on a later sync
+  pub pending: u64,
+  pub failed: u64,
+  pub error: Option<String>,
+}
+
+fn update<F: FnOnce(&mut SyncProgress)>(progress: &SyncProgressMap, user_id: i32, f: F) {
+  if let Some(mut p) = progress.get_mut(&user_id) {
+    f(&mut p);
+  }
+}
+
+/// Why a sync job was not queued
+#[derive(Debug)]
+pub enum EnqueueError {
+  ShuttingDown,
+  /// The account already has a job running
+  AlreadyRunning,
+  DatabaseError(String),
+}
+
+impl fmt::Display for EnqueueError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::ShuttingDown => write!(f, "The server is shutting down"),
+      Self::AlreadyRunning => write!(f, "A sync is already running"),
+      Self::DatabaseError(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+/// Why the google account of a user can not be used
+#[derive(Debug)]
+enum AccountError {
+  /// Not linked, linked without photo library access or the access was
+  /// revoked
+  Unusable(String),
+  /// The database or google could not be reached, may work on a later try
+  Unreachable(String),
+}
+
+impl fmt::Display for AccountError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Unusable(e) | Self::Unreachable(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+/// The sync job queue: records jobs in the `sync_jobs` table, runs them in
+/// the background (one per account at a time) and stops them when the server
+/// shuts down
+#[derive(Clone)]
+pub struct JobRunner {
+  store: SharedMediaStore,
+  pool: Arc<DownloaderPool>,
+  /// For refreshing google access tokens
+  http: HttpClient,
+  progress: SyncProgressMap,
+  /// Jobs stop after the item they are on
+  stop: CancellationToken,
+  /// The grace period is over, downloads stop where they are
+  abort: CancellationToken,
+  tasks: TaskTracker,
+  /// Jobs left over from the last run were picked up again
+  resumed: Arc<AtomicBool>,
+}
+
+impl JobRunner {
+  pub fn new(store: SharedMediaStore, pool: Arc<DownloaderPool>, http: HttpClient) -> Self {
+    Self {
+      store,
+      pool,
+      http,
+      progress: Arc::new(DashMap::new()),
+      stop: CancellationToken::new(),
+      abort: CancellationToken::new(),
+      tasks: TaskTracker::new(),
+      resumed: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  /// Whether new jobs are refused because the server is shutting down
+  #[inline]
+  pub fn is_stopping(&self) -> bool {
+    self.stop.is_cancelled()
+  }
+
+  /// Whether the jobs left over from the last run were resumed, after that
+  /// every running or interrupted job in the database should be running here
+  #[inline]
+  pub fn has_resumed(&self) -> bool {
+    self.resumed.load(Ordering::Relaxed)
+  }
+
+  /// Progress of the last sync of a user
+  pub fn get_progress(&self, user_id: i32) -> SyncProgress {
+    self.progress.get(&user_id).map(|p| p.clone()).unwrap_or_default()
+  }
+
+  /// Whether a user has a sync running
+  pub fn is_running(&self, user_id: i32) -> bool {
+    self.progress.get(&user_id).is_some_and(|p| p.running)
+  }
+
+  /// Record a sync job for the library of `owner` and start it, unless one is
+  /// already running for them
+  ///
+  /// Returns the progress of the new job or an EnqueueError if it was not
+  /// started
+  pub async fn enqueue(
+    &self,
+    owner: LayoutOwner,
+    token: String,
+    filters: Option<Filters>,
+  ) -> Result<SyncProgress, EnqueueError> {
+    if self.is_stopping() {
+      return Err(EnqueueError::ShuttingDown);
+    }
+
+    let user_id = owner.user_id;
+    let progress = SyncProgress { running: true, started_at: chrono::Utc::now().timestamp(), ..Default::default() };
+    {
+      // Claimed before the job is recorded, so two requests can not both start
+      // one
+      let mut current = self.progress.entry(user_id).or_default();
+      if current.running {
+        return Err(EnqueueError::AlreadyRunning);
+      }
+      *current = progress.clone();
+    }
+
+    let res = self
+      .store
+      .get_database()
+      .lock()
+      .await
+      .new_sync_job(
+        user_id,
+        filters.as_ref().map(|f| serde_json::to_string(f).unwrap_or_default()),
+        serde_json::to_string(&progress).unwrap_or_default(),
+      )
+      .await;
+    let job = match res {
+      Ok(j) => j,
+      Err(e) => {
+        update(&self.progress, user_id, |p| {
+          p.running = false;
+          p.error = Some(e.get_message());
+        });
+        return Err(EnqueueError::DatabaseError(e.get_message()));
+      }
+    };
+
+    self.spawn(SyncJob { id: job.id, owner, token, filters, page_token: None });
+    Ok(progress)
+  }
+
+  /// Who owns the library of a user and the token to access it, from the
+  /// database for when the user is not logged in
+  pub async fn library_of(&self, user_id: i32) -> Result<(LayoutOwner, String), String> {
+    let database = self.store.get_database();
+    let user = database.lock().await.get_user_by(users::Column::Id, user_id).await.map_err(|e| e.get_message())?;
+    let guser = self.google_account(user_id).await.map_err(|e| e.to_string())?;
+
+    let owner = LayoutOwner { user_id, username: user.get_username(), account: Some(guser.get_name().to_string()) };
+    Ok((owner, guser.get_auth_token().to_string()))
+  }
+
+  /// The google account of a user with an access token that is good for a
+  /// while, refreshed with the stored refresh token if it is about to expire
+  async fn google_account(&self, user_id: i32) -> Result<GUser, AccountError> {
+    let database = self.store.get_database();
+    let guser = database.lock().await.get_google_account(user_id).await;
+    let mut guser = match guser.map_err(|e| AccountError::Unreachable(e.get_message()))? {
+      Some(g) if g.has_photos_access() => g,
+      _ => return Err(AccountError::Unusable("The user has no google account with photo library access".to_string())),
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let refresh_token = match (guser.get_expires_at(), guser.get_refresh_token()) {
+      (Some(expires_at), Some(t)) if expires_at - TOKEN_REFRESH_MARGIN <= now => t.to_string(),
+      (Some(expires_at), None) if expires_at <= now =>
+        return Err(AccountError::Unusable(
+          "The google access token expired, link the google account again".to_string(),
+        )),
+      // Still good, or linked before expiry times were stored
+      _ => return Ok(guser),
+    };
+
+    let token = match refresh_access_token(self.http.get_oauth_client(), &refresh_token).await {
+      Ok(t) => t,
+      Err(UserManagerError::AuthenticationError(e)) =>
+        return Err(AccountError::Unusable(format!("{}, link the google account again", e))),
+      Err(e) => return Err(AccountError::Unreachable(e.get_message())),
+    };
+    guser.set_auth_token(token.access_token);
+    guser.set_expires_at(token.expires_at);
+    if token.refresh_token.is_some() {
+      guser.set_refresh_token(token.refresh_token);
+    }
+    // The new token works either way, the next call refreshes again
+    if let Err(e) = database.lock().await.update_google_token(user_id, &guser).await {
+      error!("Failed to store the refreshed google access token of user {}: {}", user_id, e.get_message());
+    }
+    debug!("Refreshed the google access token of user {}", user_id);
+
+    Ok(guser)
+  }
+
+  /// Start the sync jobs a shutdown interrupted again, and the ones that were
+  /// still running when the server went away without one
+  pub async fn resume(&self) {
+    let database = self.store.get_database();
+    let mut jobs = Vec::new();
+    for status in [SyncJobStatus::Interrupted, SyncJobStatus::Running] {
+      match database.lock().await.get_sync_jobs(status).await {
+        Ok(j) => jobs.extend(j),
+        Err(e) => error!("Failed to fetch {} sync jobs: {}", status, e.get_message()),
+      }
+    }
+
+    for job in jobs {
+      let res = match self.is_running(job.user_id) {
+        true => Err("The user already has a sync job running".to_string()),
+        false => self.resume_job(&job).await,
+      };
+
+      if let Err(e) = res {
+        error!("Could not resume sync job {} of user {}: {}", job.id, job.user_id, e);
+        let res =
+          database.lock().await.checkpoint_sync_job(job.id, SyncJobStatus::Failed, None, job.progress, Some(e)).await;
+        if let Err(e) = res {
+          error!("Failed to record sync job {} as failed: {}", job.id, e.get_message());
+        }
+      }
+    }
+
+    self.resumed.store(true, Ordering::Relaxed);
+  }
+
+  async fn resume_job(&self, job: &sync_jobs::Model) -> Result<(), String> {
+    let (owner, token) = self.library_of(job.user_id).await?;
+    let filters = match &job.filters {
+      Some(f) => Some(serde_json::from_str(f).map_err(|e| format!("Bad filters: {}", e))?),
+      None => None,
+    };
+
+    let mut progress: SyncProgress = serde_json::from_str(&job.progress).unwrap_or_default();
+    progress.running = true;
+    progress.error = None;
+    self.progress.insert(job.user_id, progress);
+
+    self.spawn(SyncJob { id: job.id, owner, token, filters, page_token: job.page_token.clone() });
+    Ok(())
+  }
+
+  /// Download a media item of `owner` from google again, to restore the blob
+  /// with `hash` it was archived as. If google now serves different content
+  /// the item is linked to that instead
+  ///
+  /// Returns true if the blob was restored or an error message if the item
+  /// could not be downloaded
+  pub async fn redownload(
+    &self,
+    owner: &LayoutOwner,
+    token: &str,
+    google_id: &str,
+    hash: &str,
+  ) -> Result<bool, String> {
+    let mut guard = acquire(&self.pool, owner.user_id, token, self)
+      .await?
+      .ok_or_else(|| "The server is shutting down".to_string())?;
+    let downloader = guard.get();
+
+    let res = downloader.batch_get(&[google_id.to_string()]).await.map_err(|e| format!("{:?}", e))?;
+    let item = match res.media_item_results.into_iter().next().map(|r| (r.media_item, r.status)) {
+      Some((Some(item), _)) => item,
+      Some((None, Some(status))) => return Err(format!("{} ({})", status.message, status.code)),
+      _ => return Err("Google did not return the item".to_string()),
+    };
+
+    let staged = download_item(&self.store, downloader, owner, &item, &self.abort).await.map_err(|e| e.to_string())?;
+    if self.store.restore(hash, &staged).await.map_err(|e| e.to_string())? {
+      return Ok(true);
+    }
+
+    warn!("{} changed on google since it was archived, linking it to the new content", google_id);
+    self.store.commit(owner, &item_info(&item), staged).await.map_err(|e| e.to_string())?;
+    Ok(false)
+  }
+
+  fn spawn(&self, job: SyncJob) {
+    self.tasks.spawn(sync_user(self.clone(), job));
+  }
+
+  /// Stop taking new jobs and let the running ones finish the item they are
+  /// on. Downloads still going after `grace` are cut off, every job records a
+  /// checkpoint to resume from on the next start
+  pub async fn shutdown(&self, grace: Duration) {
+    self.stop.cancel();
+    self.tasks.close();
+    if self.tasks.is_empty() {
+      return;
+    }
+
+    info!("Waiting up to {:?} for {} sync jobs to stop", grace, self.tasks.len());
+    if timeout(grace, self.tasks.wait()).await.is_ok() {
+      return;
+    }
+
+    warn!("Sync jobs did not stop within {:?}, cutting off their downloads", grace);
+    self.abort.cancel();
+    if timeout(ABORT_WAIT, self.tasks.wait()).await.is_err() {
+      error!("{} sync jobs did not record a checkpoint in time", self.tasks.len());
+    }
+  }
+}
+
+/// A sync job, new or resumed from its checkpoint
+struct SyncJob {
+  id: i32,
+  owner: LayoutOwner,
+  token: String,
+  filters: Option<Filters>,
+  /// Page of the library to continue from, None to start from the beginning
+  page_token: Option<String>,
+}
+
+/// How a run ended without failing
+enum Outcome {
+  Finished,
+  /// Stopped by a shutdown, the page token to continue from
+  Interrupted(Option<String>),
+  /// The daily API quota is used up, the page token to continue from once it
+  /// resets at the unix timestamp
+  Paused(Option<String>, i64),
+}
+
+/// Archive every media item in the library of a user that has not been
+/// archived yet, or only the ones matching the filters of the job
+async fn sync_user(runner: JobRunner, mut job: SyncJob) {
+  let (store, progress) = (&runner.store, &runner.progress);
+  let user_id = job.owner.user_id;
+  match job.page_token {
+    Some(_) => info!("Resuming sync job {} for user {}", job.id, user_id),
+    None => info!("Starting sync job {} for user {}", job.id, user_id),
+  }
+  let res = loop {
+    match run(store, runner.pool.clone(), progress, &job, &runner).await {
+      Ok(Outcome::Paused(page_token, reset_at)) => {
+        job.page_token = page_token;
+        if !pause(&runner, &job, reset_at).await {
+          break Ok(Outcome::Interrupted(job.page_token.clone()));
+        }
+      }
+      res => break res,
+    }
+  };
+
+  update(progress, user_id, |p| {
+    p.running = false;
+    p.finished_at = Some(chrono::Utc::now().timestamp());
+    p.error = match &res {
+      Ok(Outcome::Finished) => None,
+      Ok(Outcome::Interrupted(_) | Outcome::Paused(..)) =>
+        Some("Interrupted by a shutdown, resumes on the next start".to_string()),
+      Err(e) => Some(e.clone()),
+    };
+  });
+
+  let (status, page_token, error) = match res {
+    Ok(Outcome::Finished) => {
+      info!("Finished sync job {} for user {}", job.id, user_id);
+      (SyncJobStatus::Finished, None, None)
+    }
+    Ok(Outcome::Interrupted(page_token) | Outcome::Paused(page_token, _)) => {
+      info!("Interrupted sync job {} for user {}", job.id, user_id);
+      (SyncJobStatus::Interrupted, page_token, None)
+    }
+    Err(e) => {
+      error!("Sync job {} for user {} failed: {}", job.id, user_id, e);
+      (SyncJobStatus::Failed, None, Some(e))
+    }
+  };
+  checkpoint(store, progress, &job, status, page_token, error).await;
+}
+
+/// Wait for the daily API quota to reset without holding on to a downloader.
+/// The job is recorded as interrupted meanwhile, so a restart picks it up
+///
+/// Returns false if the server started shutting down while waiting
+async fn pause(runner: &JobRunner, job: &SyncJob, reset_at: i64) -> bool {
+  let (store, progress) = (&runner.store, &runner.progress);
+  let user_id = job.owner.user_id;
+  let reset = chrono::DateTime::from_timestamp(reset_at, 0).unwrap_or_default();
+  info!("Daily API quota used up, pausing sync job {} for user {} until {}", job.id, user_id, reset);
+
+  update(progress, user_id, |p| p.error = Some(format!("Daily API quota used up, continuing after {}", reset)));
+  checkpoint(store, progress, job, SyncJobStatus::Interrupted, job.page_token.clone(), None).await;
+
+  let wait = Duration::from_secs((reset_at - chrono::Utc::now().timestamp()).max(0) as u64) + QUOTA_RESET_MARGIN;
+  tokio::select! {
+    _ = runner.stop.cancelled() => return false,
+    _ = sleep(wait) => (),
+  }
+
+  info!("Continuing sync job {} for user {}", job.id, user_id);
+  update(progress, user_id, |p| p.error = None);
+  checkpoint(store, progress, job, SyncJobStatus::Running, job.page_token.clone(), None).await;
+  true
+}
+
+/// Save where a job is at so it can be resumed
+async fn checkpoint(
+  store: &SharedMediaStore,
+  progress: &SyncProgressMap,
+  job: &SyncJob,
+  status: SyncJobStatus,
+  page_token: Option<String>,
+  error: Option<String>,
+) {
+  let snapshot = progress.get(&job.owner.user_id).map(|p| p.clone()).unwrap_or_default();
+  let snapshot = serde_json::to_string(&snapshot).unwrap_or_default();
+
+  let res = store.get_database().lock().await.checkpoint_sync_job(job.id, status, page_token, snapshot, error).await;
+  if let Err(e) = res {
+    error!("Failed to checkpoint sync job {}: {}", job.id, e.get_message());
+  }
+}
+
+async fn run(
+  store: &SharedMediaStore,
+  pool: Arc<DownloaderPool>,
+  progress: &SyncProgressMap,
+  job: &SyncJob,
+  runner: &JobRunner,
+) -> Result<Outcome, String> {
+  let owner = &job.owner;
+  let user_id = owner.user_id;
+  let known = store.get_database().lock().await.get_media_item_ids(user_id).await;
+  let known = known.map_err(|e| e.get_message())?;
+
+  let pending = store.get_database().lock().await.get_pending_media_items(user_id).await;
+  let mut pending: HashMap<String, pending_media_items::Model> =
+    pending.map_err(|e| e.get_message())?.into_iter().map(|p| (p.google_id.clone(), p)).collect();
+
+  // A resumed job already did this before it was interrupted
+  if job.page_token.is_none() {
+    let mut guard = match acquire(&pool, user_id, &job.token, runner).await? {
+      Some(g) => g,
+      None => return Ok(Outcome::Interrupted(None)),
+    };
+    let downloader = guard.get();
+
+    // Albums first, so layouts using `{album}` can place the items right away
+    if let Err(e) = sync_albums(store, downloader, progress, user_id).await {
+      error!("Failed to sync the albums of user {}: {}", user_id, e);
+    }
+
+    // Items that were still processing on the last runs and are due for
+    // another look, a filtered sync may not list them
+    if !retry_pending(store, downloader, progress, owner, &mut pending, runner).await {
+      return Ok(Outcome::Interrupted(None));
+    }
+  }
+
+  let mut page_token = job.page_token.clone();
+  loop {
+    // Given back after every page, so other users get a turn when the pool is
+    // busy
+    let mut guard = match acquire(&pool, user_id, &job.token, runner).await? {
+      Some(g) => g,
+      None => return Ok(Outcome::Interrupted(page_token)),
+    };
+    let downloader = guard.get();
+
+    let page = match &job.filters {
+      Some(f) => downloader.search(f, page_token.clone()).await,
+      None => downloader.list_photos(page_token.clone()).await,
+    };
+    let page = match page {
+      Ok(p) => p,
+      Err(DownloaderError::QuotaExhausted(reset_at)) => return Ok(Outcome::Paused(page_token, reset_at)),
+      Err(e) => return Err(format!("{:?}", e)),
+    };
+    update(progress, user_id, |p| p.listed += page.media_items.len() as u64);
+
+    let now = chrono::Utc::now().timestamp();
+    let mut queue = VecDeque::new();
+    for item in page.media_items {
+      if known.contains(&item.id) {
+        update(progress, user_id, |p| p.skipped += 1);
+      } else if !item.is_ready() && pending.get(&item.id).is_some_and(|p| p.next_attempt_at > now) {
+        update(progress, user_id, |p| p.pending += 1);
+      } else {
+        queue.push_back(item);
+      }
+    }
+
+    // The items of the page that were archived are known on the next run,
+    // so an interrupted page is simply listed again
+    if !archive_queue(store, downloader, progress, owner, &mut pending, queue, runner).await {
+      return Ok(Outcome::Interrupted(page_token));
+    }
+
+    page_token = page.next_page_token;
+    if page_token.is_none() {
+      break;
+    }
+    checkpoint(store, progress, job, SyncJobStatus::Running, page_token.clone(), None).await;
+  }
+
+  Ok(Outcome::Finished)
+}
+
+/// Get a downloader for a background sync of a user
+///
+/// Returns None if the server started shutting down while waiting
+async fn acquire(
+  pool: &Arc<DownloaderPool>,
+  user_id: i32,
+  token: &str,
+  runner: &JobRunner,
+) -> Result<Option<DownloaderGuard>, String> {
+  let res = pool.clone().acquire_cancellable(user_id, Priority::Background, &runner.stop).await;
+  let mut guard = match res {
+    Ok(g) => g,
+    Err(DownloaderError::Cancelled | DownloaderError::ShutDown) => return Ok(None),
+    Err(e) => return Err(format!("{:?}", e)),
+  };
+
+  // Syncs can outlast the access token they started with, and the user may
+  // have unlinked the account or revoked the access meanwhile
+  match runner.google_account(user_id).await {
+    Ok(g) => guard.get().set_token(g.get_auth_token()),
+    Err(AccountError::Unusable(e)) => return Err(e),
+    Err(AccountError::Unreachable(e)) => {
+      warn!("Could not refresh the google access token of user {}: {}", user_id, e);
+      guard.get().set_token(token);
+    }
+  }
+  Ok(Some(guard))
+}
+
+/// Archive the items of a queue, refreshing their urls when they get old.
+/// Videos google has not finished processing are recorded as pending instead
+///
+/// Returns false if the queue was left unfinished because of a shutdown
+async fn archive_queue(
+  store: &SharedMediaStore,
+  downloader: &Downloader,
+  progress: &SyncProgressMap,
+  owner: &LayoutOwner,
+  pending: &mut HashMap<String, pending_media_items::Model>,
+  mut queue: VecDeque<MediaItem>,
+  runner: &JobRunner,
+) -> bool {
+  let user_id = owner.user_id;
+  let mut fetched_at = Instant::now();
+  let mut stale = false;
+  let mut refreshed = HashSet::new();
+
+  loop {
+    if runner.is_stopping() && !queue.is_empty() {
+      return false;
+    }
+
+    if !queue.is_empty() && (stale || fetched_at.elapsed() >= URL_MAX_AGE) {
+      let dropped = refresh_urls(downloader, &mut queue).await;
+      update(progress, user_id, |p| p.failed += dropped);
+      fetched_at = Instant::now();
+      stale = false;
+    }
+
+    let item = match queue.pop_front() {
+      Some(i) => i,
+      None => break,
+    };
+
+    if !item.is_ready() {
+      defer_item(store, progress, user_id, pending, &item).await;
+      continue;
+    }
+
+    match archive_item(store, downloader, owner, &item, &runner.abort).await {
+      Ok(deduplicated) => {
+        downloader.get_stats().item_archived();
+        match deduplicated {
+          true => update(progress, user_id, |p| p.deduplicated += 1),
+          false => update(progress, user_id, |p| p.stored += 1),
+        }
+        if pending.remove(&item.id).is_some() {
+          let res = store.get_database().lock().await.delete_pending_media_item(user_id, &item.id).await;
+          if let Err(e) = res {
+            error!("Failed to forget pending item {}: {}", item.id, e.get_message());
+          }
+        }
+      }
+      // The rest of the queue was listed at the same time, so refresh it too
+      Err(ArchiveError::Expired) if refreshed.insert(item.id.clone()) => {
+        debug!("Base url of {} expired, refreshing {} queued items", item.id, queue.len() + 1);
+        queue.push_front(item);
+        stale = true;
+      }
+      Err(ArchiveError::Interrupted) => return false,
+      Err(e) => {
+        error!("Failed to archive {} for user {}: {}", item.id, user_id, e);
+        update(progress, user_id, |p| p.failed += 1);
+      }
+    }
+  }
+
+  true
+}
+
+/// Record a video that is still processing so a later sync tries it again,
+/// waiting longer after every attempt
+async fn defer_item(
+  store: &SharedMediaStore,
+  progress: &SyncProgressMap,
+  user_id: i32,
+  pending: &mut HashMap<String, pending_media_items::Model>,
+  item: &MediaItem,
+) {
+  let attempts = pending.get(&item.id).map(|p| p.attempts).unwrap_or_default();
+  let delay = PENDING_RETRY_BASE.saturating_mul(2u32.saturating_pow(attempts.max(0) as u32)).min(PENDING_RETRY_MAX);
+  let next_attempt_at = chrono::Utc::now().timestamp() + delay.as_secs() as i64;
+  let status = item.get_video_status().unwrap_or_default().to_string();
+
+  debug!("{} is not ready ({}), checking again in {:?}", item.id, status, delay);
+  update(progress, user_id, |p| p.pending += 1);
+
+  let res = store.get_database().lock().await.set_media_item_pending(user_id, &item.id, &status, next_attempt_at).await;
+  match res {
+    Ok(p) => {
+      pending.insert(item.id.clone(), p);
+    }
+    Err(e) => error!("Failed to record pending item {}: {}", item.id, e.get_message()),
+  }
+}
+
+/// Look up the pending items that are due again and archive the ones google
+/// finished processing. Items google does not know anymore are forgotten
+///
+/// Returns false if they were left unfinished because of a shutdown
+async fn retry_pending(
+  store: &SharedMediaStore,
+  downloader: &Downloader,
+  progress: &SyncProgressMap,
+  owner: &LayoutOwner,
+  pending: &mut HashMap<String, pending_media_items::Model>,
+  runner: &JobRunner,
+) -> bool {
+  let now = chrono::Utc::now().timestamp();
+  let due: Vec<String> = pending.values().filter(|p| p.next_attempt_at <= now).map(|p| p.google_id.clone()).collect();
+  if due.is_empty() {
+    return true;
+  }
+  info!("Checking {} pending items of user {}", due.len(), owner.user_id);
+
+  let mut queue = VecDeque::new();
+  for chunk in due.chunks(MAX_BATCH_GET_IDS) {
+    let res = match downloader.batch_get(chunk).await {
+      Ok(r) => r,
+      Err(e) => {
+        // They are still pending, the next sync tries again
+        error!("Failed to fetch pending media items: {:?}", e);
+        return true;
+      }
+    };
+
+    for (id, result) in chunk.iter().zip(res.media_item_results) {
+      match result.media_item {
+        Some(item) => queue.push_back(item),
+        None => {
+          warn!("Pending item {} is gone, forgetting it", id);
+          pending.remove(id);
+          let res = store.get_database().lock().await.delete_pending_media_item(owner.user_id, id).await;
+          if let Err(e) = res {
+            error!("Failed to forget pending item {}: {}", id, e.get_message());
+          }
+        }
+      }
+    }
+  }
+
+  archive_queue(store, downloader, progress, owner, pending, queue, runner).await
+}
+
+/// Record every album of a user (their own and the ones shared with them) and
+/// which media items are in it
+async fn sync_albums(
+  store: &SharedMediaStore,
+  downloader: &Downloader,
+  progress: &SyncProgressMap,
+  user_id: i32,
+) -> Result<(), String> {
+  let mut albums: Vec<AlbumInfo> = Vec::new();
+
+  let mut page_token = None;
+  loop {
+    let page = downloader.list_albums(page_token).await.map_err(|e| format!("{:?}", e))?;
+    albums.extend(page.albums.into_iter().map(|a| album_info(a, false)));
+
+    page_token = page.next_page_token;
+    if page_token.is_none() {
+      break;
+    }
+  }
+
+  // Shared albums the user owns are in both lists
+  let mut page_token = None;
+  loop {
+    let page = downloader.list_shared_albums(page_token).await.map_err(|e| format!("{:?}", e))?;
+    for album in page.shared_albums {
+      match albums.iter_mut().find(|a| a.google_id == album.id) {
+        Some(a) => a.shared = true,
+        None => albums.push(album_info(album, true)),
+      }
+    }
+
+    page_token = page.next_page_token;
+    if page_token.is_none() {
+      break;
+    }
+  }
+
+  for album in &albums {
+    let mut item_ids = Vec::new();
+    let mut page_token = None;
+    loop {
+      let page = downloader.list_album_items(&album.google_id, page_token).await.map_err(|e| format!("{:?}", e))?;
+      item_ids.extend(page.media_items.into_iter().map(|i| i.id));
+
+      page_token = page.next_page_token;
+      if page_token.is_none() {
+        break;
+      }
+    }
+
+    let res = store.get_database().lock().await.set_album(user_id, album, &item_ids).await;
+    res.map_err(|e| e.get_message())?;
+    update(progress, user_id, |p| p.albums += 1);
+  }
+
+  let keep: Vec<String> = albums.into_iter().map(|a| a.google_id).collect();
+  let res = store.get_database().lock().await.delete_albums_except(user_id, &keep).await;
+  match res.map_err(|e| e.get_message())? {
+    0 => (),
+    n => info!("Removed {} albums of user {} that no longer exist", n, user_id),
+  }
+
+  Ok(())
+}
+
+fn album_info(album: Album, shared: bool) -> AlbumInfo {
+  AlbumInfo { google_id: album.id, title: album.title, shared, cover_media_item_id: album.cover_photo_media_item_id }
+}
+
+/// Get fresh `baseUrl`s for every queued item with `mediaItems:batchGet`
+///
+/// Returns how many items were dropped because google did not return them
+async fn refresh_urls(downloader: &Downloader, queue: &mut VecDeque<MediaItem>) -> u64 {
+  let ids: Vec<String> = queue.iter().map(|i| i.id.clone()).collect();
+  let mut fresh = HashMap::new();
+
+  for chunk in ids.chunks(MAX_BATCH_GET_IDS) {
+    let res = match downloader.batch_get(chunk).await {
+      Ok(r) => r,
+      Err(e) => {
+        // Keep the old urls, the downloads fail and are counted on their own
+        error!("Failed to refresh media items: {:?}", e);
+        return 0;
+      }
+    };
+
+    for (id, result) in chunk.iter().zip(res.media_item_results) {
+      match (result.media_item, result.status) {
+        (Some(item), _) => {
+          fresh.insert(id.clone(), item);
+        }
+        (None, Some(status)) => warn!("Could not refresh {}: {} ({})", id, status.message, status.code),
+        (None, None) => warn!("Could not refresh {}: no item in response", id),
+      }
+    }
+  }
+
+  let before = queue.len();
+  queue.retain_mut(|item| match fresh.remove(&item.id) {
+    Some(f) => {
+      *item = f;
+      true
+    }
+    None => false,
+  });
+
+  (before - queue.len()) as u64
+}
+
+#[derive(Debug)]
+enum ArchiveError {
+  /// The `baseUrl` of the item expired, the download can continue after it is
+  /// refreshed
+  Expired,
+  /// Cut off by a shutdown, what was downloaded is kept for the next run
+  Interrupted,
+  Failed(String),
+}
+
+impl fmt::Display for ArchiveError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Expired => write!(f, "Base url expired"),
+      Self::Interrupted => write!(f, "Interrupted by a shutdown"),
+      Self::Failed(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+/// Download a media item and hand it to the store
+///
+/// Returns true if the content was already stored
+async fn archive_item(
+  store: &SharedMediaStore,
+  downloader: &Downloader,
+  owner: &LayoutOwner,
+  item: &MediaItem,
+  abort: &CancellationToken,
+) -> Result<bool, ArchiveError> {
+  let staged = download_item(store, downloader, owner, item, abort).await?;
+  store.commit(owner, &item_info(item), staged).await.map_err(|e| ArchiveError::Failed(e.to_string()))
+}
+
+fn item_info(item: &MediaItem) -> MediaItemInfo {
+  MediaItemInfo {
+    google_id: item.id.clone(),
+    filename: item.filename.clone(),
+    mime_type: item.mime_type.clone(),
+    creation_time: Some(item.media_metadata.creation_time.clone()),
+  }
+}
+
+/// Download a media item to the staging directory, retrying failed attempts.
+/// Interrupted downloads are resumed with a range request
+async fn download_item(
+  store: &SharedMediaStore,
+  downloader: &Downloader,
+  owner: &LayoutOwner,
+  item: &MediaItem,
+  abort: &CancellationToken,
+) -> Result<StagedFile, ArchiveError> {
+  let mut stager = store.stager(owner.user_id, &item.id).await.map_err(|e| ArchiveError::Failed(e.to_string()))?;
+  let mut attempt = 0;
+
+  let staged = loop {
+    let res = match fetch(downloader, item, &mut stager, abort).await {
+      Ok(_) => stager.finish().await.map_err(|e| e.to_string()),
+      Err(DownloaderError::Cancelled) => {
+        if let Err(e) = stager.checkpoint().await {
+          error!("Failed to record download progress of {}: {}", item.id, e);
+        }
+        return Err(ArchiveError::Interrupted);
+      }
+      Err(DownloaderError::ExpiredUrl(_)) => {
+        // Keep what we have, the download continues once the url is refreshed
+        let _ = stager.checkpoint().await;
+        return Err(ArchiveError::Expired);
+      }
+      Err(e) => Err(format!("{:?}", e)),
+    };
+
+    match res {
+      Ok(s) => break s,
+      Err(e) if attempt < CONFIG.downloader.max_retries => {
+        // Keep what we have so the next attempt only fetches the rest
+        if let Err(e) = stager.checkpoint().await {
+          error!("Failed to record download progress of {}: {}", item.id, e);
+        }
+
+        attempt += 1;
+        let backoff = Duration::from_secs(2u64.pow(attempt).min(60));
+        warn!("Download of {} failed at {} bytes ({}), retrying in {:?}", item.id, stager.get_offset(), e, backoff);
+        tokio::select! {
+          _ = abort.cancelled() => return Err(ArchiveError::Interrupted),
+          _ = sleep(backoff) => (),
+        }
+      }
+      Err(e) => {
+        let _ = stager.checkpoint().await;
+        return Err(ArchiveError::Failed(e));
+      }
+    }
+  };
+  debug!("Downloaded {} ({} bytes, {})", item.id, staged.size, staged.hash);
+
+  Ok(staged)
+}
+
+/// Download the part of an item the stager does not have yet, until `abort`
+/// is cancelled
+async fn fetch(
+  downloader: &Downloader,
+  item: &MediaItem,
+  stager: &mut Stager,
+  abort: &CancellationToken,
+) -> Result<(), DownloaderError> {
+  let io_error = |e: std::io::Error| DownloaderError::RequestError(e.to_string());
+
+  let offset = stager.get_offset();
+  if offset > 0 && stager.get_expected_size() == Some(offset) {
+    return Ok(());
+  }
+
+  let mut res = tokio::select! {
+    biased;
+    _ = abort.cancelled() => return Err(DownloaderError::Cancelled),
+    res = downloader.download(item, offset) => res?,
+  };
+  if res.status().as_u16() == 206 {
+    let range = res.headers().get("content-range").and_then(|r| r.to_str().ok()).and_then(parse_content_range);
+    match range {
+      Some((start, total)) if start == offset => stager.set_expected_size(total),
+      _ => {
+        stager.restart().await.map_err(io_error)?;
+        return Err(DownloaderError::ApiError("Server answered with a different range".to_owned()));
+      }
+    }
+  } else {
+    if offset > 0 {
+      debug!("Server ignored the range for {}, starting over", item.id);
+      stager.restart().await.map_err(io_error)?;
+    }
+    stager.set_expected_size(res.content_length());
+  }
+
+  loop {
+    let chunk = tokio::select! {
+      biased;
+      _ = abort.cancelled() => return Err(DownloaderError::Cancelled),
+      chunk = res.chunk() => chunk.map_err(|e| DownloaderError::RequestError(e.to_string()))?,
+    };
+    let chunk = match chunk {
+      Some(c) => c,
+      None => break,
+    };
+    stager.write(&chunk).await.map_err(io_error)?;
+    downloader.throttle(chunk.len()).await;
+  }
+
+  match stager.get_expected_size() {
+    Some(expected) if stager.get_offset() < expected =>
+      Err(DownloaderError::RequestError(format!("Connection closed at {} of {} bytes", stager.get_offset(), expected))),
+    _ => Ok(()),
+  }
+}
+
+/// Parse a `Content-Range: bytes <start>-<end>/<total>` header
+///
+/// Returns the start and the total size if it is known
+fn parse_content_range(header: &str) -> Option<(u64, Option<u64>)> {
+  let (range, total) = header.strip_prefix("bytes ")?.split_once('/')?;
+  let start = range.split_once('-')?.0.parse().ok()?;
+
+  Some((start, total.parse().ok()))
+}
@@ -0,0 +1,2 @@
+pub mod schedule_manager;
+pub mod scheduler;
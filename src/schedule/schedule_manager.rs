@@ -0,0 +1,244 @@
+use std::sync::Arc;
+
+use archive_database::{database::SharedDatabase, entities::sync_schedules};
+use async_trait::async_trait;
+use chrono::Local;
+use log::error;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use webrs::{api::ApiMethod, request::Request, response::Response};
+
+use crate::{
+  audit::audit_log::{AuditAction, SharedAuditLog},
+  photos::photo_manager::PhotoManager,
+  schedule::scheduler::{Scheduler, SharedScheduler},
+  user::user_manager::SharedUserManager,
+};
+
+pub type SharedScheduleManager = Arc<Mutex<ScheduleManager>>;
+
+/// Lets users set up the cron schedules their library is synced on
+pub struct ScheduleManager {
+  user_manager: SharedUserManager,
+  audit_log: SharedAuditLog,
+  database: SharedDatabase,
+  scheduler: SharedScheduler,
+}
+
+impl ScheduleManager {
+  pub fn new(
+    user_manager: SharedUserManager,
+    audit_log: SharedAuditLog,
+    database: SharedDatabase,
+    scheduler: SharedScheduler,
+  ) -> SharedScheduleManager {
+    Arc::new(Mutex::new(Self { user_manager, audit_log, database, scheduler }))
+  }
+
+  /// A schedule as it is sent to clients, with its filters as an object
+  fn schedule_json(schedule: &sync_schedules::Model) -> Value {
+    let filters = schedule.filters.as_deref().and_then(|f| serde_json::from_str::<Value>(f).ok());
+    json!({
+      "id": schedule.id,
+      "cron": schedule.cron,
+      "enabled": schedule.enabled,
+      "filters": filters,
+      "last_run_at": schedule.last_run_at,
+      "next_run_at": schedule.next_run_at,
+      "created_at": schedule.created_at,
+    })
+  }
+
+  /// Read the json body of a request
+  fn parse_body<'r>(req: &Request<'r>) -> Result<Value, Response<'r>> {
+    serde_json::from_slice(&req.get_data()).map_err(|e| {
+      error!("Failed to parse request json: {}", e);
+      Response::from_json(400, json!({ "error": "Failed to parse request json" })).unwrap()
+    })
+  }
+
+  /// Read the `id` of the schedule a request is about
+  fn parse_id<'r>(json: &Value) -> Result<i32, Response<'r>> {
+    json["id"]
+      .as_i64()
+      .and_then(|i| i32::try_from(i).ok())
+      .ok_or_else(|| Response::from_json(400, json!({ "error": "Missing schedule id" })).unwrap())
+  }
+
+  /// When a schedule runs next
+  ///
+  /// Returns None for disabled schedules, or the response to send instead if
+  /// the cron expression is invalid or never runs outside the quiet hours
+  fn next_run<'r>(&self, cron: &str, enabled: bool) -> Result<Option<i64>, Response<'r>> {
+    let cron = Scheduler::parse_cron(cron).map_err(|e| Response::from_json(400, json!({ "error": e })).unwrap())?;
+    if !enabled {
+      return Ok(None);
+    }
+
+    match self.scheduler.next_run(&cron, Local::now()) {
+      Some(n) => Ok(Some(n)),
+      None =>
+        Err(Response::from_json(400, json!({ "error": "The schedule never runs outside the quiet hours" })).unwrap()),
+    }
+  }
+
+  /// List the sync schedules of a user
+  pub async fn handle_list<'s, 'r>(&'s self, id: i32) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    match self.database.lock().await.get_sync_schedules(id).await {
+      Ok(s) => {
+        let schedules: Vec<Value> = s.iter().map(Self::schedule_json).collect();
+        Some(Response::from_json(200, json!({ "schedules": schedules })).unwrap())
+      }
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+
+  /// Create a sync schedule from the `cron` expression of the body, `enabled`
+  /// and `filters` are optional
+  pub async fn handle_new<'s, 'r>(&'s self, id: i32, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let json = match Self::parse_body(&req) {
+      Ok(j) => j,
+      Err(res) => return Some(res),
+    };
+    let cron = match json["cron"].as_str() {
+      Some(c) => c.trim().to_string(),
+      None => return Some(Response::from_json(400, json!({ "error": "Missing cron expression" })).unwrap()),
+    };
+    let enabled = json["enabled"].as_bool().unwrap_or(true);
+    let filters = match PhotoManager::parse_filters(&req) {
+      Ok(f) => f.map(|f| serde_json::to_string(&f).unwrap_or_default()),
+      Err(res) => return Some(res),
+    };
+    let next_run_at = match self.next_run(&cron, enabled) {
+      Ok(n) => n,
+      Err(res) => return Some(res),
+    };
+
+    let res = self.database.lock().await.new_sync_schedule(id, cron, enabled, filters, next_run_at).await;
+    match res {
+      Ok(s) => {
+        let details = json!({ "schedule": s.id, "cron": s.cron });
+        self.audit_log.record(&req, AuditAction::ScheduleCreated, Some(id), Some(id), Some(details)).await;
+        Some(Response::from_json(201, Self::schedule_json(&s)).unwrap())
+      }
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+
+  /// Change the `cron`, `enabled` or `filters` (null to clear them) of the
+  /// schedule with the `id` of the body
+  pub async fn handle_modify<'s, 'r>(&'s self, id: i32, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let json = match Self::parse_body(&req) {
+      Ok(j) => j,
+      Err(res) => return Some(res),
+    };
+    let schedule_id = match Self::parse_id(&json) {
+      Ok(i) => i,
+      Err(res) => return Some(res),
+    };
+
+    let mut schedule = match self.database.lock().await.get_sync_schedule(id, schedule_id).await {
+      Ok(Some(s)) => s,
+      Ok(None) => return Some(Response::from_json(404, json!({ "error": "Schedule not found" })).unwrap()),
+      Err(e) => return Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    };
+
+    if let Some(cron) = json["cron"].as_str() {
+      schedule.cron = cron.trim().to_string();
+    }
+    if let Some(enabled) = json["enabled"].as_bool() {
+      schedule.enabled = enabled;
+    }
+    if json.get("filters").is_some() {
+      schedule.filters = match PhotoManager::parse_filters(&req) {
+        Ok(f) => f.map(|f| serde_json::to_string(&f).unwrap_or_default()),
+        Err(res) => return Some(res),
+      };
+    }
+    schedule.next_run_at = match self.next_run(&schedule.cron, schedule.enabled) {
+      Ok(n) => n,
+      Err(res) => return Some(res),
+    };
+
+    let res = self.database.lock().await.update_sync_schedule(schedule).await;
+    match res {
+      Ok(s) => {
+        let details = json!({ "schedule": s.id, "cron": s.cron, "enabled": s.enabled });
+        self.audit_log.record(&req, AuditAction::ScheduleModified, Some(id), Some(id), Some(details)).await;
+        Some(Response::from_json(200, Self::schedule_json(&s)).unwrap())
+      }
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+
+  /// Delete the schedule with the `id` of the body
+  pub async fn handle_delete<'s, 'r>(&'s self, id: i32, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let json = match Self::parse_body(&req) {
+      Ok(j) => j,
+      Err(res) => return Some(res),
+    };
+    let schedule_id = match Self::parse_id(&json) {
+      Ok(i) => i,
+      Err(res) => return Some(res),
+    };
+
+    match self.database.lock().await.delete_sync_schedule(id, schedule_id).await {
+      Ok(true) => (),
+      Ok(false) => return Some(Response::from_json(404, json!({ "error": "Schedule not found" })).unwrap()),
+      Err(e) => return Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+
+    let details = json!({ "schedule": schedule_id });
+    self.audit_log.record(&req, AuditAction::ScheduleDeleted, Some(id), Some(id), Some(details)).await;
+    Some(Response::from_json(200, json!({ "deleted": schedule_id })).unwrap())
+  }
+}
+
+#[async_trait]
+impl ApiMethod for ScheduleManager {
+  fn get_endpoint(&self) -> &str {
+    "/schedules"
+  }
+
+  async fn handle_get<'s, 'r>(&'s mut self, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let id = match self.user_manager.lock().await.validate_request(&req).await {
+      Ok(id) => id,
+      Err(_) => return Some(Response::basic(401, "Unauthorized")),
+    };
+    match req.get_endpoint().rsplit("schedules/").next() {
+      Some("list") => self.handle_list(id).await,
+      _ => Some(Response::basic(404, "Not Found")),
+    }
+  }
+
+  async fn handle_post<'s, 'r>(&'s mut self, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let id = match self.user_manager.lock().await.validate_request(&req).await {
+      Ok(id) => id,
+      Err(_) => return Some(Response::basic(401, "Unauthorized")),
+    };
+    match req.get_endpoint().rsplit("/").next() {
+      Some("new") => self.handle_new(id, req).await,
+      Some("modify") => self.handle_modify(id, req).await,
+      Some("delete") => self.handle_delete(id, req).await,
+      _ => Some(Response::basic(404, "Not Found")),
+    }
+  }
+}
@@ -0,0 +1,349 @@
+use std::{sync::Arc, time::Duration};
+
+use archive_config::SchedulerConfig;
+use archive_database::{database::SharedDatabase, entities::sync_schedules};
+use chrono::{DateTime, Local, NaiveTime, TimeDelta, TimeZone};
+use croner::Cron;
+use gphotos_downloader::structs::Filters;
+use log::{debug, error, info, warn};
+use rand::Rng;
+use tokio::time::interval;
+
+use crate::photos::sync::{EnqueueError, JobRunner};
+
+/// How many nights in a row a schedule may fall in the quiet hours before it
+/// is considered to never run
+const MAX_QUIET_SKIPS: usize = 8;
+
+/// Part of the day scheduled syncs do not start in
+#[derive(Debug, Clone)]
+struct QuietHours {
+  start: NaiveTime,
+  end: NaiveTime,
+}
+
+impl QuietHours {
+  fn contains(&self, time: NaiveTime) -> bool {
+    match self.start <= self.end {
+      true => self.start <= time && time < self.end,
+      // Goes past midnight
+      false => time >= self.start || time < self.end,
+    }
+  }
+
+  /// When the quiet hours `time` is in are over
+  fn end_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+    next_at(self.end, time)
+  }
+
+  /// When the next quiet hours start after `time`
+  fn start_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+    next_at(self.start, time)
+  }
+}
+
+/// The first time the clock shows `time` after `after`
+fn next_at(time: NaiveTime, after: DateTime<Local>) -> Option<DateTime<Local>> {
+  let mut date = after.date_naive();
+  if time <= after.time() {
+    date = date.succ_opt()?;
+  }
+  Local.from_local_datetime(&date.and_time(time)).earliest()
+}
+
+/// A random delay of up to `max_secs`, so schedules set to the same time do
+/// not all start at once
+fn jitter(max_secs: u64) -> i64 {
+  match max_secs {
+    0 => 0,
+    j => rand::thread_rng().gen_range(0..=j) as i64,
+  }
+}
+
+/// The next occurrence of `cron` after `after` outside the quiet hours, plus
+/// the jitter. The jitter is cut short when it would run into the quiet hours
+///
+/// Returns None if the schedule never runs
+fn next_occurrence(
+  cron: &Cron,
+  after: DateTime<Local>,
+  quiet_hours: Option<&QuietHours>,
+  jitter_secs: u64,
+) -> Option<DateTime<Local>> {
+  let mut next = cron.find_next_occurrence(&after, false).ok()?;
+  for _ in 0..MAX_QUIET_SKIPS {
+    let quiet = match quiet_hours {
+      Some(q) if q.contains(next.time()) => q,
+      Some(q) => {
+        let room = (q.start_after(next)? - next).num_seconds().max(1) as u64 - 1;
+        return Some(next + TimeDelta::seconds(jitter(jitter_secs.min(room))));
+      }
+      None => return Some(next + TimeDelta::seconds(jitter(jitter_secs))),
+    };
+    next = cron.find_next_occurrence(&quiet.end_after(next)?, true).ok()?;
+  }
+
+  None
+}
+
+pub type SharedScheduler = Arc<Scheduler>;
+
+/// Starts sync jobs on the cron schedules of the users, through the job queue
+pub struct Scheduler {
+  database: SharedDatabase,
+  jobs: JobRunner,
+  quiet_hours: Option<QuietHours>,
+  jitter_secs: u64,
+  tick: Duration,
+}
+
+impl Scheduler {
+  /// Build a scheduler from the `scheduler` config
+  ///
+  /// Returns an error message if a quiet hours time is not `HH:MM`
+  pub fn from_config(
+    config: &SchedulerConfig,
+    database: SharedDatabase,
+    jobs: JobRunner,
+  ) -> Result<SharedScheduler, String> {
+    let parse =
+      |t: &str| NaiveTime::parse_from_str(t, "%H:%M").map_err(|e| format!("Invalid quiet hours time {}: {}", t, e));
+
+    let quiet_hours = match &config.quiet_hours {
+      Some(q) => Some(QuietHours { start: parse(&q.start)?, end: parse(&q.end)? }),
+      None => None,
+    };
+
+    Ok(Arc::new(Self {
+      database,
+      jobs,
+      quiet_hours,
+      jitter_secs: config.jitter_secs,
+      tick: Duration::from_secs(config.tick_secs.max(1)),
+    }))
+  }
+
+  /// Parse a cron expression, with 5 fields (or 6 with seconds)
+  ///
+  /// Returns an error message if it is not valid
+  pub fn parse_cron(expression: &str) -> Result<Cron, String> {
+    Cron::new(expression).with_seconds_optional().parse().map_err(|e| format!("Invalid cron expression: {}", e))
+  }
+
+  /// When a schedule runs next after `after`: the next occurrence of `cron`
+  /// outside the quiet hours, plus the jitter
+  ///
+  /// Returns the unix timestamp or None if the schedule never runs
+  pub fn next_run(&self, cron: &Cron, after: DateTime<Local>) -> Option<i64> {
+    next_occurrence(cron, after, self.quiet_hours.as_ref(), self.jitter_secs).map(|n| n.timestamp())
+  }
+
+  /// Look for due schedules every tick, until the server shuts down
+  pub fn start(self: &Arc<Self>) {
+    let scheduler = Arc::clone(self);
+    tokio::spawn(async move {
+      let mut interval = interval(scheduler.tick);
+      loop {
+        interval.tick().await;
+        if scheduler.jobs.is_stopping() {
+          return;
+        }
+        scheduler.run_due().await;
+      }
+    });
+  }
+
+  /// Start a sync for every schedule that is due, unless it is quiet or the
+  /// account already has one running
+  async fn run_due(&self) {
+    let now = Local::now();
+    let due = {
+      let database = self.database.lock().await;
+      if !database.is_connected() {
+        return;
+      }
+      database.get_due_sync_schedules(now.timestamp()).await
+    };
+    let due = match due {
+      Ok(d) => d,
+      Err(e) => return error!("Failed to fetch due sync schedules: {}", e.get_message()),
+    };
+
+    for schedule in due {
+      let (ran, next) = match &self.quiet_hours {
+        // Still due, it runs once they are over
+        Some(q) if q.contains(now.time()) => {
+          debug!("Holding back sync schedule {} until the quiet hours are over", schedule.id);
+          (false, q.end_after(now).map(|e| e.timestamp() + jitter(self.jitter_secs)))
+        }
+        _ => {
+          let ran = self.run(&schedule).await;
+          let next = match Self::parse_cron(&schedule.cron) {
+            Ok(c) => self.next_run(&c, now),
+            Err(e) => {
+              warn!("Sync schedule {} has a bad cron expression: {}", schedule.id, e);
+              None
+            }
+          };
+          (ran, next)
+        }
+      };
+
+      let last = ran.then_some(now.timestamp());
+      if let Err(e) = self.database.lock().await.set_sync_schedule_run(schedule.id, last, next).await {
+        error!("Failed to save the next run of sync schedule {}: {}", schedule.id, e.get_message());
+      }
+    }
+  }
+
+  /// Queue the sync of a schedule
+  ///
+  /// Returns whether a job was started
+  async fn run(&self, schedule: &sync_schedules::Model) -> bool {
+    let user_id = schedule.user_id;
+    if self.jobs.is_running(user_id) {
+      info!("Skipping sync schedule {}, user {} already has a sync running", schedule.id, user_id);
+      return false;
+    }
+
+    let filters: Option<Filters> = match schedule.filters.as_deref().map(serde_json::from_str).transpose() {
+      Ok(f) => f,
+      Err(e) => {
+        warn!("Sync schedule {} has bad filters: {}", schedule.id, e);
+        return false;
+      }
+    };
+
+    let (owner, token) = match self.jobs.library_of(user_id).await {
+      Ok(l) => l,
+      Err(e) => {
+        warn!("Skipping sync schedule {} of user {}: {}", schedule.id, user_id, e);
+        return false;
+      }
+    };
+
+    match self.jobs.enqueue(owner, token, filters).await {
+      Ok(_) => {
+        info!("Started scheduled sync of user {} (schedule {})", user_id, schedule.id);
+        true
+      }
+      Err(EnqueueError::AlreadyRunning) => {
+        info!("Skipping sync schedule {}, user {} already has a sync running", schedule.id, user_id);
+        false
+      }
+      Err(e) => {
+        warn!("Failed to start sync schedule {} of user {}: {}", schedule.id, user_id, e);
+        false
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn time(t: &str) -> NaiveTime {
+    NaiveTime::parse_from_str(t, "%H:%M").unwrap()
+  }
+
+  fn quiet(start: &str, end: &str) -> QuietHours {
+    QuietHours { start: time(start), end: time(end) }
+  }
+
+  fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+    Local.with_ymd_and_hms(2024, 6, day, hour, minute, 0).unwrap()
+  }
+
+  fn cron(expression: &str) -> Cron {
+    Scheduler::parse_cron(expression).unwrap()
+  }
+
+  #[test]
+  fn quiet_hours_within_a_day() {
+    let q = quiet("01:00", "05:00");
+    assert!(!q.contains(time("00:59")));
+    assert!(q.contains(time("01:00")));
+    assert!(q.contains(time("04:59")));
+    assert!(!q.contains(time("05:00")));
+  }
+
+  #[test]
+  fn quiet_hours_past_midnight() {
+    let q = quiet("22:00", "06:00");
+    assert!(q.contains(time("22:00")));
+    assert!(q.contains(time("23:59")));
+    assert!(q.contains(time("00:00")));
+    assert!(q.contains(time("05:59")));
+    assert!(!q.contains(time("06:00")));
+    assert!(!q.contains(time("21:59")));
+  }
+
+  #[test]
+  fn quiet_hours_end() {
+    let q = quiet("22:00", "06:00");
+    // Before midnight they end the next day, after it the same day
+    assert_eq!(q.end_after(at(10, 23, 0)), Some(at(11, 6, 0)));
+    assert_eq!(q.end_after(at(11, 2, 0)), Some(at(11, 6, 0)));
+
+    let q = quiet("01:00", "05:00");
+    assert_eq!(q.end_after(at(10, 3, 0)), Some(at(10, 5, 0)));
+    assert_eq!(q.start_after(at(10, 0, 30)), Some(at(10, 1, 0)));
+    assert_eq!(q.start_after(at(10, 12, 0)), Some(at(11, 1, 0)));
+  }
+
+  #[test]
+  fn next_occurrence_without_quiet_hours() {
+    assert_eq!(next_occurrence(&cron("0 3 * * *"), at(10, 12, 0), None, 0), Some(at(11, 3, 0)));
+    assert_eq!(next_occurrence(&cron("*/15 * * * *"), at(10, 12, 0), None, 0), Some(at(10, 12, 15)));
+  }
+
+  #[test]
+  fn next_occurrence_skips_quiet_hours() {
+    let q = quiet("22:00", "06:00");
+    assert_eq!(next_occurrence(&cron("0 * * * *"), at(10, 21, 30), Some(&q), 0), Some(at(11, 6, 0)));
+    assert_eq!(next_occurrence(&cron("0 * * * *"), at(10, 12, 30), Some(&q), 0), Some(at(10, 13, 0)));
+  }
+
+  #[test]
+  fn next_occurrence_never_outside_quiet_hours() {
+    let q = quiet("22:00", "06:00");
+    assert_eq!(next_occurrence(&cron("0 3 * * *"), at(10, 12, 0), Some(&q), 0), None);
+  }
+
+  #[test]
+  fn jitter_stays_in_bounds() {
+    for _ in 0..100 {
+      let next = next_occurrence(&cron("0 12 * * *"), at(10, 8, 0), None, 600).unwrap();
+      assert!(next >= at(10, 12, 0) && next <= at(10, 12, 10), "{}", next);
+    }
+  }
+
+  #[test]
+  fn jitter_does_not_cross_into_quiet_hours() {
+    let q = quiet("22:00", "06:00");
+    for _ in 0..100 {
+      // Still runs the same evening, before the quiet hours
+      let next = next_occurrence(&cron("55 21 * * *"), at(10, 12, 0), Some(&q), 600).unwrap();
+      assert!(next >= at(10, 21, 55) && next < at(10, 22, 0), "{}", next);
+    }
+  }
+
+  #[test]
+  fn jitter_does_not_cross_into_quiet_hours_past_midnight() {
+    let q = quiet("00:00", "06:00");
+    for _ in 0..100 {
+      let next = next_occurrence(&cron("55 23 * * *"), at(10, 12, 0), Some(&q), 600).unwrap();
+      assert!(next >= at(10, 23, 55) && next < at(11, 0, 0), "{}", next);
+    }
+  }
+
+  #[test]
+  fn jitter_after_quiet_hours() {
+    let q = quiet("22:00", "06:00");
+    for _ in 0..100 {
+      let next = next_occurrence(&cron("0 * * * *"), at(10, 21, 30), Some(&q), 600).unwrap();
+      assert!(next >= at(11, 6, 0) && next <= at(11, 6, 10), "{}", next);
+    }
+  }
+}
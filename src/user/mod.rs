@@ -1,2 +1,3 @@
-mod oauth;
+pub mod oauth;
+mod password_policy;
 pub mod user_manager;
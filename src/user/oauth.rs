@@ -8,10 +8,13 @@ use std::{
 };
 
 use archive_config::CONFIG;
-use log::info;
+use archive_database::structs::PHOTOS_READONLY_SCOPE;
+use log::{error, info, warn};
 use oauth2::{
-  basic::BasicClient, reqwest::async_http_client, url::Url, AuthUrl, AuthorizationCode, ClientId, ClientSecret,
-  CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, TokenUrl,
+  basic::{BasicClient, BasicErrorResponseType},
+  url::Url,
+  AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointNotSet, EndpointSet, PkceCodeChallenge,
+  PkceCodeVerifier, RedirectUrl, RefreshToken, RequestTokenError, Scope, TokenResponse, TokenUrl,
 };
 use rand::{distributions::Alphanumeric, Rng};
 use serde::Deserialize;
@@ -19,6 +22,15 @@ use webrs::{request::Request, response::Response};
 
 use crate::user::user_manager::UserManagerError;
 
+const REQUESTED_SCOPES: [&str; 2] = [PHOTOS_READONLY_SCOPE, "https://www.googleapis.com/auth/userinfo.profile"];
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OAuthClientType {
+  #[default]
+  Installed,
+  Web,
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
 pub struct OAuthParameters {
@@ -28,12 +40,17 @@ pub struct OAuthParameters {
   token_uri: String,
   auth_provider_x509_cert_url: String,
   client_secret: String,
+  #[serde(default)]
   redirect_uris: Vec<String>,
+  #[serde(skip)]
+  client_type: OAuthClientType,
 }
 
 #[derive(Deserialize, Debug)]
-struct OAuthWrapper {
-  installed: OAuthParameters,
+#[serde(rename_all = "lowercase")]
+enum OAuthWrapper {
+  Installed(OAuthParameters),
+  Web(OAuthParameters),
 }
 
 impl OAuthParameters {
@@ -42,31 +59,153 @@ impl OAuthParameters {
     let creds_contents = read_to_string(creds_file_path)?;
     let oauth: OAuthWrapper = serde_json::from_str(&creds_contents)?;
 
-    Ok(oauth.installed)
+    Ok(match oauth {
+      OAuthWrapper::Installed(p) => Self { client_type: OAuthClientType::Installed, ..p },
+      OAuthWrapper::Web(p) => Self { client_type: OAuthClientType::Web, ..p },
+    })
   }
+
+  /// Load the client secret from `server.client_secret_path` and check that
+  /// google will accept our redirect URI for it
+  pub fn load() -> Result<Self, String> {
+    let path = &CONFIG.server.client_secret_path;
+    let params = Self::parse(path).map_err(|e| format!("Failed to load OAuth client secret from {}: {}", path, e))?;
+    params
+      .check_redirect_uri(&OAuthFlow::redirect_url())
+      .map_err(|e| format!("OAuth redirect URL check failed: {}", e))?;
+    Ok(params)
+  }
+
+  #[inline]
+  pub fn get_client_type(&self) -> OAuthClientType {
+    self.client_type
+  }
+
+  /// Check that google will accept `redirect_uri` for this client.
+  ///
+  /// Web clients need an exact match in `redirect_uris`, installed clients only
+  /// allow loopback redirects (any port)
+  pub fn check_redirect_uri(&self, redirect_uri: &str) -> Result<(), String> {
+    let url = Url::parse(redirect_uri).map_err(|e| format!("Invalid redirect URI '{}': {}", redirect_uri, e))?;
+
+    match self.client_type {
+      OAuthClientType::Web => {
+        if self.redirect_uris.iter().any(|u| u == redirect_uri) {
+          Ok(())
+        } else {
+          Err(format!(
+            "Redirect URI '{}' is not listed in the client secret file (listed: {:?})",
+            redirect_uri, self.redirect_uris
+          ))
+        }
+      }
+      OAuthClientType::Installed => match url.host_str() {
+        Some("localhost" | "127.0.0.1" | "[::1]") if url.scheme() == "http" => Ok(()),
+        _ => Err(format!(
+          "Installed OAuth clients only support loopback redirects, got '{}'. Use a web client for public deployments",
+          redirect_uri
+        )),
+      },
+    }
+  }
+}
+
+/// A client with the auth, token and redirect URLs set
+type OAuthClient = BasicClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet>;
+
+/// A client with only the token URL set, for refreshing tokens
+type RefreshClient = BasicClient<EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet>;
+
+/// The result of a successful code exchange or token refresh
+pub struct GrantedToken {
+  pub access_token: String,
+  /// Empty for refreshed tokens
+  pub scopes: Vec<String>,
+  /// Google only sends one for the code exchange when offline access was
+  /// asked for
+  pub refresh_token: Option<String>,
+  /// Unix time the access token stops working
+  pub expires_at: Option<i64>,
+}
+
+impl GrantedToken {
+  fn from_response<R: TokenResponse>(token_res: &R, scopes: Vec<String>) -> Self {
+    Self {
+      access_token: token_res.access_token().secret().to_string(),
+      scopes,
+      refresh_token: token_res.refresh_token().map(|t| t.secret().to_string()),
+      expires_at: token_res.expires_in().map(|d| chrono::Utc::now().timestamp() + d.as_secs() as i64),
+    }
+  }
+}
+
+/// Get a new access token with a `refresh_token` stored when the account was
+/// linked, using an `http_client` that does not follow redirects
+///
+/// Returns an AuthenticationError if google no longer accepts the refresh
+/// token (the user revoked the access) or an OAuthError if the refresh failed
+pub async fn refresh_access_token(
+  http_client: &reqwest::Client,
+  refresh_token: &str,
+) -> Result<GrantedToken, UserManagerError> {
+  let oauth_params = OAuthParameters::parse(&CONFIG.server.client_secret_path)
+    .map_err(|e| UserManagerError::OAuthError(format!("Failed to load OAuth client secret: {}", e)))?;
+  let token_url = Url::from_str(&oauth_params.token_uri)
+    .map_err(|e| UserManagerError::OAuthError(format!("Invalid token URI: {}", e)))?;
+  let oauth_client: RefreshClient = BasicClient::new(ClientId::new(oauth_params.client_id))
+    .set_client_secret(ClientSecret::new(oauth_params.client_secret))
+    .set_token_uri(TokenUrl::from_url(token_url));
+
+  let token_res = oauth_client
+    .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+    .request_async(http_client)
+    .await
+    .map_err(|e| match e {
+      RequestTokenError::ServerResponse(r) if *r.error() == BasicErrorResponseType::InvalidGrant =>
+        UserManagerError::AuthenticationError("Google no longer accepts the refresh token".to_owned()),
+      e => {
+        error!("Failed to refresh OAuth token: {}", e);
+        UserManagerError::OAuthError("Failed to refresh OAuth token".to_owned())
+      }
+    })?;
+
+  Ok(GrantedToken::from_response(&token_res, Vec::new()))
 }
 
 #[derive(Clone)]
 pub struct OAuthFlow {
   user_id: i32,
-  oauth_client: BasicClient,
+  session_token: String,
+  session_binding: String,
+  oauth_client: OAuthClient,
   pkce_verifier: Arc<Mutex<Option<PkceCodeVerifier>>>,
 }
 
 impl OAuthFlow {
-  pub fn new(user_id: i32) -> Result<Self, Box<dyn Error>> {
+  /// Create a new flow for the session `session_token` of user `user_id`
+  pub fn new<S: ToString>(user_id: i32, session_token: S) -> Result<Self, Box<dyn Error>> {
     let oauth_params = OAuthParameters::parse(&CONFIG.server.client_secret_path)?;
-    let oauth_client = BasicClient::new(
-      ClientId::new(oauth_params.client_id),
-      Some(ClientSecret::new(oauth_params.client_secret)),
-      AuthUrl::from_url(Url::from_str(&oauth_params.auth_uri).unwrap()),
-      Some(TokenUrl::from_url(Url::from_str(&oauth_params.token_uri).unwrap())),
-    )
-    .set_redirect_uri(
-      RedirectUrl::new(format!("http://localhost:{}/api/users/oauth/callback", CONFIG.server.port)).unwrap(),
-    );
-
-    Ok(Self { user_id, oauth_client, pkce_verifier: Arc::new(Mutex::new(None)) })
+    let oauth_client = BasicClient::new(ClientId::new(oauth_params.client_id))
+      .set_client_secret(ClientSecret::new(oauth_params.client_secret))
+      .set_auth_uri(AuthUrl::from_url(Url::from_str(&oauth_params.auth_uri)?))
+      .set_token_uri(TokenUrl::from_url(Url::from_str(&oauth_params.token_uri)?))
+      .set_redirect_uri(RedirectUrl::new(Self::redirect_url())?);
+
+    let session_binding: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+
+    Ok(Self {
+      user_id,
+      session_token: session_token.to_string(),
+      session_binding,
+      oauth_client,
+      pkce_verifier: Arc::new(Mutex::new(None)),
+    })
+  }
+
+  /// The callback URL google redirects back to, built from the public URL
+  #[inline]
+  pub fn redirect_url() -> String {
+    format!("{}/api/users/oauth/callback", CONFIG.server.get_public_url())
   }
 
   #[inline]
@@ -74,6 +213,18 @@ impl OAuthFlow {
     self.user_id
   }
 
+  #[inline]
+  pub fn get_session_token(&self) -> &str {
+    &self.session_token
+  }
+
+  /// Random value given to the browser as a cookie when the flow starts, the
+  /// callback has to present it
+  #[inline]
+  pub fn get_session_binding(&self) -> &str {
+    &self.session_binding
+  }
+
   pub fn generate_auth_url(&mut self) -> (String, String) {
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
     let state: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
@@ -83,11 +234,12 @@ impl OAuthFlow {
     let auth_url = self
       .oauth_client
       .authorize_url(|| csrf_token)
-      .add_scopes([
-        Scope::new("https://www.googleapis.com/auth/photoslibrary.readonly".to_string()),
-        Scope::new("https://www.googleapis.com/auth/userinfo.profile".to_string()),
-      ])
+      .add_scopes(REQUESTED_SCOPES.map(|s| Scope::new(s.to_string())))
       .set_pkce_challenge(pkce_challenge)
+      // A refresh token, so scheduled syncs keep working once the access token
+      // expires. Google only hands it out on the consent screen
+      .add_extra_param("access_type", "offline")
+      .add_extra_param("prompt", "consent")
       .url();
 
     if let Ok(mut v) = self.pkce_verifier.lock() {
@@ -97,28 +249,53 @@ impl OAuthFlow {
     (auth_url.0.to_string(), state)
   }
 
-  pub async fn process(&mut self, code: String) -> Result<String, UserManagerError> {
+  /// Exchange the code google sent to the callback for an access token, using
+  /// an `http_client` that does not follow redirects
+  pub async fn process(
+    &mut self,
+    http_client: &reqwest::Client,
+    code: String,
+  ) -> Result<GrantedToken, UserManagerError> {
     let auth_code = AuthorizationCode::new(code);
 
     let pkce_verifier = match self.pkce_verifier.lock().unwrap().take() {
       Some(v) => v,
-      None => return Err(UserManagerError::AuthenticationError("Internal Server Error".to_owned())),
+      None => return Err(UserManagerError::OAuthError("OAuth flow was already used".to_owned())),
     };
 
     let token_res = self
       .oauth_client
       .exchange_code(auth_code)
       .set_pkce_verifier(pkce_verifier)
-      .request_async(async_http_client)
+      .request_async(http_client)
       .await
-      .map_err(|_| UserManagerError::AuthenticationError("Internal OAuth error".to_owned()))?;
+      .map_err(|e| {
+        error!("Failed to exchange OAuth code: {}", e);
+        UserManagerError::OAuthError("Failed to exchange OAuth code".to_owned())
+      })?;
 
-    let access_token = token_res.access_token().secret().to_string();
+    let access_token = token_res.access_token().secret();
     let hidden = {
       let (f, l) = access_token.split_at(4);
       format!("{}{}", f, "*".repeat(l.len()))
     };
     info!("Access token: {}", hidden);
-    Ok(access_token)
+
+    let scopes: Vec<String> = match token_res.scopes() {
+      Some(s) => s.iter().map(|s| (**s).clone()).collect(),
+      // A response without a scope means everything requested was granted
+      None => REQUESTED_SCOPES.iter().map(|s| s.to_string()).collect(),
+    };
+
+    let missing: Vec<&str> = REQUESTED_SCOPES.into_iter().filter(|r| !scopes.iter().any(|s| s == r)).collect();
+    if !missing.is_empty() {
+      warn!("User {} did not grant the scopes: {:?}", self.user_id, missing);
+    }
+
+    if token_res.refresh_token().is_none() {
+      warn!("Google did not send a refresh token for user {}, scheduled syncs will stop working", self.user_id);
+    }
+
+    Ok(GrantedToken::from_response(&token_res, scopes))
   }
 }
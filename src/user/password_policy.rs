@@ -0,0 +1,176 @@
+use std::{collections::HashSet, fs::read_to_string};
+
+use archive_config::PasswordPolicyConfig;
+use log::{error, info};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+const MIN_USERNAME_LENGTH: usize = 3;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PolicyViolation {
+  rule: &'static str,
+  message: String,
+}
+
+impl PolicyViolation {
+  fn new<S: ToString>(rule: &'static str, message: S) -> Self {
+    Self { rule, message: message.to_string() }
+  }
+}
+
+pub struct PasswordPolicy {
+  config: PasswordPolicyConfig,
+  blocklist: HashSet<String>,
+}
+
+impl PasswordPolicy {
+  pub fn new(config: PasswordPolicyConfig) -> Self {
+    let blocklist = match &config.blocklist_path {
+      Some(path) => match read_to_string(path) {
+        Ok(c) => {
+          let list: HashSet<String> =
+            c.lines().map(|l| l.trim().to_lowercase()).filter(|l| !l.is_empty() && !l.starts_with('#')).collect();
+          info!("Loaded {} blocked passwords from {}", list.len(), path);
+          list
+        }
+        Err(e) => {
+          error!("Failed to read password blocklist {}: {}", path, e);
+          HashSet::new()
+        }
+      },
+      None => HashSet::new(),
+    };
+
+    Self { config, blocklist }
+  }
+
+  /// Check a username, returns every rule it breaks
+  pub fn validate_username(&self, username: &str) -> Vec<PolicyViolation> {
+    let mut violations = Vec::new();
+    if username.chars().count() < MIN_USERNAME_LENGTH {
+      violations.push(PolicyViolation::new(
+        "username_min_length",
+        format!("Username must be at least {} characters", MIN_USERNAME_LENGTH),
+      ));
+    }
+
+    violations
+  }
+
+  /// Check a password for `username`, returns every rule it breaks
+  pub fn validate_password(&self, username: &str, password: &str) -> Vec<PolicyViolation> {
+    let mut violations = Vec::new();
+
+    if password.chars().count() < self.config.min_length {
+      violations.push(PolicyViolation::new(
+        "min_length",
+        format!("Password must be at least {} characters", self.config.min_length),
+      ));
+    }
+
+    if self.config.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+      violations.push(PolicyViolation::new("lowercase", "Password must contain a lowercase letter"));
+    }
+
+    if self.config.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+      violations.push(PolicyViolation::new("uppercase", "Password must contain an uppercase letter"));
+    }
+
+    if self.config.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+      violations.push(PolicyViolation::new("digit", "Password must contain a digit"));
+    }
+
+    if self.config.require_symbol && !password.chars().any(|c| !c.is_alphanumeric() && !c.is_whitespace()) {
+      violations.push(PolicyViolation::new("symbol", "Password must contain a symbol"));
+    }
+
+    if password.eq_ignore_ascii_case(username) {
+      violations.push(PolicyViolation::new("not_username", "Password can not be the username"));
+    }
+
+    if self.blocklist.contains(&password.to_lowercase()) {
+      violations.push(PolicyViolation::new("blocklist", "Password is too common"));
+    }
+
+    violations
+  }
+
+  /// Error json for a list of violations
+  pub fn violations_json(violations: &[PolicyViolation]) -> Value {
+    json!({
+      "error": violations.iter().map(|v| v.message.clone()).collect::<Vec<_>>().join(", "),
+      "violations": violations
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn strict() -> PasswordPolicy {
+    let config = PasswordPolicyConfig {
+      min_length: 10,
+      require_lowercase: true,
+      require_uppercase: true,
+      require_digit: true,
+      require_symbol: true,
+      blocklist_path: None,
+    };
+    PasswordPolicy { config, blocklist: HashSet::from(["correcthorse1!a".to_string()]) }
+  }
+
+  fn rules(violations: &[PolicyViolation]) -> Vec<&'static str> {
+    violations.iter().map(|v| v.rule).collect()
+  }
+
+  #[test]
+  fn username_length() {
+    let policy = PasswordPolicy::new(PasswordPolicyConfig::default());
+    assert_eq!(rules(&policy.validate_username("ab")), ["username_min_length"]);
+    assert!(policy.validate_username("abc").is_empty());
+    // Counted in characters, not bytes
+    assert_eq!(rules(&policy.validate_username("éé")), ["username_min_length"]);
+  }
+
+  #[test]
+  fn default_policy_only_checks_length() {
+    let policy = PasswordPolicy::new(PasswordPolicyConfig::default());
+    assert!(policy.validate_password("alice", "aaaaaaaa").is_empty());
+    assert_eq!(rules(&policy.validate_password("alice", "aaaaaaa")), ["min_length"]);
+  }
+
+  #[test]
+  fn valid_password() {
+    assert!(strict().validate_password("alice", "Tr0ub4dor&3x").is_empty());
+  }
+
+  #[test]
+  fn each_rule() {
+    let policy = strict();
+    assert_eq!(rules(&policy.validate_password("alice", "Tr0ub&3x")), ["min_length"]);
+    assert_eq!(rules(&policy.validate_password("alice", "TR0UB4DOR&3X")), ["lowercase"]);
+    assert_eq!(rules(&policy.validate_password("alice", "tr0ub4dor&3x")), ["uppercase"]);
+    assert_eq!(rules(&policy.validate_password("alice", "Troubador&xx")), ["digit"]);
+    assert_eq!(rules(&policy.validate_password("alice", "Tr0ub4dor3xx")), ["symbol"]);
+  }
+
+  #[test]
+  fn every_violation_is_listed() {
+    assert_eq!(rules(&strict().validate_password("alice", "abc")), ["min_length", "uppercase", "digit", "symbol"]);
+  }
+
+  #[test]
+  fn username_in_password() {
+    let policy = PasswordPolicy::new(PasswordPolicyConfig::default());
+    assert_eq!(rules(&policy.validate_password("Administrator", "administrator")), ["not_username"]);
+    assert!(policy.validate_password("alice", "alice-in-wonderland").is_empty());
+  }
+
+  #[test]
+  fn blocklist_ignores_case() {
+    let policy = strict();
+    assert_eq!(rules(&policy.validate_password("alice", "CorrectHorse1!A")), ["blocklist"]);
+  }
+}
@@ -3,39 +3,49 @@ use std::{
   borrow::BorrowMut,
   collections::BTreeMap,
   error::Error,
+  str::FromStr,
   sync::Arc,
   time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use archive_config::CONFIG;
+use archive_config::{RegistrationMode, CONFIG};
 use archive_database::{
   database::SharedDatabase,
   entities::users,
-  structs::{GUser, User},
+  structs::{GUser, Registration, Role, User},
 };
 use async_trait::async_trait;
 use bcrypt::{hash, verify, BcryptError, DEFAULT_COST};
 use dashmap::DashMap;
+use gphotos_downloader::http::HttpClient;
 use hmac::{Hmac, Mac};
 use jwt::{token::Signed, Header, SignWithKey, Token, VerifyWithKey};
-use log::{debug, error, trace};
-use reqwest::Client;
+use log::{debug, error, info, trace};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_json::{from_str, json, Value};
 use sha2::Sha256;
 use tokio::{sync::Mutex, time::interval};
 use webrs::{api::ApiMethod, request::Request, response::Response, server::WebrsHttp};
 
-use super::oauth::OAuthFlow;
+use crate::audit::audit_log::{AuditAction, SharedAuditLog};
+
+use super::{
+  oauth::{OAuthFlow, OAuthParameters},
+  password_policy::PasswordPolicy,
+};
 
 pub type SharedUserManager = Arc<Mutex<UserManager>>;
 
 const AUTH_HEADER: &str = "authorization";
+const OAUTH_BINDING_COOKIE: &str = "oauth_binding";
 
 #[derive(Debug)]
 pub enum UserManagerError {
   TokenError(String),
   AuthenticationError(String),
+  OAuthError(String),
+  DatabaseError(String),
 }
 
 impl fmt::Display for UserManagerError {
@@ -43,6 +53,8 @@ impl fmt::Display for UserManagerError {
     match &self {
       Self::AuthenticationError(m) => write!(f, "Authentication Error: {}", m),
       Self::TokenError(m) => write!(f, "Token Error: {}", m),
+      Self::OAuthError(m) => write!(f, "OAuth Error: {}", m),
+      Self::DatabaseError(m) => write!(f, "Database Error: {}", m),
     }
   }
 }
@@ -50,7 +62,7 @@ impl fmt::Display for UserManagerError {
 impl Error for UserManagerError {
   fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
     match self {
-      Self::TokenError(_) | Self::AuthenticationError(_) => None,
+      Self::TokenError(_) | Self::AuthenticationError(_) | Self::OAuthError(_) | Self::DatabaseError(_) => None,
     }
   }
 }
@@ -58,7 +70,7 @@ impl Error for UserManagerError {
 impl UserManagerError {
   pub fn get_message(&self) -> String {
     match self {
-      Self::AuthenticationError(m) | Self::TokenError(m) => m.clone(),
+      Self::AuthenticationError(m) | Self::TokenError(m) | Self::OAuthError(m) | Self::DatabaseError(m) => m.clone(),
     }
   }
 
@@ -80,12 +92,27 @@ pub struct UserManager {
   http_server: Arc<WebrsHttp>,
   active_users: DashMap<i32, User>,
   oauth_flows: DashMap<String, (i32, OAuthFlow, u64)>,
+  password_policy: Arc<PasswordPolicy>,
+  audit_log: SharedAuditLog,
+  http: HttpClient,
 }
 
 impl UserManager {
-  pub fn new(http_server: Arc<WebrsHttp>, database: SharedDatabase) -> SharedUserManager {
-    let user_manager =
-      Arc::new(Mutex::new(Self { http_server, database, active_users: DashMap::new(), oauth_flows: DashMap::new() }));
+  pub fn new(
+    http_server: Arc<WebrsHttp>,
+    database: SharedDatabase,
+    audit_log: SharedAuditLog,
+    http: HttpClient,
+  ) -> SharedUserManager {
+    let user_manager = Arc::new(Mutex::new(Self {
+      http_server,
+      database,
+      active_users: DashMap::new(),
+      oauth_flows: DashMap::new(),
+      password_policy: Arc::new(PasswordPolicy::new(CONFIG.auth.password_policy.clone())),
+      audit_log,
+      http,
+    }));
 
     let cleanup = Arc::clone(&user_manager);
     tokio::spawn(async move {
@@ -110,7 +137,14 @@ impl UserManager {
     user_manager
   }
 
-  pub async fn init(&self) {}
+  /// Check the OAuth client secret against the redirect URL
+  ///
+  /// Returns an error message if google would refuse every login flow
+  pub async fn init(&self) -> Result<(), String> {
+    let params = OAuthParameters::load()?;
+    info!("Using OAuth redirect URL {} ({:?} client)", OAuthFlow::redirect_url(), params.get_client_type());
+    Ok(())
+  }
 
   #[inline]
   pub fn get_active_users(&self) -> &DashMap<i32, User> {
@@ -176,7 +210,7 @@ impl UserManager {
     }
   }
 
-  pub async fn validate_request<'s, 'r>(&'s self, req: &Request<'r>) -> Result<i32, UserManagerError> {
+  pub(crate) fn get_bearer_token(req: &Request) -> Result<String, UserManagerError> {
     let headers = req.get_headers();
     let auth_header =
       headers.get(AUTH_HEADER).ok_or(UserManagerError::AuthenticationError("No 'authorization' header".to_owned()))?;
@@ -185,7 +219,18 @@ impl UserManager {
       return Err(UserManagerError::AuthenticationError("Invalid header format".to_owned()));
     }
 
-    let (valid, id) = self.validate_token(&auth_header[7..])?;
+    Ok(auth_header[7..].to_string())
+  }
+
+  fn get_cookie(req: &Request, name: &str) -> Option<String> {
+    let cookies = req.get_headers().get("cookie")?.to_string();
+    cookies.split(';').filter_map(|c| c.trim().split_once('=')).find(|(k, _)| *k == name).map(|(_, v)| v.to_string())
+  }
+
+  pub async fn validate_request<'s, 'r>(&'s self, req: &Request<'r>) -> Result<i32, UserManagerError> {
+    let token = Self::get_bearer_token(req)?;
+
+    let (valid, id) = self.validate_token(&token)?;
     if !valid {
       return Err(UserManagerError::AuthenticationError("Invalid token".to_owned()));
     }
@@ -213,33 +258,54 @@ impl UserManager {
     let username: &str = json["username"].as_str()?;
     let password: &str = json["password"].as_str()?;
 
-    if username.len() < 3 {
-      return Some(
-        Response::from_json(
-          400,
-          json!({
-            "error": "Username is too short"
-          }),
-        )
-        .unwrap(),
-      );
+    let mut violations = self.password_policy.validate_username(username);
+    violations.extend(self.password_policy.validate_password(username, password));
+    if !violations.is_empty() {
+      return Some(Response::from_json(400, PasswordPolicy::violations_json(&violations)).unwrap());
     }
 
-    if password.len() < 8 {
-      return Some(
-        Response::from_json(
-          400,
-          json!({
-            "error": "Password is too short"
-          }),
-        )
-        .unwrap(),
-      );
+    let database = self.database.lock().await;
+    let user_count = match database.count_users().await {
+      Ok(c) => c,
+      Err(e) => return Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    };
+
+    let mut user = User::new(username, &Self::hash_password(password));
+    let mut invite: Option<&str> = None;
+
+    // The first account can always be created and becomes the admin
+    if user_count == 0 {
+      user.set_role(Role::Admin);
+    } else {
+      match CONFIG.auth.registration {
+        RegistrationMode::Open => (),
+        RegistrationMode::Closed => {
+          return Some(Response::from_json(403, json!({ "error": "Registration is disabled" })).unwrap());
+        }
+        RegistrationMode::Invite => match json["invite"].as_str() {
+          Some(c) => invite = Some(c),
+          None => return Some(Response::from_json(403, json!({ "error": "An invite code is required" })).unwrap()),
+        },
+      }
     }
 
-    match self.database.lock().await.new_user(User::new(username, &Self::hash_password(password))).await {
-      Ok(_) => {
+    let res = match invite {
+      Some(code) => database.new_user_with_invite(user, code).await,
+      None => database.new_user(user).await,
+    };
+    drop(database);
+
+    match res {
+      Ok(Registration::InvalidInvite) =>
+        Some(Response::from_json(403, json!({ "error": "Invalid or expired invite code" })).unwrap()),
+      Ok(Registration::UsernameTaken) =>
+        Some(Response::from_json(409, json!({ "error": format!("User {} already exists", username) })).unwrap()),
+      Ok(Registration::Created) => {
         debug!("Added new user");
+        self
+          .audit_log
+          .record(&req, AuditAction::UserCreated, None, None, Some(json!({ "username": username, "invite": invite })))
+          .await;
         Some(
           Response::from_json(
             200,
@@ -256,7 +322,7 @@ impl UserManager {
           Response::from_json(
             500,
             json!({
-              "error": format!("User {} already exists", username)
+              "error": e.get_message()
             }),
           )
           .unwrap(),
@@ -265,6 +331,78 @@ impl UserManager {
     }
   }
 
+  /// Make sure user `id` is logged in and has at least `role`
+  pub fn require_role(&self, id: i32, role: Role) -> Result<(), UserManagerError> {
+    match self.active_users.get(&id) {
+      Some(u) if u.get_role() >= role => Ok(()),
+      Some(_) => Err(UserManagerError::AuthenticationError("Insufficient permissions".to_owned())),
+      None => Err(UserManagerError::AuthenticationError("User is not logged in or does not exist".to_owned())),
+    }
+  }
+
+  /// Get the id of the user making the request if they are an admin
+  ///
+  /// Returns the response to send instead if they are not logged in (401) or
+  /// not an admin (403)
+  async fn require_admin<'r>(&self, req: &Request<'r>) -> Result<i32, Response<'r>> {
+    let id = self.validate_request(req).await.map_err(|e| Response::from_json(401, e.to_json()).unwrap())?;
+    self.require_role(id, Role::Admin).map_err(|e| Response::from_json(403, e.to_json()).unwrap())?;
+    Ok(id)
+  }
+
+  async fn handle_new_invite<'s, 'r>(&'s self, req: Request<'r>) -> Option<Response<'r>> {
+    let id = match self.require_admin(&req).await {
+      Ok(id) => id,
+      Err(res) => return Some(res),
+    };
+
+    let json: Value = serde_json::from_slice(&req.get_data()).unwrap_or(json!({}));
+
+    let role = match json["role"].as_str().map(Role::from_str).unwrap_or(Ok(Role::Member)) {
+      Ok(r) => r,
+      Err(e) => return Some(Response::from_json(400, json!({ "error": e.get_message() })).unwrap()),
+    };
+    let expires_in = json["expires_in"].as_u64().unwrap_or(CONFIG.auth.invite_expiry_secs);
+    let expires_at = chrono::Utc::now().timestamp() + expires_in as i64;
+
+    let code: String = rand::thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+
+    match self.database.lock().await.new_invite(code, role, id, expires_at).await {
+      Ok(invite) => {
+        debug!("User {} created a {} invite", id, role);
+        self
+          .audit_log
+          .record(
+            &req,
+            AuditAction::InviteCreated,
+            Some(id),
+            None,
+            Some(json!({ "role": invite.role, "expires_at": invite.expires_at })),
+          )
+          .await;
+        Some(
+          Response::from_json(
+            200,
+            json!({ "code": invite.code, "role": invite.role, "expires_at": invite.expires_at }),
+          )
+          .unwrap(),
+        )
+      }
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+
+  async fn handle_list_invites<'s, 'r>(&'s self, req: Request<'r>) -> Option<Response<'r>> {
+    if let Err(res) = self.require_admin(&req).await {
+      return Some(res);
+    }
+
+    match self.database.lock().await.get_invites().await {
+      Ok(invites) => Some(Response::from_json(200, json!({ "invites": invites })).unwrap()),
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+
   async fn handle_user_login<'s, 'r>(&'s mut self, req: Request<'r>) -> Option<Response<'r>> {
     let json: Value = match serde_json::from_slice(&req.get_data()) {
       Ok(j) => j,
@@ -285,12 +423,22 @@ impl UserManager {
     let username = json["username"].as_str()?;
     let password = json["password"].as_str()?;
 
-    if let Ok(mut u) = self.database.lock().await.get_user_by(users::Column::Username, username).await {
+    let database = self.database.lock().await;
+    let user = database.get_user_by(users::Column::Username, username).await;
+    if let Ok(mut u) = user {
       if let Ok(true) = UserManager::verify_password(password, &u.get_password_hash()) {
         let session_token = Self::generate_session_token(u.clone());
         let session_token = session_token.unwrap();
         u.borrow_mut().set_session_token(session_token.as_str());
 
+        match database.get_google_account(u.get_id()).await {
+          Ok(Some(guser)) => u.set_guser(guser),
+          Ok(None) => (),
+          Err(e) => error!("Failed to load google account for '{}': {}", u.get_username(), e),
+        }
+
+        drop(database);
+        self.audit_log.record(&req, AuditAction::Login, Some(u.get_id()), Some(u.get_id()), None).await;
         self.active_users.insert(u.get_id(), u);
         return Some(
           Response::from_json(
@@ -304,6 +452,9 @@ impl UserManager {
       }
     }
 
+    drop(database);
+    self.audit_log.record(&req, AuditAction::LoginFailed, None, None, Some(json!({ "username": username }))).await;
+
     return Some(
       Response::from_json(
         401,
@@ -325,7 +476,10 @@ impl UserManager {
 
     if let Some((_, u)) = self.active_users.remove(&id) {
       trace!("User '{}' logged out", u.get_username());
+      let session_token = u.get_session_token();
+      self.oauth_flows.retain(|_, (_, flow, _)| Some(flow.get_session_token().to_string()) != session_token);
       drop(u);
+      self.audit_log.record(&req, AuditAction::Logout, Some(id), Some(id), None).await;
       return Some(Response::from_json(200, json!({ "success": "Successfully logged out" })).unwrap());
     };
 
@@ -344,12 +498,117 @@ impl UserManager {
   }
 
   // These to need auth
+  /// Delete your own account (needs the password) or, as an admin, the
+  /// account with `id`
   async fn handle_delete_user<'s, 'r>(&'s self, req: Request<'r>) -> Option<Response<'r>> {
-    todo!()
+    let id = match self.validate_request(&req).await {
+      Ok(id) => id,
+      Err(e) => return Some(Response::from_json(401, e.to_json()).unwrap()),
+    };
+
+    let json: Value = serde_json::from_slice(&req.get_data()).unwrap_or(json!({}));
+    let target = json["id"].as_i64().map(|t| t as i32).unwrap_or(id);
+
+    if target == id {
+      let user = self.active_users.get(&id)?.clone();
+      let password = json["password"].as_str().unwrap_or_default();
+      if !matches!(UserManager::verify_password(password, &user.get_password_hash()), Ok(true)) {
+        return Some(Response::from_json(401, json!({ "error": "Invalid password" })).unwrap());
+      }
+    } else if let Err(e) = self.require_role(id, Role::Admin) {
+      return Some(Response::from_json(403, e.to_json()).unwrap());
+    }
+
+    if let Err(e) = self.database.lock().await.delate_user(target).await {
+      return Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap());
+    }
+
+    if let Some((_, u)) = self.active_users.remove(&target) {
+      let session_token = u.get_session_token();
+      self.oauth_flows.retain(|_, (_, flow, _)| Some(flow.get_session_token().to_string()) != session_token);
+    }
+
+    debug!("User {} deleted user {}", id, target);
+    self.audit_log.record(&req, AuditAction::UserDeleted, Some(id), Some(target), None).await;
+    Some(Response::from_json(200, json!({ "success": "User deleted" })).unwrap())
   }
 
   async fn handle_modify_user<'s, 'r>(&'s self, req: Request<'r>) -> Option<Response<'r>> {
-    todo!()
+    let id = match self.validate_request(&req).await {
+      Ok(id) => id,
+      Err(e) => return Some(Response::from_json(401, e.to_json()).unwrap()),
+    };
+
+    let json: Value = match serde_json::from_slice(&req.get_data()) {
+      Ok(j) => j,
+      Err(_) => return Some(Response::from_json(400, json!({ "error": "Failed to parse request json" })).unwrap()),
+    };
+
+    let password = json["password"].as_str()?;
+    let new_password = json["new_password"].as_str()?;
+
+    let user = self.active_users.get(&id)?.clone();
+    if !matches!(UserManager::verify_password(password, &user.get_password_hash()), Ok(true)) {
+      return Some(Response::from_json(401, json!({ "error": "Invalid password" })).unwrap());
+    }
+
+    let res = self.set_password(user.get_id(), new_password).await;
+    if res.as_ref().is_some_and(|r| r.get_code() == 200) {
+      self.audit_log.record(&req, AuditAction::PasswordChanged, Some(id), Some(id), None).await;
+    }
+
+    res
+  }
+
+  async fn handle_reset_password<'s, 'r>(&'s self, req: Request<'r>) -> Option<Response<'r>> {
+    let id = match self.require_admin(&req).await {
+      Ok(id) => id,
+      Err(res) => return Some(res),
+    };
+
+    let json: Value = match serde_json::from_slice(&req.get_data()) {
+      Ok(j) => j,
+      Err(_) => return Some(Response::from_json(400, json!({ "error": "Failed to parse request json" })).unwrap()),
+    };
+
+    let target = json["id"].as_i64()? as i32;
+    let new_password = json["new_password"].as_str()?;
+
+    let res = self.set_password(target, new_password).await;
+
+    // A reset logs the user out everywhere
+    if res.as_ref().is_some_and(|r| r.get_code() == 200) {
+      self.active_users.remove(&target);
+      self.audit_log.record(&req, AuditAction::PasswordReset, Some(id), Some(target), None).await;
+    }
+
+    res
+  }
+
+  /// Check `new_password` against the password policy and save it for user `id`
+  async fn set_password<'r>(&self, id: i32, new_password: &str) -> Option<Response<'r>> {
+    let database = self.database.lock().await;
+    let user = match database.get_user_by(users::Column::Id, id).await {
+      Ok(u) => u,
+      Err(e) => return Some(Response::from_json(404, json!({ "error": e.get_message() })).unwrap()),
+    };
+
+    let violations = self.password_policy.validate_password(&user.get_username(), new_password);
+    if !violations.is_empty() {
+      return Some(Response::from_json(400, PasswordPolicy::violations_json(&violations)).unwrap());
+    }
+
+    let password_hash = Self::hash_password(new_password);
+    if let Err(e) = database.update_user(id, user.get_username(), password_hash.clone()).await {
+      return Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap());
+    }
+
+    if let Some(mut u) = self.active_users.get_mut(&id) {
+      u.set_password_hash(&password_hash);
+    }
+
+    debug!("Changed password of user {}", id);
+    Some(Response::from_json(200, json!({ "success": "Password changed" })).unwrap())
   }
 
   async fn handle_new_oauth_url<'s, 'r>(&'s mut self, req: Request<'r>) -> Option<Response<'r>> {
@@ -364,7 +623,12 @@ impl UserManager {
       return Some(Response::from_json(401, json!({ "error": "User is not logged in or does not exist"})).unwrap());
     }
 
-    let mut flow = match OAuthFlow::new(id) {
+    let session_token = match Self::get_bearer_token(&req) {
+      Ok(t) => t,
+      Err(e) => return Some(Response::from_json(401, e.to_json()).unwrap()),
+    };
+
+    let mut flow = match OAuthFlow::new(id, session_token) {
       Ok(f) => f,
       Err(e) =>
         return Some(
@@ -385,74 +649,153 @@ impl UserManager {
         return false;
       }
     });
+    let secure = if CONFIG.server.get_public_url().starts_with("https://") { "; Secure" } else { "" };
+    let cookie = format!(
+      "{}={}; Path=/api/users/oauth; Max-Age=600; HttpOnly; SameSite=Lax{}",
+      OAUTH_BINDING_COOKIE,
+      flow.get_session_binding(),
+      secure
+    );
     self.oauth_flows.insert(state, (id, flow, curr_time));
-    return Some(Response::from_json(200, json!({ "oauth_url": url })).unwrap());
+
+    let mut res = Response::from_json(200, json!({ "oauth_url": url })).unwrap();
+    res.add_header("set-cookie".to_string(), &cookie);
+    return Some(res);
   }
 
   async fn handle_oauth_callback<'s, 'r>(&'s mut self, req: Request<'r>) -> Option<Response<'r>> {
     let params = req.get_url_params();
-    let state = if let Some(s) = params.get("state") {
-      s
-    } else {
-      return Some(Response::from_json(400, json!({ "error": "No state param" })).unwrap());
+    let state = match params.get("state") {
+      Some(s) => s.to_string(),
+      None => return Some(Self::oauth_redirect(Err("missing_state"))),
     };
-    let code = if let Some(s) = params.get("code") {
-      s
-    } else {
-      return Some(Response::from_json(400, json!({ "error": "No code param" })).unwrap());
+
+    // States are single use, the flow is gone no matter how the callback ends
+    let (id, mut flow) = match self.oauth_flows.remove(&state) {
+      Some((_, (id, flow, _))) => (id, flow),
+      None => return Some(Self::oauth_redirect(Err("unknown_state"))),
     };
+    trace!("Removed OAuth flow: state = {}, id = {}", state, id);
 
-    let (id, mut flow) = if let Some(f) = self.oauth_flows.get_mut(*state) {
-      (f.0, f.1.clone())
-    } else {
-      return Some(Response::from_json(401, json!({ "error": "No flow for state" })).unwrap());
+    if flow.get_user_id() != id {
+      return Some(Self::oauth_redirect(Err("invalid_id")));
+    }
+
+    if Self::get_cookie(&req, OAUTH_BINDING_COOKIE).as_deref() != Some(flow.get_session_binding()) {
+      error!("OAuth callback for user {} did not come from the session that started it", id);
+      return Some(Self::oauth_redirect(Err("session_mismatch")));
+    }
+
+    if let Some(e) = params.get("error") {
+      debug!("Google returned an OAuth error for user {}: {}", id, e);
+      return Some(Self::oauth_redirect(Err("access_denied")));
+    }
+
+    let code = match params.get("code") {
+      Some(c) => c.to_string(),
+      None => return Some(Self::oauth_redirect(Err("missing_code"))),
     };
 
-    let mut u = match self.active_users.get_mut(&id) {
-      Some(u) => u,
-      None => return Some(Response::from_json(401, json!({ "error": "User is not active" })).unwrap()),
-    }; // Should always be active here
+    match self.link_google_account(id, &mut flow, code).await {
+      Ok(guser) => {
+        let details = json!({ "scopes": guser.get_scopes(), "photos_access": guser.has_photos_access() });
+        self.audit_log.record(&req, AuditAction::GoogleLinked, Some(id), Some(id), Some(details)).await;
 
-    if flow.get_user_id() != id {
-      return Some(Response::from_json(401, json!({ "error": "Invalid id" })).unwrap());
-    }
-
-    let mut res: Option<Response> = None;
-
-    match flow.process(code.to_string()).await {
-      Ok(t) => {
-        let client: Client = Client::new();
-        let res =
-          match client.get("https://www.googleapis.com/oauth2/v1/userinfo?alt=json").bearer_auth(&t).send().await {
-            Ok(r) => r.text().await.unwrap(),
-            Err(e) => {
-              error!("Failed to get google user info: {}", e);
-              return Some(Response::from_json(500, json!({ "error": "Failed to get google user info" })).unwrap());
-            }
-          };
-        
-        trace!("Res: {}", res);
-        let json: UserinfoJson = from_str(&res).unwrap(); // TODO: Handle this?
-    
-
-        let guser = GUser::new(t, json.name, json.picture);
-        trace!("{:?}", guser);
-        u.set_guser(guser);
+        if guser.has_photos_access() {
+          Some(Self::oauth_redirect(Ok("success")))
+        } else {
+          Some(Self::oauth_redirect(Ok("missing_scopes")))
+        }
       }
       Err(e) => {
-        res = Some(Response::from_json(501, json!({ "error": e.to_string() })).unwrap());
+        error!("Failed to link google account for user {}: {}", id, e);
+        let details = json!({ "error": e.get_message() });
+        self.audit_log.record(&req, AuditAction::GoogleLinkFailed, Some(id), Some(id), Some(details)).await;
+        Some(Self::oauth_redirect(Err("link_failed")))
       }
+    }
+  }
+
+  async fn handle_unlink_google<'s, 'r>(&'s self, req: Request<'r>) -> Option<Response<'r>> {
+    let id = match self.validate_request(&req).await {
+      Ok(id) => id,
+      Err(e) => return Some(Response::from_json(401, e.to_json()).unwrap()),
     };
 
-    if res.is_none() {
-      let mut temp = Response::basic(301, "Found");
-      temp.add_header("location".to_string(), "/");
-      res = Some(temp);
+    if let Err(e) = self.database.lock().await.delete_google_account(id).await {
+      return Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap());
     }
 
-    trace!("Removed OAuth flow: state = {}, id = {}, code = {}", &state, &id, res.as_ref().unwrap().get_code());
-    self.oauth_flows.remove(*state);
+    if let Some(mut u) = self.active_users.get_mut(&id) {
+      u.remove_guser();
+    }
+
+    self.audit_log.record(&req, AuditAction::GoogleUnlinked, Some(id), Some(id), None).await;
+    Some(Response::from_json(200, json!({ "success": "Google account unlinked" })).unwrap())
+  }
+
+  /// Finish the flow and store the linked account, the returned account may be
+  /// missing scopes the user unticked on the consent screen
+  async fn link_google_account(&self, id: i32, flow: &mut OAuthFlow, code: String) -> Result<GUser, UserManagerError> {
+    let token = flow.process(self.http.get_oauth_client(), code).await?;
+    let userinfo = self.fetch_google_userinfo(&token.access_token).await?;
+
+    let mut guser = GUser::new(token.access_token, userinfo.name, userinfo.picture);
+    guser.set_scopes(token.scopes);
+    guser.set_refresh_token(token.refresh_token);
+    guser.set_expires_at(token.expires_at);
+    trace!("{:?}", guser);
+
+    self
+      .database
+      .lock()
+      .await
+      .set_google_account(id, &guser)
+      .await
+      .map_err(|e| UserManagerError::DatabaseError(e.get_message()))?;
+
+    if let Some(mut u) = self.active_users.get_mut(&id) {
+      u.set_guser(guser.clone());
+    }
+
+    Ok(guser)
+  }
 
+  async fn fetch_google_userinfo(&self, token: &str) -> Result<UserinfoJson, UserManagerError> {
+    let res = self
+      .http
+      .get("https://www.googleapis.com/oauth2/v1/userinfo?alt=json")
+      .bearer_auth(token)
+      .send()
+      .await
+      .map_err(|e| UserManagerError::OAuthError(format!("Failed to get google user info: {}", e)))?;
+
+    if !res.status().is_success() {
+      return Err(UserManagerError::OAuthError(format!("Google user info request failed with {}", res.status())));
+    }
+
+    let text =
+      res.text().await.map_err(|e| UserManagerError::OAuthError(format!("Failed to read google user info: {}", e)))?;
+    trace!("Res: {}", text);
+
+    from_str(&text).map_err(|e| UserManagerError::OAuthError(format!("Bad google user info json: {}", e)))
+  }
+
+  /// Send the browser back to the frontend with the result of the OAuth flow
+  fn oauth_redirect<'r>(result: Result<&str, &str>) -> Response<'r> {
+    let frontend_url = CONFIG.server.get_frontend_url();
+    let separator = if frontend_url.contains('?') { '&' } else { '?' };
+    let location = match result {
+      Ok(s) => format!("{}{}oauth={}", frontend_url, separator, s),
+      Err(e) => format!("{}{}oauth=error&reason={}", frontend_url, separator, e),
+    };
+
+    let mut res = Response::basic(302, "Found");
+    res.add_header("location".to_string(), &location);
+    res.add_header(
+      "set-cookie".to_string(),
+      &format!("{}=; Path=/api/users/oauth; Max-Age=0; HttpOnly; SameSite=Lax", OAUTH_BINDING_COOKIE),
+    );
     res
   }
 
@@ -464,10 +807,13 @@ impl UserManager {
         "id": id,
         "username": user.get_username(),
         "created_at": user.get_created_at(),
+        "role": user.get_role(),
         "google": if let Some(guser) = user.get_guser() {
           json!({
             "username": guser.get_name(),
-            "pfp_url": guser.get_pfp_url()
+            "pfp_url": guser.get_pfp_url(),
+            "scopes": guser.get_scopes(),
+            "photos_access": guser.has_photos_access()
           })
         } else {
           json!(false)
@@ -496,6 +842,7 @@ impl ApiMethod for UserManager {
       Some("userinfo") => self.handle_user_info(req).await,
       Some("oauth/url") => self.handle_new_oauth_url(req).await,
       Some("oauth/callback") => self.handle_oauth_callback(req).await,
+      Some("invites") => self.handle_list_invites(req).await,
       _ => Some(Response::basic(404, "Not Found")),
     }
   }
@@ -510,6 +857,9 @@ impl ApiMethod for UserManager {
       Some("modify") => self.handle_modify_user(req).await,
       Some("login") => self.handle_user_login(req).await,
       Some("logout") => self.handle_user_logout(req).await,
+      Some("invite") => self.handle_new_invite(req).await,
+      Some("reset") => self.handle_reset_password(req).await,
+      Some("unlink") => self.handle_unlink_google(req).await,
       _ => Some(Response::basic(404, "Not Found")),
     }
   }
@@ -0,0 +1,2 @@
+pub mod verifier;
+pub mod verify_manager;
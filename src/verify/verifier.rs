@@ -0,0 +1,801 @@
+use std::{
+  collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+  fmt,
+  str::FromStr,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+
+use archive_database::{
+  entities::{media_items, sync_jobs, verify_runs},
+  structs::{SyncJobStatus, VerifyRunStatus},
+};
+use archive_storage::{error::StorageError, SharedStorage};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::photos::{
+  layout::LayoutOwner,
+  media_store::{MediaStore, SharedMediaStore},
+  sync::JobRunner,
+};
+
+/// Only this many findings are listed in a report, the rest are only counted
+/// so the report of a badly broken archive stays readable
+const MAX_LISTED_FINDINGS: usize = 1000;
+
+/// Media items read from the database at once
+const PAGE_SIZE: u64 = 500;
+
+/// How long a sync job has to go without a checkpoint to count as stuck when
+/// the jobs were not resumed in this process, ex: for the `verify` command
+/// while a server may be running them. A job waiting for the API quota to
+/// reset checkpoints about once a day
+const STUCK_JOB_AGE: i64 = 25 * 60 * 60;
+
+/// Something the verifier can do about a finding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Repair {
+  /// Download lost or damaged blobs from google again and put missing
+  /// browsable copies back from their blob
+  Redownload,
+  /// Use orphaned files with content an item is missing
+  Adopt,
+  /// Remove items whose content is lost and close stuck sync jobs
+  Purge,
+}
+
+impl FromStr for Repair {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "redownload" => Ok(Self::Redownload),
+      "adopt" => Ok(Self::Adopt),
+      "purge" => Ok(Self::Purge),
+      _ => Err(format!("Unknown repair {}, expected redownload, adopt or purge", s)),
+    }
+  }
+}
+
+/// What to check and repair
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VerifyOptions {
+  /// Hash every blob instead of only comparing sizes, this reads the whole
+  /// archive
+  pub checksums: bool,
+  pub repair: Vec<Repair>,
+}
+
+impl VerifyOptions {
+  /// Read the options of the `verify` command: `--checksums` and
+  /// `--repair=redownload,adopt,purge`
+  ///
+  /// Returns an error message for unknown arguments or repairs
+  pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+    let mut options = Self::default();
+    for arg in args {
+      match arg.split_once('=') {
+        None if arg == "--checksums" => options.checksums = true,
+        Some(("--repair", repairs)) =>
+          for repair in repairs.split(',').filter(|r| !r.is_empty()) {
+            options.repair.push(repair.parse()?);
+          },
+        _ => return Err(format!("Unknown argument {}", arg)),
+      }
+    }
+
+    Ok(options)
+  }
+
+  #[inline]
+  fn repairs(&self, repair: Repair) -> bool {
+    self.repair.contains(&repair)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+  /// The blob of an item is not in storage
+  MissingBlob,
+  /// The blob of an item has a different size than the item
+  SizeMismatch,
+  /// The blob of an item does not hash to its name
+  ChecksumMismatch,
+  /// The browsable copy of an item is gone or has a different size
+  BadCopy,
+  /// An item has no browsable copy while the layout is enabled
+  Unplaced,
+  /// A blob in storage the database does not know about
+  OrphanBlob,
+  /// A file in the layout no item points at
+  OrphanFile,
+  /// A sync job is marked running or interrupted but nothing is running it
+  StuckJob,
+}
+
+impl Problem {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::MissingBlob => "missing_blob",
+      Self::SizeMismatch => "size_mismatch",
+      Self::ChecksumMismatch => "checksum_mismatch",
+      Self::BadCopy => "bad_copy",
+      Self::Unplaced => "unplaced",
+      Self::OrphanBlob => "orphan_blob",
+      Self::OrphanFile => "orphan_file",
+      Self::StuckJob => "stuck_job",
+    }
+  }
+
+  /// Whether the content of the item is lost or damaged
+  fn is_blob(&self) -> bool {
+    matches!(self, Self::MissingBlob | Self::SizeMismatch | Self::ChecksumMismatch)
+  }
+
+  fn is_copy(&self) -> bool {
+    matches!(self, Self::BadCopy | Self::Unplaced)
+  }
+
+  fn is_orphan(&self) -> bool {
+    matches!(self, Self::OrphanBlob | Self::OrphanFile)
+  }
+}
+
+/// One problem with the archive
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+  pub problem: Problem,
+  /// Storage key of the file the finding is about
+  pub key: Option<String>,
+  pub user_id: Option<i32>,
+  /// Id of the media item or sync job the finding is about
+  pub id: Option<i32>,
+  pub google_id: Option<String>,
+  pub detail: String,
+  /// What was done about it, None if nothing was
+  pub repair: Option<String>,
+  /// Content hash of the blob or orphan, used to match them up
+  #[serde(skip)]
+  hash: Option<String>,
+}
+
+impl Finding {
+  fn item(problem: Problem, item: &media_items::Model, key: Option<&str>, detail: String) -> Self {
+    Self {
+      problem,
+      key: key.map(|k| k.to_string()),
+      user_id: Some(item.user_id),
+      id: Some(item.id),
+      google_id: Some(item.google_id.clone()),
+      detail,
+      repair: None,
+      hash: Some(item.blob_hash.clone()),
+    }
+  }
+
+  fn orphan(problem: Problem, key: &str, size: u64) -> Self {
+    Self {
+      problem,
+      key: Some(key.to_string()),
+      user_id: None,
+      id: None,
+      google_id: None,
+      detail: format!("{} bytes, not in the database", size),
+      repair: None,
+      hash: None,
+    }
+  }
+}
+
+impl fmt::Display for Finding {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.problem.as_str())?;
+    if let Some(key) = &self.key {
+      write!(f, " {}", key)?;
+    }
+    if let Some(google_id) = &self.google_id {
+      write!(f, " ({})", google_id)?;
+    }
+    write!(f, ": {}", self.detail)?;
+    match &self.repair {
+      Some(r) => write!(f, ", {}", r),
+      None => Ok(()),
+    }
+  }
+}
+
+/// What a verify run found and did
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+  pub items: u64,
+  /// Distinct blobs checked
+  pub blobs: u64,
+  /// Objects in storage
+  pub files: u64,
+  /// Findings by problem
+  pub problems: BTreeMap<Problem, u64>,
+  pub repaired: u64,
+  pub unrepaired: u64,
+  /// The first findings, up to 1000
+  pub findings: Vec<Finding>,
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+  AlreadyRunning,
+  DatabaseError(String),
+  Failed(String),
+}
+
+impl fmt::Display for VerifyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::AlreadyRunning => write!(f, "A verify run is already in progress"),
+      Self::DatabaseError(e) => write!(f, "Database error: {}", e),
+      Self::Failed(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl From<StorageError> for VerifyError {
+  fn from(value: StorageError) -> Self {
+    Self::Failed(format!("Storage error: {}", value))
+  }
+}
+
+/// Everything found while scanning, and what is needed to repair it
+#[derive(Default)]
+struct Scan {
+  report: VerifyReport,
+  findings: Vec<Finding>,
+  /// Items and sync jobs with findings, by id
+  items: HashMap<i32, media_items::Model>,
+  jobs: HashMap<i32, sync_jobs::Model>,
+}
+
+impl Scan {
+  fn add(&mut self, finding: Finding) {
+    debug!("Verify found {}", finding);
+    self.findings.push(finding);
+  }
+
+  /// Mark every unrepaired finding `matches` accepts as repaired
+  fn repaired<F: Fn(&Finding) -> bool>(&mut self, matches: F, repair: &str) {
+    for finding in self.findings.iter_mut().filter(|f| f.repair.is_none() && matches(f)) {
+      finding.repair = Some(repair.to_string());
+    }
+  }
+
+  fn into_report(mut self) -> VerifyReport {
+    for finding in &self.findings {
+      *self.report.problems.entry(finding.problem).or_default() += 1;
+      match finding.repair {
+        Some(_) => self.report.repaired += 1,
+        None => self.report.unrepaired += 1,
+      }
+    }
+    self.findings.truncate(MAX_LISTED_FINDINGS);
+    self.report.findings = self.findings;
+    self.report
+  }
+}
+
+pub type SharedVerifier = Arc<Verifier>;
+
+/// Checks the archive in storage against the database and repairs what it
+/// can, one run at a time
+pub struct Verifier {
+  store: SharedMediaStore,
+  storage: SharedStorage,
+  jobs: JobRunner,
+  running: AtomicBool,
+}
+
+impl Verifier {
+  pub fn new(store: SharedMediaStore, jobs: JobRunner) -> SharedVerifier {
+    Arc::new(Self { storage: store.get_storage(), store, jobs, running: AtomicBool::new(false) })
+  }
+
+  #[inline]
+  pub fn is_running(&self) -> bool {
+    self.running.load(Ordering::Relaxed)
+  }
+
+  /// Record a verify run and do it in the background
+  ///
+  /// Returns the run or a VerifyError if one is already in progress or it
+  /// could not be recorded
+  pub async fn start(
+    self: &Arc<Self>,
+    started_by: Option<i32>,
+    options: VerifyOptions,
+  ) -> Result<verify_runs::Model, VerifyError> {
+    let run = self.begin(started_by, &options).await?;
+
+    let verifier = Arc::clone(self);
+    let id = run.id;
+    tokio::spawn(async move {
+      if let Err(e) = verifier.finish(id, options).await {
+        error!("Verify run {} failed: {}", id, e);
+      }
+    });
+
+    Ok(run)
+  }
+
+  /// Mark the runs the last server run was doing as failed. Only done once
+  /// at server startup, later on a run may be going in a `verify` command
+  pub async fn fail_leftover_runs(&self) {
+    let res = self.store.get_database().lock().await.fail_running_verify_runs("Cut off by a restart").await;
+    match res {
+      Ok(0) => (),
+      Ok(n) => warn!("Marked {} verify runs cut off by a restart as failed", n),
+      Err(e) => error!("Failed to mark the verify runs cut off by a restart as failed: {}", e.get_message()),
+    }
+  }
+
+  /// Record a verify run and wait for it
+  ///
+  /// Returns the report or a VerifyError if the run could not be done
+  pub async fn run(&self, started_by: Option<i32>, options: VerifyOptions) -> Result<VerifyReport, VerifyError> {
+    let run = self.begin(started_by, &options).await?;
+    self.finish(run.id, options).await
+  }
+
+  async fn begin(&self, started_by: Option<i32>, options: &VerifyOptions) -> Result<verify_runs::Model, VerifyError> {
+    if self.running.swap(true, Ordering::SeqCst) {
+      return Err(VerifyError::AlreadyRunning);
+    }
+
+    let database = self.store.get_database();
+    let res =
+      database.lock().await.new_verify_run(started_by, serde_json::to_string(options).unwrap_or_default()).await;
+
+    res.map_err(|e| {
+      self.running.store(false, Ordering::SeqCst);
+      VerifyError::DatabaseError(e.get_message())
+    })
+  }
+
+  /// Do a recorded run and save its report
+  async fn finish(&self, id: i32, options: VerifyOptions) -> Result<VerifyReport, VerifyError> {
+    let started_at = chrono::Utc::now().timestamp();
+    info!("Verifying the archive (checksums: {}, repairs: {:?})", options.checksums, options.repair);
+    let res = self.verify(&options, started_at).await;
+
+    let (status, report, error) = match &res {
+      Ok(r) => (VerifyRunStatus::Finished, serde_json::to_string(r).ok(), None),
+      Err(e) => (VerifyRunStatus::Failed, None, Some(e.to_string())),
+    };
+    let saved = self.store.get_database().lock().await.finish_verify_run(id, status, report, error).await;
+    self.running.store(false, Ordering::SeqCst);
+    if let Err(e) = saved {
+      error!("Failed to save verify run {}: {}", id, e.get_message());
+    }
+
+    if let Ok(r) = &res {
+      info!(
+        "Verify run {} done: {} items, {} blobs, {} files, {} problems ({} repaired)",
+        id,
+        r.items,
+        r.blobs,
+        r.files,
+        r.repaired + r.unrepaired,
+        r.repaired
+      );
+    }
+    res
+  }
+
+  fn check_stopping(&self) -> Result<(), VerifyError> {
+    match self.jobs.is_stopping() {
+      true => Err(VerifyError::Failed("Interrupted by a shutdown".to_string())),
+      false => Ok(()),
+    }
+  }
+
+  async fn verify(&self, options: &VerifyOptions, started_at: i64) -> Result<VerifyReport, VerifyError> {
+    let mut scan = Scan::default();
+    self.scan_items(&mut scan, options, started_at).await?;
+    self.scan_jobs(&mut scan, started_at).await?;
+
+    if options.repairs(Repair::Adopt) {
+      self.adopt(&mut scan).await?;
+    }
+    if options.repairs(Repair::Redownload) {
+      self.redownload(&mut scan).await?;
+    }
+    if options.repairs(Repair::Purge) {
+      self.purge(&mut scan).await?;
+    }
+
+    Ok(scan.into_report())
+  }
+
+  /// Check every media item against storage, then look for files nothing
+  /// points at. Items and files added after `started_at` are left alone, a
+  /// sync may be writing them
+  async fn scan_items(&self, scan: &mut Scan, options: &VerifyOptions, started_at: i64) -> Result<(), VerifyError> {
+    // Listed before the items are read, so everything an item points at is
+    // either listed or really missing
+    let blobs: HashMap<String, u64> = self.storage.list("blobs/").await?.into_iter().map(|o| (o.key, o.size)).collect();
+    let layout_prefix = self.store.layout_prefix();
+    let mut copies = HashMap::new();
+    if let Some(prefix) = &layout_prefix {
+      for object in self.storage.list(prefix).await? {
+        if !object.key.starts_with("blobs/") && !object.key.starts_with("health/") {
+          copies.insert(object.key.clone(), object);
+        }
+      }
+    }
+    scan.report.files = (blobs.len() + copies.len()) as u64;
+
+    let database = self.store.get_database();
+    let mut seen_blobs = HashSet::new();
+    let mut seen_copies = HashSet::new();
+    let mut checksums: HashMap<String, Option<String>> = HashMap::new();
+    let mut after_id = 0;
+    loop {
+      self.check_stopping()?;
+      let items = database.lock().await.get_media_items_after(after_id, PAGE_SIZE).await;
+      let items = items.map_err(|e| VerifyError::DatabaseError(e.get_message()))?;
+      let last = match items.last() {
+        Some(i) => i.id,
+        None => break,
+      };
+
+      for item in items {
+        let key = MediaStore::blob_key(&item.blob_hash);
+        seen_blobs.insert(key.clone());
+        if let Some(path) = &item.path {
+          seen_copies.insert(path.clone());
+        }
+        if item.stored_at >= started_at {
+          continue;
+        }
+        scan.report.items += 1;
+
+        let finding = match blobs.get(&key) {
+          None => Some(Finding::item(Problem::MissingBlob, &item, Some(&key), "Blob is not in storage".to_string())),
+          Some(size) if *size != item.size as u64 => Some(Finding::item(
+            Problem::SizeMismatch,
+            &item,
+            Some(&key),
+            format!("Blob is {} bytes, expected {}", size, item.size),
+          )),
+          Some(_) if options.checksums => {
+            if !checksums.contains_key(&item.blob_hash) {
+              let hash = match hash_object(&self.storage, &key).await {
+                Ok((h, _)) => Some(h),
+                Err(e) => {
+                  warn!("Failed to read {}: {}", key, e);
+                  None
+                }
+              };
+              checksums.insert(item.blob_hash.clone(), hash);
+            }
+            match &checksums[&item.blob_hash] {
+              Some(h) if *h == item.blob_hash => None,
+              Some(h) =>
+                Some(Finding::item(Problem::ChecksumMismatch, &item, Some(&key), format!("Blob hashes to {}", h))),
+              None =>
+                Some(Finding::item(Problem::ChecksumMismatch, &item, Some(&key), "Blob can not be read".to_string())),
+            }
+          }
+          Some(_) => None,
+        };
+
+        let copy = match (&layout_prefix, &item.path) {
+          (None, _) => None,
+          (Some(_), None) =>
+            Some(Finding::item(Problem::Unplaced, &item, None, "Item has no browsable copy".to_string())),
+          (Some(_), Some(path)) => match copies.get(path) {
+            None => Some(Finding::item(Problem::BadCopy, &item, Some(path), "Browsable copy is missing".to_string())),
+            Some(o) if o.size != item.size as u64 => Some(Finding::item(
+              Problem::BadCopy,
+              &item,
+              Some(path),
+              format!("Browsable copy is {} bytes, expected {}", o.size, item.size),
+            )),
+            Some(_) => None,
+          },
+        };
+
+        if finding.is_some() || copy.is_some() {
+          scan.items.insert(item.id, item);
+        }
+        finding.into_iter().chain(copy).for_each(|f| scan.add(f));
+      }
+
+      debug!("Verify progress: {} items, {} problems", scan.report.items, scan.findings.len());
+      after_id = last;
+    }
+    scan.report.blobs = seen_blobs.len() as u64;
+
+    // Blobs with a row but no items are waiting to be collected, not orphans
+    let known = database.lock().await.get_blob_hashes().await;
+    let known = known.map_err(|e| VerifyError::DatabaseError(e.get_message()))?;
+    for (key, size) in &blobs {
+      let hash = key.rsplit('/').next().unwrap_or_default();
+      if !seen_blobs.contains(key) && !known.contains(hash) {
+        scan.add(Finding::orphan(Problem::OrphanBlob, key, *size));
+      }
+    }
+
+    for (key, object) in &copies {
+      if !seen_copies.contains(key) && object.modified.is_none_or(|m| m < started_at) {
+        scan.add(Finding::orphan(Problem::OrphanFile, key, object.size));
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Look for sync jobs that are marked running or interrupted but are not
+  /// running. Until the jobs of the last run were resumed here they may be
+  /// running in another process, then only the ones that have not
+  /// checkpointed for `STUCK_JOB_AGE` count
+  async fn scan_jobs(&self, scan: &mut Scan, started_at: i64) -> Result<(), VerifyError> {
+    let resumed = self.jobs.has_resumed();
+    let database = self.store.get_database();
+    for status in [SyncJobStatus::Running, SyncJobStatus::Interrupted] {
+      let jobs = database.lock().await.get_sync_jobs(status).await;
+      for job in jobs.map_err(|e| VerifyError::DatabaseError(e.get_message()))? {
+        let detail = match resumed {
+          true if self.jobs.is_running(job.user_id) || job.updated_at >= started_at => continue,
+          true => format!("Sync job is {} but not running", status),
+          false if job.updated_at >= started_at - STUCK_JOB_AGE => continue,
+          false =>
+            format!("Sync job is {} but has not moved for {} hours", status, (started_at - job.updated_at) / 3600),
+        };
+
+        scan.add(Finding {
+          problem: Problem::StuckJob,
+          key: None,
+          user_id: Some(job.user_id),
+          id: Some(job.id),
+          google_id: None,
+          detail,
+          repair: None,
+          hash: None,
+        });
+        scan.jobs.insert(job.id, job);
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Use orphaned files that have the content of a lost blob or a missing
+  /// browsable copy
+  async fn adopt(&self, scan: &mut Scan) -> Result<(), VerifyError> {
+    let wanted_blobs: HashSet<String> =
+      scan.findings.iter().filter(|f| f.problem.is_blob()).filter_map(|f| f.hash.clone()).collect();
+    let mut wanted_copies: HashMap<String, Vec<i32>> = HashMap::new();
+    for finding in scan.findings.iter().filter(|f| f.problem.is_copy()) {
+      if let (Some(hash), Some(id)) = (&finding.hash, finding.id) {
+        wanted_copies.entry(hash.clone()).or_default().push(id);
+      }
+    }
+    if wanted_blobs.is_empty() && wanted_copies.is_empty() {
+      return Ok(());
+    }
+
+    let orphans: Vec<(usize, Problem, String)> = scan
+      .findings
+      .iter()
+      .enumerate()
+      .filter(|(_, f)| f.problem.is_orphan())
+      .filter_map(|(i, f)| f.key.clone().map(|k| (i, f.problem, k)))
+      .collect();
+
+    for (index, problem, key) in orphans {
+      self.check_stopping()?;
+      let hash = match hash_object(&self.storage, &key).await {
+        Ok((h, _)) => h,
+        Err(e) => {
+          warn!("Failed to read {}: {}", key, e);
+          continue;
+        }
+      };
+      scan.findings[index].hash = Some(hash.clone());
+      let mut adopted = Vec::new();
+
+      let blob_lost =
+        scan.findings.iter().any(|f| f.repair.is_none() && f.problem.is_blob() && f.hash.as_ref() == Some(&hash));
+      if wanted_blobs.contains(&hash) && blob_lost {
+        let blob_key = MediaStore::blob_key(&hash);
+        match self.storage.copy(&key, &blob_key).await {
+          Ok(_) => {
+            scan.repaired(|f| f.problem.is_blob() && f.hash.as_ref() == Some(&hash), &format!("adopted {}", key));
+            adopted.push(format!("as blob {}", hash));
+          }
+          Err(e) => error!("Failed to adopt {} as blob {}: {}", key, hash, e),
+        }
+      }
+
+      // A file can only be the copy of one item
+      let item = match problem {
+        Problem::OrphanFile => wanted_copies.get_mut(&hash).and_then(|ids| ids.pop()),
+        _ => None,
+      };
+      if let Some(item_id) = item {
+        let old = scan.items.get(&item_id).and_then(|i| i.path.clone());
+        let res = self
+          .store
+          .get_database()
+          .lock()
+          .await
+          .set_media_item_path(item_id, Some(key.clone()), Some(hash.clone()))
+          .await;
+        match res {
+          Ok(true) => {
+            if let Some(old) = old {
+              if let Err(e) = self.storage.delete(&old).await {
+                warn!("Failed to remove the old copy {}: {}", old, e);
+              }
+            }
+            scan.repaired(|f| f.problem.is_copy() && f.id == Some(item_id), &format!("adopted {}", key));
+            adopted.push(format!("as the copy of item {}", item_id));
+          }
+          Ok(false) => warn!("Could not adopt {}, another item has that path", key),
+          Err(e) => error!("Failed to adopt {}: {}", key, e.get_message()),
+        }
+      }
+
+      if !adopted.is_empty() {
+        scan.findings[index].repair = Some(format!("adopted {}", adopted.join(" and ")));
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Download lost blobs from google again and put missing browsable copies
+  /// back from their blobs
+  async fn redownload(&self, scan: &mut Scan) -> Result<(), VerifyError> {
+    let mut by_hash: BTreeMap<String, Vec<i32>> = BTreeMap::new();
+    for finding in scan.findings.iter().filter(|f| f.repair.is_none() && f.problem.is_blob()) {
+      if let (Some(hash), Some(id)) = (&finding.hash, finding.id) {
+        by_hash.entry(hash.clone()).or_default().push(id);
+      }
+    }
+
+    let mut libraries: HashMap<i32, Result<(LayoutOwner, String), String>> = HashMap::new();
+    for (hash, ids) in by_hash {
+      for id in ids {
+        self.check_stopping()?;
+        let item = match scan.items.get(&id) {
+          Some(i) => i.clone(),
+          None => continue,
+        };
+        if let Entry::Vacant(e) = libraries.entry(item.user_id) {
+          e.insert(self.jobs.library_of(item.user_id).await);
+        }
+        let (owner, token) = match &libraries[&item.user_id] {
+          Ok(l) => l,
+          Err(e) => {
+            warn!("Can not download {} again: {}", item.google_id, e);
+            continue;
+          }
+        };
+
+        match self.jobs.redownload(owner, token, &item.google_id, &hash).await {
+          Ok(true) => {
+            scan.repaired(|f| f.problem.is_blob() && f.hash.as_ref() == Some(&hash), "downloaded again");
+            break;
+          }
+          // Only this item moved to the new content, the others still need
+          // the old blob
+          Ok(false) => scan.repaired(|f| f.problem.is_blob() && f.id == Some(id), "downloaded again, content changed"),
+          Err(e) => warn!("Failed to download {} again: {}", item.google_id, e),
+        }
+      }
+    }
+
+    let copies: Vec<i32> = scan
+      .findings
+      .iter()
+      .filter(|f| f.repair.is_none() && f.problem.is_copy())
+      .filter_map(|f| f.id)
+      // The blob has to be fine to copy it
+      .filter(|id| !scan.findings.iter().any(|f| f.repair.is_none() && f.problem.is_blob() && f.id == Some(*id)))
+      .collect();
+    if copies.is_empty() {
+      return Ok(());
+    }
+
+    let owners = self.store.get_owners().await.map_err(|e| VerifyError::Failed(e.to_string()))?;
+    for id in copies {
+      self.check_stopping()?;
+      let (item, owner) = match scan.items.get(&id).and_then(|i| owners.get(&i.user_id).map(|o| (i, o))) {
+        Some(p) => p,
+        None => continue,
+      };
+
+      // A damaged copy has to go first, placing keeps copies that exist
+      if let Some(path) = &item.path {
+        self.storage.delete(path).await?;
+      }
+      match self.store.place(owner, item).await {
+        Ok(_) => scan.repaired(|f| f.problem.is_copy() && f.id == Some(id), "copied from the blob"),
+        Err(e) => warn!("Failed to place {}: {}", item.google_id, e),
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Remove the items whose content is lost and close stuck sync jobs
+  async fn purge(&self, scan: &mut Scan) -> Result<(), VerifyError> {
+    let database = self.store.get_database();
+
+    let lost: HashSet<i32> =
+      scan.findings.iter().filter(|f| f.repair.is_none() && f.problem.is_blob()).filter_map(|f| f.id).collect();
+    for id in lost {
+      let item = match scan.items.get(&id) {
+        Some(i) => i,
+        None => continue,
+      };
+      if let Err(e) = database.lock().await.delete_media_item(item.user_id, &item.google_id).await {
+        error!("Failed to purge {}: {}", item.google_id, e.get_message());
+        continue;
+      }
+      if let Some(path) = &item.path {
+        if let Err(e) = self.storage.delete(path).await {
+          warn!("Failed to remove the copy {} of a purged item: {}", path, e);
+        }
+      }
+      scan.repaired(|f| f.id == Some(id) && (f.problem.is_blob() || f.problem.is_copy()), "purged");
+    }
+
+    let stuck: Vec<sync_jobs::Model> = scan.jobs.values().cloned().collect();
+    for job in stuck {
+      let res = database
+        .lock()
+        .await
+        .checkpoint_sync_job(
+          job.id,
+          SyncJobStatus::Failed,
+          None,
+          job.progress,
+          Some("Stuck, closed by verify".to_string()),
+        )
+        .await;
+      match res {
+        Ok(_) => scan.repaired(|f| f.problem == Problem::StuckJob && f.id == Some(job.id), "closed as failed"),
+        Err(e) => error!("Failed to close sync job {}: {}", job.id, e.get_message()),
+      }
+    }
+
+    Ok(())
+  }
+}
+
+/// Read a whole object, hashing it
+///
+/// Returns its SHA-256 hash and size
+async fn hash_object(storage: &SharedStorage, key: &str) -> Result<(String, u64), StorageError> {
+  let mut stream = storage.get_range(key, None).await?;
+  let mut hasher = Sha256::new();
+  let mut size = 0;
+  let mut buf = vec![0; 64 * 1024];
+  loop {
+    let n = stream.read(&mut buf).await.map_err(StorageError::from)?;
+    if n == 0 {
+      break;
+    }
+    hasher.update(&buf[..n]);
+    size += n as u64;
+  }
+
+  Ok((format!("{:x}", hasher.finalize()), size))
+}
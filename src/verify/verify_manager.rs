@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use archive_database::{entities::verify_runs, structs::Role};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use webrs::{api::ApiMethod, request::Request, response::Response};
+
+use crate::{
+  audit::audit_log::{AuditAction, SharedAuditLog},
+  user::user_manager::SharedUserManager,
+  verify::verifier::{SharedVerifier, VerifyError, VerifyOptions},
+};
+
+pub type SharedVerifyManager = Arc<Mutex<VerifyManager>>;
+
+/// How many past runs are listed
+const MAX_LISTED_RUNS: u64 = 50;
+
+/// Lets admins check the archive for missing and damaged files and repair it
+pub struct VerifyManager {
+  user_manager: SharedUserManager,
+  audit_log: SharedAuditLog,
+  verifier: SharedVerifier,
+}
+
+impl VerifyManager {
+  pub fn new(
+    user_manager: SharedUserManager,
+    audit_log: SharedAuditLog,
+    verifier: SharedVerifier,
+  ) -> SharedVerifyManager {
+    Arc::new(Mutex::new(Self { user_manager, audit_log, verifier }))
+  }
+
+  /// A run as it is sent to clients, with its options and report as objects
+  fn run_json(run: &verify_runs::Model) -> Value {
+    let parse = |s: &str| serde_json::from_str::<Value>(s).ok();
+    json!({
+      "id": run.id,
+      "started_by": run.started_by,
+      "status": run.status,
+      "options": parse(&run.options),
+      "report": run.report.as_deref().and_then(parse),
+      "started_at": run.started_at,
+      "finished_at": run.finished_at,
+      "error": run.error,
+    })
+  }
+
+  /// List the last verify runs without their reports
+  pub async fn handle_list_runs<'s, 'r>(&'s self) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let database = self.audit_log.get_database();
+    let res = database.lock().await.get_verify_runs(MAX_LISTED_RUNS).await;
+    match res {
+      Ok(r) => {
+        let runs: Vec<Value> = r.iter().map(Self::run_json).collect();
+        Some(Response::from_json(200, json!({ "running": self.verifier.is_running(), "runs": runs })).unwrap())
+      }
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+
+  /// Get the run with the `id` url param and its report
+  pub async fn handle_get_run<'s, 'r>(&'s self, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let id: i32 = match req.get_url_params().get("id").and_then(|i| i.parse().ok()) {
+      Some(i) => i,
+      None => return Some(Response::from_json(400, json!({ "error": "Missing run id" })).unwrap()),
+    };
+
+    let database = self.audit_log.get_database();
+    let res = database.lock().await.get_verify_run(id).await;
+    match res {
+      Ok(Some(r)) => Some(Response::from_json(200, Self::run_json(&r)).unwrap()),
+      Ok(None) => Some(Response::from_json(404, json!({ "error": "Verify run not found" })).unwrap()),
+      Err(e) => Some(Response::from_json(500, json!({ "error": e.get_message() })).unwrap()),
+    }
+  }
+
+  /// Start verifying the archive in the background, the body can turn on
+  /// `checksums` and list the `repair`s to do
+  pub async fn handle_start<'s, 'r>(&'s self, id: i32, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let json: Value = serde_json::from_slice(&req.get_data()).unwrap_or(json!({}));
+    let options: VerifyOptions = match serde_json::from_value(json) {
+      Ok(o) => o,
+      Err(e) => return Some(Response::from_json(400, json!({ "error": format!("Invalid options: {}", e) })).unwrap()),
+    };
+
+    let run = match self.verifier.start(Some(id), options.clone()).await {
+      Ok(r) => r,
+      Err(e) => {
+        let code = match e {
+          VerifyError::AlreadyRunning => 409,
+          _ => 500,
+        };
+        return Some(Response::from_json(code, json!({ "error": e.to_string() })).unwrap());
+      }
+    };
+    let details = json!({ "run": run.id, "options": options });
+    self.audit_log.record(&req, AuditAction::VerifyStarted, Some(id), None, Some(details)).await;
+
+    Some(Response::from_json(202, Self::run_json(&run)).unwrap())
+  }
+
+  /// Get the id of the user making the request if they are an admin
+  ///
+  /// Returns the response to send instead if they are not
+  async fn require_admin<'r>(&self, req: &Request<'r>) -> Result<i32, Response<'r>> {
+    let user_manager = self.user_manager.lock().await;
+    let id = user_manager.validate_request(req).await.map_err(|_| Response::basic(401, "Unauthorized"))?;
+    if let Err(e) = user_manager.require_role(id, Role::Admin) {
+      return Err(Response::from_json(403, e.to_json()).unwrap());
+    }
+
+    Ok(id)
+  }
+}
+
+#[async_trait]
+impl ApiMethod for VerifyManager {
+  fn get_endpoint(&self) -> &str {
+    "/verify"
+  }
+
+  async fn handle_get<'s, 'r>(&'s mut self, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    if let Err(res) = self.require_admin(&req).await {
+      return Some(res);
+    }
+
+    match req.get_endpoint().rsplit("verify/").next() {
+      Some("runs") => self.handle_list_runs().await,
+      Some("run") => self.handle_get_run(req).await,
+      _ => Some(Response::basic(404, "Not Found")),
+    }
+  }
+
+  async fn handle_post<'s, 'r>(&'s mut self, req: Request<'r>) -> Option<Response<'r>>
+  where
+    'r: 's,
+  {
+    let id = match self.require_admin(&req).await {
+      Ok(id) => id,
+      Err(res) => return Some(res),
+    };
+
+    match req.get_endpoint().rsplit("/").next() {
+      Some("start") => self.handle_start(id, req).await,
+      _ => Some(Response::basic(404, "Not Found")),
+    }
+  }
+}